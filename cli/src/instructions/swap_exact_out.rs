@@ -26,6 +26,7 @@ pub async fn execute_swap_exact_out<C: Deref<Target = impl Signer> + Clone>(
     let rpc_client = program.async_rpc();
     let lb_pair_state = rpc_client
         .get_account_and_deserialize(&lb_pair, |account| {
+            validate_lb_pair_account_data(&account.data)?;
             Ok(LbPairAccount::deserialize(&account.data)?.0)
         })
         .await?;
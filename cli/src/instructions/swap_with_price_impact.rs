@@ -29,6 +29,7 @@ pub async fn execute_swap_with_price_impact<C: Deref<Target = impl Signer> + Clo
     let rpc_client = program.async_rpc();
     let lb_pair_state = rpc_client
         .get_account_and_deserialize(&lb_pair, |account| {
+            validate_lb_pair_account_data(&account.data)?;
             Ok(LbPairAccount::deserialize(&account.data)?.0)
         })
         .await?;
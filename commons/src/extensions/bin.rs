@@ -6,6 +6,13 @@ pub trait BinExtension {
     fn get_max_amount_out(&self, swap_for_y: bool) -> u64;
     fn get_max_amount_in(&self, price: u128, swap_for_y: bool) -> Result<u64>;
     fn calculate_out_amount(&self, liquidity_share: u128) -> Result<(u64, u64)>;
+    fn get_liquidity(amount_x: u64, amount_y: u64, price: u128) -> Result<u128>;
+    fn get_liquidity_share(&self, in_liquidity: u128, price: u128) -> Result<u128>;
+    fn deposit(&mut self, amount_x: u64, amount_y: u64, liquidity_share: u128) -> Result<()>;
+    /// Value of the token X of the bin in token Y, at the price of bin `bin_id`
+    fn value_x_in_y(&self, bin_id: i32, bin_step: u16) -> Result<f64>;
+    /// Value of the bin in token Y, with token X valued at the price of bin `bin_id`
+    fn value_in_y(&self, bin_id: i32, bin_step: u16) -> Result<f64>;
 
     fn swap(
         &mut self,
@@ -16,7 +23,17 @@ pub trait BinExtension {
         host_fee_bps: Option<u16>,
     ) -> Result<SwapResult>;
 
+    fn swap_exact_out(
+        &mut self,
+        amount_out: u64,
+        price: u128,
+        swap_for_y: bool,
+        lb_pair: &LbPair,
+        host_fee_bps: Option<u16>,
+    ) -> Result<SwapResult>;
+
     fn get_amount_out(amount_in: u64, price: u128, swap_for_y: bool) -> Result<u64>;
+    fn get_precise_amount_out(amount_in: u64, price: u128, swap_for_y: bool) -> Result<u128>;
     fn get_amount_in(amount_out: u64, price: u128, swap_for_y: bool) -> Result<u64>;
 }
 
@@ -29,6 +46,19 @@ impl BinExtension for Bin {
         Ok(self.price)
     }
 
+    fn value_x_in_y(&self, bin_id: i32, bin_step: u16) -> Result<f64> {
+        if self.amount_x == 0 {
+            return Ok(0.0);
+        }
+
+        let price = q64x64_price_to_f64(get_price_from_id(bin_id, bin_step)?);
+        Ok(self.amount_x as f64 * price)
+    }
+
+    fn value_in_y(&self, bin_id: i32, bin_step: u16) -> Result<f64> {
+        Ok(self.value_x_in_y(bin_id, bin_step)? + self.amount_y as f64)
+    }
+
     fn is_empty(&self, is_x: bool) -> bool {
         if is_x {
             self.amount_x == 0
@@ -70,6 +100,21 @@ impl BinExtension for Bin {
         }
     }
 
+    // Same as get_amount_out, but in Q64.64 before the truncation. For analytics only, the program transfers get_amount_out
+    fn get_precise_amount_out(amount_in: u64, price: u128, swap_for_y: bool) -> Result<u128> {
+        if swap_for_y {
+            price.checked_mul(amount_in.into()).context("overflow")
+        } else {
+            mul_div(
+                u128::from(amount_in) << SCALE_OFFSET,
+                ONE,
+                price,
+                Rounding::Down,
+            )
+            .context("overflow")
+        }
+    }
+
     fn calculate_out_amount(&self, liquidity_share: u128) -> Result<(u64, u64)> {
         let out_amount_x = safe_mul_div_cast(
             liquidity_share,
@@ -87,6 +132,43 @@ impl BinExtension for Bin {
         Ok((out_amount_x, out_amount_y))
     }
 
+    // Liquidity of the amounts valued in token Y, in Q64.64. price * amount_x + amount_y
+    fn get_liquidity(amount_x: u64, amount_y: u64, price: u128) -> Result<u128> {
+        let liquidity_x = price.checked_mul(amount_x.into()).context("overflow")?;
+        let liquidity_y = u128::from(amount_y)
+            .checked_shl(SCALE_OFFSET.into())
+            .context("overflow")?;
+
+        liquidity_x.checked_add(liquidity_y).context("overflow")
+    }
+
+    // Liquidity share minted by depositing `in_liquidity` into the bin, same as the program
+    fn get_liquidity_share(&self, in_liquidity: u128, price: u128) -> Result<u128> {
+        let bin_liquidity = Self::get_liquidity(self.amount_x, self.amount_y, price)?;
+
+        if bin_liquidity == 0 || self.liquidity_supply == 0 {
+            return Ok(in_liquidity);
+        }
+
+        safe_mul_div_cast(
+            in_liquidity,
+            self.liquidity_supply,
+            bin_liquidity,
+            Rounding::Down,
+        )
+    }
+
+    fn deposit(&mut self, amount_x: u64, amount_y: u64, liquidity_share: u128) -> Result<()> {
+        self.amount_x = self.amount_x.checked_add(amount_x).context("overflow")?;
+        self.amount_y = self.amount_y.checked_add(amount_y).context("overflow")?;
+        self.liquidity_supply = self
+            .liquidity_supply
+            .checked_add(liquidity_share)
+            .context("overflow")?;
+
+        Ok(())
+    }
+
     //接收一笔输入金额 amount_in，计算出在这个窗口能兑换到多少输出金额 amount_out，需要支付多少手续费 fee，并最终更新窗口的库存。
     fn swap(
         &mut self,
@@ -134,40 +216,104 @@ impl BinExtension for Bin {
             )
         };
 
-        let host_fee = match host_fee_bps {
-            Some(bps) => protocol_fee
-                .checked_mul(bps.into())
-                .context("overflow")?
-                .checked_div(BASIS_POINT_MAX as u64)
-                .context("overflow")?,
-            None => 0,
-        };
-
-        let protocol_fee_after_host_fee = protocol_fee.checked_sub(host_fee).context("overflow")?;
+        settle_swap(
+            self,
+            amount_in_with_fees,
+            amount_out,
+            fee,
+            protocol_fee,
+            swap_for_y,
+            host_fee_bps,
+            false,
+        )
+    }
 
-        let amount_into_bin = amount_in_with_fees.checked_sub(fee).context("overflow")?;
+    // Swap for exactly `amount_out`, or the whole bin liquidity when it is smaller. is_exact_out_amount tells whether the bin filled amount_out.
+    fn swap_exact_out(
+        &mut self,
+        amount_out: u64,
+        price: u128,
+        swap_for_y: bool,
+        lb_pair: &LbPair,
+        host_fee_bps: Option<u16>,
+    ) -> Result<SwapResult> {
+        let max_amount_out = self.get_max_amount_out(swap_for_y);
 
-        if swap_for_y {
-            self.amount_x = self
-                .amount_x
-                .checked_add(amount_into_bin)
-                .context("overflow")?;
-            self.amount_y = self.amount_y.checked_sub(amount_out).context("overflow")?;
+        let (amount_in, amount_out, is_exact_out_amount) = if amount_out >= max_amount_out {
+            (
+                self.get_max_amount_in(price, swap_for_y)?,
+                max_amount_out,
+                amount_out == max_amount_out,
+            )
         } else {
-            self.amount_y = self
-                .amount_y
-                .checked_add(amount_into_bin)
-                .context("overflow")?;
-            self.amount_x = self.amount_x.checked_sub(amount_out).context("overflow")?;
-        }
+            (
+                Bin::get_amount_in(amount_out, price, swap_for_y)?,
+                amount_out,
+                true,
+            )
+        };
+
+        let fee = lb_pair.compute_fee(amount_in)?;
+        let amount_in_with_fees = amount_in.checked_add(fee).context("overflow")?;
 
-        Ok(SwapResult {
+        settle_swap(
+            self,
             amount_in_with_fees,
             amount_out,
             fee,
-            protocol_fee_after_host_fee,
-            host_fee,
-            is_exact_out_amount: false,
-        })
+            lb_pair.compute_protocol_fee(fee)?,
+            swap_for_y,
+            host_fee_bps,
+            is_exact_out_amount,
+        )
     }
 }
+
+// Split the host fee from the protocol fee, and move the swapped amounts in and out of the bin
+#[allow(clippy::too_many_arguments)]
+fn settle_swap(
+    bin: &mut Bin,
+    amount_in_with_fees: u64,
+    amount_out: u64,
+    fee: u64,
+    protocol_fee: u64,
+    swap_for_y: bool,
+    host_fee_bps: Option<u16>,
+    is_exact_out_amount: bool,
+) -> Result<SwapResult> {
+    let host_fee = match host_fee_bps {
+        Some(bps) => protocol_fee
+            .checked_mul(bps.into())
+            .context("overflow")?
+            .checked_div(BASIS_POINT_MAX as u64)
+            .context("overflow")?,
+        None => 0,
+    };
+
+    let protocol_fee_after_host_fee = protocol_fee.checked_sub(host_fee).context("overflow")?;
+
+    let amount_into_bin = amount_in_with_fees.checked_sub(fee).context("overflow")?;
+
+    if swap_for_y {
+        bin.amount_x = bin
+            .amount_x
+            .checked_add(amount_into_bin)
+            .context("overflow")?;
+        bin.amount_y = bin.amount_y.checked_sub(amount_out).context("overflow")?;
+    } else {
+        bin.amount_y = bin
+            .amount_y
+            .checked_add(amount_into_bin)
+            .context("overflow")?;
+        bin.amount_x = bin.amount_x.checked_sub(amount_out).context("overflow")?;
+    }
+
+    Ok(SwapResult {
+        amount_in_with_fees,
+        amount_out,
+        fee,
+        protocol_fee_after_host_fee,
+        host_fee,
+        is_exact_out_amount,
+    })
+}
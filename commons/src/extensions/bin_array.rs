@@ -1,17 +1,28 @@
 use crate::*;
 use num_integer::Integer;
-use solana_sdk::{instruction::AccountMeta, pubkey::Pubkey};
+use solana_sdk::{account::Account, instruction::AccountMeta, pubkey::Pubkey};
+use std::collections::HashMap;
+use std::iter::Zip;
+use std::ops::RangeInclusive;
+use std::slice::Iter;
 
 pub trait BinArrayExtension {
+    fn index(&self) -> i64;
     fn is_bin_id_within_range(&self, bin_id: i32) -> Result<bool>;
     fn get_bin_index_in_array(&self, bin_id: i32) -> Result<usize>;
+    /// Q64.64 price of the lowest and highest bin of the bin array
+    fn price_range(&self, bin_step: u16) -> Result<(u128, u128)>;
 
     fn get_bin_array_lower_upper_bin_id(index: i32) -> Result<(i32, i32)>;
     fn bin_id_to_bin_array_index(bin_id: i32) -> Result<i32>;
+    /// Index of the bin array containing `bin_id`, and the offset of the bin in its `bins`
+    fn bin_id_to_array_and_offset(bin_id: i32) -> Result<(i32, usize)>;
     fn bin_id_to_bin_array_key(lb_pair: Pubkey, bin_id: i32) -> Result<Pubkey>;
 
     fn get_bin_mut<'a>(&'a mut self, bin_id: i32) -> Result<&'a mut Bin>;
     fn get_bin<'a>(&'a self, bin_id: i32) -> Result<&'a Bin>;
+    /// Bins of the bin array with their bin id, in ascending bin id
+    fn iter_bins(&self) -> Result<Zip<RangeInclusive<i32>, Iter<'_, Bin>>>;
 
     fn get_bin_array_account_metas_coverage(
         lower_bin_id: i32,
@@ -23,6 +34,10 @@ pub trait BinArrayExtension {
 }
 
 impl BinArrayExtension for BinArray {
+    fn index(&self) -> i64 {
+        self.index
+    }
+
     fn get_bin_array_lower_upper_bin_id(index: i32) -> Result<(i32, i32)> {
         let lower_bin_id = index
             .checked_mul(MAX_BIN_PER_ARRAY as i32)
@@ -52,6 +67,12 @@ impl BinArrayExtension for BinArray {
         Ok(&self.bins[self.get_bin_index_in_array(bin_id)?])
     }
 
+    fn iter_bins(&self) -> Result<Zip<RangeInclusive<i32>, Iter<'_, Bin>>> {
+        let (lower_bin_id, upper_bin_id) =
+            BinArray::get_bin_array_lower_upper_bin_id(self.index as i32)?;
+        Ok((lower_bin_id..=upper_bin_id).zip(self.bins.iter()))
+    }
+
     fn get_bin_index_in_array(&self, bin_id: i32) -> Result<usize> {
         ensure!(self.is_bin_id_within_range(bin_id)?, "Bin id out of range");
         let (lower_bin_id, _) = BinArray::get_bin_array_lower_upper_bin_id(self.index as i32)?;
@@ -59,6 +80,15 @@ impl BinArrayExtension for BinArray {
         Ok(index as usize)
     }
 
+    fn price_range(&self, bin_step: u16) -> Result<(u128, u128)> {
+        let (lower_bin_id, upper_bin_id) =
+            BinArray::get_bin_array_lower_upper_bin_id(self.index as i32)?;
+        Ok((
+            get_price_from_id(lower_bin_id, bin_step)?,
+            get_price_from_id(upper_bin_id, bin_step)?,
+        ))
+    }
+
     fn bin_id_to_bin_array_index(bin_id: i32) -> Result<i32> {
         let (idx, rem) = bin_id.div_rem(&(MAX_BIN_PER_ARRAY as i32));
 
@@ -69,6 +99,13 @@ impl BinArrayExtension for BinArray {
         }
     }
 
+    fn bin_id_to_array_and_offset(bin_id: i32) -> Result<(i32, usize)> {
+        let bin_array_index = Self::bin_id_to_bin_array_index(bin_id)?;
+        let (lower_bin_id, _) = Self::get_bin_array_lower_upper_bin_id(bin_array_index)?;
+        let offset = bin_id.checked_sub(lower_bin_id).context("overflow")?;
+        Ok((bin_array_index, offset as usize))
+    }
+
     fn bin_id_to_bin_array_key(lb_pair: Pubkey, bin_id: i32) -> Result<Pubkey> {
         let bin_array_index = Self::bin_id_to_bin_array_index(bin_id)?;
         Ok(derive_bin_array_pda(lb_pair, bin_array_index.into()).0)
@@ -105,3 +142,533 @@ impl BinArrayExtension for BinArray {
             .collect())
     }
 }
+
+/// Recover the index of a bin array pubkey of the pair by deriving the PDA of every index in `search_range`.
+/// Prefer reading `index` from the bin array account when it's available.
+pub fn try_bin_array_index_from_pubkey(
+    lb_pair_pubkey: Pubkey,
+    candidate: Pubkey,
+    search_range: RangeInclusive<i32>,
+) -> Option<i32> {
+    search_range
+        .into_iter()
+        .find(|index| derive_bin_array_pda(lb_pair_pubkey, (*index).into()).0 == candidate)
+}
+
+/// Find bins holding token amount below the threshold on the non empty side. Returns (bin_id, amount_x, amount_y) sorted by bin id.
+pub fn find_dust_bins<'a>(
+    bin_arrays: impl IntoIterator<Item = &'a BinArray>,
+    threshold_x: u64,
+    threshold_y: u64,
+) -> Result<Vec<(i32, u64, u64)>> {
+    let mut dust_bins = vec![];
+
+    for bin_array in bin_arrays {
+        for (bin_id, bin) in bin_array.iter_bins()? {
+            let is_dust_x = bin.amount_x > 0 && bin.amount_x < threshold_x;
+            let is_dust_y = bin.amount_y > 0 && bin.amount_y < threshold_y;
+
+            if is_dust_x || is_dust_y {
+                dust_bins.push((bin_id, bin.amount_x, bin.amount_y));
+            }
+        }
+    }
+
+    dust_bins.sort_by_key(|(bin_id, _, _)| *bin_id);
+
+    Ok(dust_bins)
+}
+
+/// Sum the liquidity supply of bins within [lower_bin_id, upper_bin_id].
+pub fn total_liquidity_supply<'a>(
+    bin_arrays: impl IntoIterator<Item = &'a BinArray>,
+    lower_bin_id: i32,
+    upper_bin_id: i32,
+) -> Result<u128> {
+    let mut total_liquidity_supply = 0u128;
+
+    for bin_array in bin_arrays {
+        for (bin_id, bin) in bin_array.iter_bins()? {
+            if (lower_bin_id..=upper_bin_id).contains(&bin_id) {
+                total_liquidity_supply = total_liquidity_supply
+                    .checked_add(bin.liquidity_supply)
+                    .context("overflow")?;
+            }
+        }
+    }
+
+    Ok(total_liquidity_supply)
+}
+
+/// Sum of the token X and Y amounts of every bin in `bin_arrays`, as (reserve_x, reserve_y).
+/// Covers the pool liquidity only when every bin array with liquidity is provided. The reserve vaults also hold the protocol fee
+/// and the swap fees not yet claimed by the positions, so their balances are expected to be larger.
+pub fn compute_reserves<'a>(bin_arrays: impl IntoIterator<Item = &'a BinArray>) -> (u128, u128) {
+    let mut reserve_x = 0u128;
+    let mut reserve_y = 0u128;
+
+    for bin in bin_arrays
+        .into_iter()
+        .flat_map(|bin_array| bin_array.bins.iter())
+    {
+        reserve_x += u128::from(bin.amount_x);
+        reserve_y += u128::from(bin.amount_y);
+    }
+
+    (reserve_x, reserve_y)
+}
+
+/// Snapshot of every bin with liquidity in `bin_arrays`.
+pub fn export_liquidity_book<'a>(
+    lb_pair: &LbPair,
+    bin_arrays: impl IntoIterator<Item = &'a BinArray>,
+) -> Result<LiquidityBookSnapshot> {
+    let mut bins = vec![];
+
+    for bin_array in bin_arrays {
+        for (bin_id, bin) in bin_array.iter_bins()? {
+            if bin.amount_x == 0 && bin.amount_y == 0 {
+                continue;
+            }
+
+            bins.push(LiquidityBookBin {
+                bin_id,
+                price: get_price_from_id(bin_id, lb_pair.bin_step)?,
+                amount_x: bin.amount_x,
+                amount_y: bin.amount_y,
+                liquidity_supply: bin.liquidity_supply,
+            });
+        }
+    }
+
+    bins.sort_by_key(|bin| bin.bin_id);
+
+    Ok(LiquidityBookSnapshot {
+        active_id: lb_pair.active_id,
+        bin_step: lb_pair.bin_step,
+        bins,
+    })
+}
+
+/// Amount of token X and Y returned by withdrawing `liquidity_share` from each bin of `shares`, which is (bin_id, liquidity_share).
+/// With [`TransferFeeMode::Net`], the transfer fee of the total withdrawn amount of each token is deducted.
+pub fn compute_withdraw_amounts<'a>(
+    bin_arrays: impl IntoIterator<Item = &'a BinArray>,
+    shares: &[(i32, u128)],
+    transfer_fee_mode: TransferFeeMode,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+    epoch: u64,
+) -> Result<(u64, u64)> {
+    let bin_arrays = bin_arrays
+        .into_iter()
+        .map(|bin_array| (bin_array.index as i32, bin_array))
+        .collect::<HashMap<_, _>>();
+
+    let mut total_x = 0u64;
+    let mut total_y = 0u64;
+
+    for (bin_id, liquidity_share) in shares {
+        let bin_array_index = BinArray::bin_id_to_bin_array_index(*bin_id)?;
+        let bin = bin_arrays
+            .get(&bin_array_index)
+            .with_context(|| format!("Bin array {} not found", bin_array_index))?
+            .get_bin(*bin_id)?;
+
+        ensure!(
+            *liquidity_share <= bin.liquidity_supply,
+            "Liquidity share exceeds liquidity supply of bin {}",
+            bin_id
+        );
+
+        if *liquidity_share == 0 {
+            continue;
+        }
+
+        let (amount_x, amount_y) = bin.calculate_out_amount(*liquidity_share)?;
+        total_x = total_x.checked_add(amount_x).context("overflow")?;
+        total_y = total_y.checked_add(amount_y).context("overflow")?;
+    }
+
+    let total_x = transfer_fee_mode.transfer_fee_excluded_amount(mint_x_account, total_x, epoch)?;
+    let total_y = transfer_fee_mode.transfer_fee_excluded_amount(mint_y_account, total_y, epoch)?;
+
+    Ok((total_x, total_y))
+}
+
+/// Per bin change between 2 snapshots of the bin arrays of the same pair, sorted by bin id. Bins without any change are omitted.
+/// Bin array missing from one of the snapshots is treated as empty.
+pub fn diff_liquidity(
+    before: &HashMap<Pubkey, BinArray>,
+    after: &HashMap<Pubkey, BinArray>,
+) -> Result<Vec<BinLiquidityDelta>> {
+    let empty_bin_array: BinArray = bytemuck::Zeroable::zeroed();
+    let mut deltas = vec![];
+
+    let bin_array_pubkeys = before
+        .keys()
+        .chain(after.keys().filter(|key| !before.contains_key(key)));
+
+    for bin_array_pubkey in bin_array_pubkeys {
+        let before_bin_array = before.get(bin_array_pubkey);
+        let after_bin_array = after.get(bin_array_pubkey);
+
+        let Some(bin_array_index) = before_bin_array.or(after_bin_array).map(|b| b.index) else {
+            continue;
+        };
+        let (lower_bin_id, _) = BinArray::get_bin_array_lower_upper_bin_id(bin_array_index as i32)?;
+
+        let before_bins = before_bin_array.unwrap_or(&empty_bin_array).bins.iter();
+        let after_bins = after_bin_array.unwrap_or(&empty_bin_array).bins.iter();
+
+        for (i, (before_bin, after_bin)) in before_bins.zip(after_bins).enumerate() {
+            let delta_x = i128::from(after_bin.amount_x) - i128::from(before_bin.amount_x);
+            let delta_y = i128::from(after_bin.amount_y) - i128::from(before_bin.amount_y);
+            let delta_liquidity_supply = i128::try_from(after_bin.liquidity_supply)
+                .context("overflow")?
+                .checked_sub(i128::try_from(before_bin.liquidity_supply).context("overflow")?)
+                .context("overflow")?;
+
+            if delta_x == 0 && delta_y == 0 && delta_liquidity_supply == 0 {
+                continue;
+            }
+
+            deltas.push(BinLiquidityDelta {
+                bin_id: lower_bin_id.checked_add(i as i32).context("overflow")?,
+                delta_x,
+                delta_y,
+                delta_liquidity_supply,
+            });
+        }
+    }
+
+    deltas.sort_by_key(|delta| delta.bin_id);
+
+    Ok(deltas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token_2022::tests::new_transfer_fee_mint_account;
+
+    fn new_test_bin_arrays(indexes: &[i64]) -> Vec<BinArray> {
+        indexes
+            .iter()
+            .map(|index| {
+                let mut bin_array: BinArray = bytemuck::Zeroable::zeroed();
+                bin_array.index = *index;
+                bin_array
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_find_dust_bins() {
+        let mut bin_arrays = new_test_bin_arrays(&[0, -1]);
+
+        bin_arrays[0].get_bin_mut(0).unwrap().amount_x = 5;
+        bin_arrays[0].get_bin_mut(0).unwrap().amount_y = 1_000;
+        bin_arrays[0].get_bin_mut(1).unwrap().amount_x = 1_000;
+        bin_arrays[0].get_bin_mut(69).unwrap().amount_x = 9;
+        bin_arrays[1].get_bin_mut(-1).unwrap().amount_y = 99;
+        bin_arrays[1].get_bin_mut(-2).unwrap().amount_y = 100;
+
+        let dust_bins = find_dust_bins(&bin_arrays, 10, 100).unwrap();
+        assert_eq!(dust_bins, vec![(-1, 0, 99), (0, 5, 1_000), (69, 9, 0)]);
+    }
+
+    #[test]
+    fn test_total_liquidity_supply() {
+        let mut bin_arrays = new_test_bin_arrays(&[0, -1]);
+
+        bin_arrays[0].get_bin_mut(0).unwrap().liquidity_supply = 100;
+        bin_arrays[0].get_bin_mut(1).unwrap().liquidity_supply = 200;
+        bin_arrays[1].get_bin_mut(-1).unwrap().liquidity_supply = 300;
+        bin_arrays[1].get_bin_mut(-2).unwrap().liquidity_supply = 400;
+
+        assert_eq!(total_liquidity_supply(&bin_arrays, -1, 1).unwrap(), 600);
+        assert_eq!(total_liquidity_supply(&bin_arrays, -10, 10).unwrap(), 1_000);
+        assert_eq!(total_liquidity_supply(&bin_arrays, 2, 10).unwrap(), 0);
+
+        bin_arrays[0].get_bin_mut(2).unwrap().liquidity_supply = u128::MAX;
+        assert!(total_liquidity_supply(&bin_arrays, 0, 2).is_err());
+    }
+
+    #[test]
+    fn test_compute_reserves() {
+        assert_eq!(compute_reserves(&[]), (0, 0));
+
+        let mut bin_arrays = new_test_bin_arrays(&[0, -1]);
+
+        bin_arrays[0].get_bin_mut(0).unwrap().amount_x = u64::MAX;
+        bin_arrays[0].get_bin_mut(0).unwrap().amount_y = 100;
+        bin_arrays[0].get_bin_mut(1).unwrap().amount_x = u64::MAX;
+        bin_arrays[1].get_bin_mut(-1).unwrap().amount_y = 200;
+
+        assert_eq!(
+            compute_reserves(&bin_arrays),
+            (u128::from(u64::MAX) * 2, 300)
+        );
+    }
+
+    #[test]
+    fn test_export_liquidity_book() {
+        let mut lb_pair: LbPair = bytemuck::Zeroable::zeroed();
+        lb_pair.bin_step = 10;
+        lb_pair.active_id = 0;
+
+        let mut bin_arrays = new_test_bin_arrays(&[0, -1]);
+
+        let bin = bin_arrays[0].get_bin_mut(0).unwrap();
+        bin.amount_x = 100;
+        bin.amount_y = 200;
+        bin.liquidity_supply = 300;
+        bin_arrays[1].get_bin_mut(-2).unwrap().amount_y = 400;
+
+        let snapshot = export_liquidity_book(&lb_pair, &bin_arrays).unwrap();
+        assert_eq!(snapshot.active_id, 0);
+        assert_eq!(snapshot.bin_step, 10);
+        assert_eq!(
+            snapshot.bins,
+            vec![
+                LiquidityBookBin {
+                    bin_id: -2,
+                    price: get_price_from_id(-2, 10).unwrap(),
+                    amount_x: 0,
+                    amount_y: 400,
+                    liquidity_supply: 0,
+                },
+                LiquidityBookBin {
+                    bin_id: 0,
+                    price: ONE,
+                    amount_x: 100,
+                    amount_y: 200,
+                    liquidity_supply: 300,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bin_id_to_array_and_offset() {
+        for (bin_id, expected) in [
+            (0, (0, 0)),
+            (69, (0, 69)),
+            (70, (1, 0)),
+            (-1, (-1, 69)),
+            (-70, (-1, 0)),
+            (-71, (-2, 69)),
+        ] {
+            assert_eq!(
+                BinArray::bin_id_to_array_and_offset(bin_id).unwrap(),
+                expected
+            );
+
+            let mut bin_array: BinArray = bytemuck::Zeroable::zeroed();
+            bin_array.index = expected.0.into();
+            assert_eq!(
+                bin_array.get_bin_index_in_array(bin_id).unwrap(),
+                expected.1
+            );
+        }
+    }
+
+    #[test]
+    fn test_iter_bins() {
+        let mut bin_array: BinArray = bytemuck::Zeroable::zeroed();
+        bin_array.index = -1;
+        bin_array.get_bin_mut(-1).unwrap().amount_x = 1_000;
+
+        let bin_ids = bin_array
+            .iter_bins()
+            .unwrap()
+            .map(|(bin_id, _)| bin_id)
+            .collect::<Vec<_>>();
+        assert_eq!(bin_ids, (-70..=-1).collect::<Vec<_>>());
+
+        let (bin_id, bin) = bin_array.iter_bins().unwrap().last().unwrap();
+        assert_eq!(bin_id, -1);
+        assert_eq!(bin.amount_x, 1_000);
+    }
+
+    #[test]
+    fn test_price_range() {
+        let mut bin_array: BinArray = bytemuck::Zeroable::zeroed();
+        assert_eq!(
+            bin_array.price_range(10).unwrap(),
+            (ONE, get_price_from_id(69, 10).unwrap())
+        );
+
+        bin_array.index = -1;
+        let (low_price, high_price) = bin_array.price_range(10).unwrap();
+        assert_eq!(low_price, get_price_from_id(-70, 10).unwrap());
+        assert_eq!(high_price, get_price_from_id(-1, 10).unwrap());
+        assert!(high_price < ONE);
+    }
+
+    #[test]
+    fn test_try_bin_array_index_from_pubkey() {
+        let lb_pair_pubkey = Pubkey::new_unique();
+        let candidate = derive_bin_array_pda(lb_pair_pubkey, -3).0;
+
+        assert_eq!(
+            try_bin_array_index_from_pubkey(lb_pair_pubkey, candidate, -5..=5),
+            Some(-3)
+        );
+        assert_eq!(
+            try_bin_array_index_from_pubkey(lb_pair_pubkey, candidate, 0..=5),
+            None
+        );
+        assert_eq!(
+            try_bin_array_index_from_pubkey(Pubkey::new_unique(), candidate, -5..=5),
+            None
+        );
+    }
+
+    #[test]
+    fn test_compute_withdraw_amounts() {
+        let mut bin_arrays = new_test_bin_arrays(&[0, -1]);
+
+        let bin = bin_arrays[0].get_bin_mut(0).unwrap();
+        bin.amount_x = 1_000;
+        bin.amount_y = 2_000;
+        bin.liquidity_supply = 100;
+
+        let bin = bin_arrays[1].get_bin_mut(-1).unwrap();
+        bin.amount_y = 3_000;
+        bin.liquidity_supply = 300;
+
+        let mint_account = Account {
+            owner: anchor_spl::token::spl_token::ID,
+            ..Default::default()
+        };
+        let shares = [(0, 50), (-1, 100)];
+
+        assert_eq!(
+            compute_withdraw_amounts(
+                &bin_arrays,
+                &shares,
+                TransferFeeMode::Net,
+                &mint_account,
+                &mint_account,
+                0
+            )
+            .unwrap(),
+            (500, 2_000)
+        );
+
+        // 1% transfer fee on token Y
+        let fee_mint_account = new_transfer_fee_mint_account(100, u64::MAX);
+        assert_eq!(
+            compute_withdraw_amounts(
+                &bin_arrays,
+                &shares,
+                TransferFeeMode::Net,
+                &mint_account,
+                &fee_mint_account,
+                0
+            )
+            .unwrap(),
+            (500, 1_980)
+        );
+        assert_eq!(
+            compute_withdraw_amounts(
+                &bin_arrays,
+                &shares,
+                TransferFeeMode::Gross,
+                &mint_account,
+                &fee_mint_account,
+                0
+            )
+            .unwrap(),
+            (500, 2_000)
+        );
+
+        // Share above supply, and bin array not provided
+        for shares in [[(0, 101)], [(70, 1)]] {
+            assert!(compute_withdraw_amounts(
+                &bin_arrays,
+                &shares,
+                TransferFeeMode::Gross,
+                &mint_account,
+                &mint_account,
+                0
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_diff_liquidity() {
+        let lb_pair_pubkey = Pubkey::new_unique();
+        let mut before = new_test_bin_arrays(&[0, -1])
+            .into_iter()
+            .map(|bin_array| {
+                (
+                    derive_bin_array_pda(lb_pair_pubkey, bin_array.index).0,
+                    bin_array,
+                )
+            })
+            .collect::<HashMap<_, _>>();
+
+        let bin_array_0 = derive_bin_array_pda(lb_pair_pubkey, 0).0;
+        let bin_array_1 = derive_bin_array_pda(lb_pair_pubkey, 1).0;
+        let bin_array_minus_1 = derive_bin_array_pda(lb_pair_pubkey, -1).0;
+
+        let bin = before
+            .get_mut(&bin_array_0)
+            .unwrap()
+            .get_bin_mut(0)
+            .unwrap();
+        bin.amount_x = 1_000;
+        bin.amount_y = 1_000;
+        bin.liquidity_supply = 2_000;
+        before
+            .get_mut(&bin_array_minus_1)
+            .unwrap()
+            .get_bin_mut(-1)
+            .unwrap()
+            .amount_y = 500;
+
+        assert!(diff_liquidity(&before, &before).unwrap().is_empty());
+
+        let mut after = before.clone();
+        // Swap in the active bin
+        let bin = after.get_mut(&bin_array_0).unwrap().get_bin_mut(0).unwrap();
+        bin.amount_x = 1_100;
+        bin.amount_y = 900;
+        // Bin array -1 is missing from the snapshot, treated as empty
+        after.remove(&bin_array_minus_1);
+        // Deposit into bin 70 of a new bin array
+        let [mut bin_array] = new_test_bin_arrays(&[1]).try_into().unwrap();
+        let bin = bin_array.get_bin_mut(70).unwrap();
+        bin.amount_x = 300;
+        bin.liquidity_supply = 300;
+        after.insert(bin_array_1, bin_array);
+
+        assert_eq!(
+            diff_liquidity(&before, &after).unwrap(),
+            vec![
+                BinLiquidityDelta {
+                    bin_id: -1,
+                    delta_x: 0,
+                    delta_y: -500,
+                    delta_liquidity_supply: 0,
+                },
+                BinLiquidityDelta {
+                    bin_id: 0,
+                    delta_x: 100,
+                    delta_y: -100,
+                    delta_liquidity_supply: 0,
+                },
+                BinLiquidityDelta {
+                    bin_id: 70,
+                    delta_x: 300,
+                    delta_y: 0,
+                    delta_liquidity_supply: 300,
+                },
+            ]
+        );
+    }
+}
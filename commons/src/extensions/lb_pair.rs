@@ -2,11 +2,15 @@ use crate::*;
 use anchor_spl::token::spl_token;
 use anchor_spl::token_2022::spl_token_2022;
 use ruint::aliases::U1024;
+use solana_sdk::clock::{Clock, DEFAULT_MS_PER_SLOT};
 use solana_sdk::pubkey::Pubkey;
 use std::ops::Deref;
 use std::ops::Shl;
 use std::ops::Shr;
 
+// Bitmap navigation treats the internal bitmap as a U1024 covering [-BIN_ARRAY_BITMAP_SIZE, BIN_ARRAY_BITMAP_SIZE)
+const _: () = assert!(BIN_ARRAY_BITMAP_SIZE as usize * 2 == U1024::BITS);
+
 pub trait LbPairExtension {
     fn bitmap_range() -> (i32, i32);
     fn get_bin_array_offset(bin_array_index: i32) -> usize;
@@ -14,11 +18,34 @@ pub trait LbPairExtension {
     fn status(&self) -> Result<PairStatusWrapper>;
     fn pair_type(&self) -> Result<PairTypeWrapper>;
     fn activation_type(&self) -> Result<ActivationTypeWrapper>;
+    fn activation_countdown(&self, clock: &Clock) -> Result<Option<ActivationCountdown>>;
+    fn activation_slot(&self, clock: &Clock) -> Result<Option<u64>>;
+    fn launch_info(&self, clock: &Clock) -> Result<LaunchInfo>;
+    fn active_liquidity(&self, active_bin_array: &BinArray) -> Result<u128>;
+    fn oracle(&self) -> Pubkey;
+    fn oracle_initialized(&self) -> bool;
+    fn protocol_fee_ui(&self, x_decimals: u8, y_decimals: u8) -> (f64, f64);
     fn compute_fee(&self, amount: u64) -> Result<u64>;
     fn get_total_fee(&self) -> Result<u128>;
+    fn get_total_fee_bps(&self) -> Result<u64>;
+    fn dynamic_fee_bounds(&self) -> Result<(u64, u64)>;
+    fn effective_spread_bps(&self) -> Result<u64>;
     fn get_base_fee(&self) -> Result<u128>;
     fn get_variable_fee(&self) -> Result<u128>;
     fn get_token_programs(&self) -> Result<[Pubkey; 2]>;
+    fn swap_for_y(&self, input_mint: &Pubkey) -> Result<bool>;
+    /// Base token of the pair, which is token X. Bin prices are the amount of token Y per token X, so they are quoted in units of token Y per 1 token X
+    fn base_mint(&self) -> Pubkey;
+    /// Quote token of the pair, which is token Y. Bin prices are denominated in token Y
+    fn quote_mint(&self) -> Pubkey;
+    fn surrounding_bin_prices(&self, count: u32) -> Result<Vec<(i32, u128)>>;
+    fn liquidity_concentration<'a>(
+        &self,
+        bin_arrays: impl IntoIterator<Item = &'a BinArray>,
+    ) -> Result<f64>;
+    /// Fraction of the value of the bins held as token X, in [0, 1], with token X valued at the price of its bin
+    fn inventory_skew<'a>(&self, bin_arrays: impl IntoIterator<Item = &'a BinArray>)
+        -> Result<f64>;
     fn compute_variable_fee(&self, volatility_accumulator: u32) -> Result<u128>;
     fn compute_protocol_fee(&self, fee_amount: u64) -> Result<u64>;
     fn compute_fee_from_amount(&self, amount_with_fees: u64) -> Result<u64>;
@@ -32,6 +59,7 @@ pub trait LbPairExtension {
     fn update_references(&mut self, current_timestamp: i64) -> Result<()>;
     fn update_volatility_accumulator(&mut self) -> Result<()>;
     fn advance_active_bin(&mut self, swap_for_y: bool) -> Result<()>;
+    fn set_active_id(&mut self, id: i32) -> Result<()>;
 }
 
 impl LbPairExtension for LbPair {
@@ -60,6 +88,101 @@ impl LbPairExtension for LbPair {
         Ok(token_programs_id)
     }
 
+    fn swap_for_y(&self, input_mint: &Pubkey) -> Result<bool> {
+        if input_mint.eq(&self.token_x_mint) {
+            Ok(true)
+        } else if input_mint.eq(&self.token_y_mint) {
+            Ok(false)
+        } else {
+            Err(anyhow!("Mint {} is not part of the pair", input_mint))
+        }
+    }
+
+    fn base_mint(&self) -> Pubkey {
+        self.token_x_mint
+    }
+
+    fn quote_mint(&self) -> Pubkey {
+        self.token_y_mint
+    }
+
+    fn surrounding_bin_prices(&self, count: u32) -> Result<Vec<(i32, u128)>> {
+        let active_id = i64::from(self.active_id);
+        let lower_bin_id = std::cmp::max(active_id - i64::from(count), MIN_BIN_ID.into());
+        let upper_bin_id = std::cmp::min(active_id + i64::from(count), MAX_BIN_ID.into());
+
+        (lower_bin_id..=upper_bin_id)
+            .map(|bin_id| {
+                let bin_id = bin_id as i32;
+                Ok((bin_id, get_price_from_id(bin_id, self.bin_step)?))
+            })
+            .collect()
+    }
+
+    fn inventory_skew<'a>(
+        &self,
+        bin_arrays: impl IntoIterator<Item = &'a BinArray>,
+    ) -> Result<f64> {
+        let mut value_x = 0f64;
+        let mut value_y = 0f64;
+
+        for bin_array in bin_arrays {
+            for (bin_id, bin) in bin_array.iter_bins()? {
+                value_x += bin.value_x_in_y(bin_id, self.bin_step)?;
+                value_y += bin.amount_y as f64;
+            }
+        }
+
+        let total_value = value_x + value_y;
+        ensure!(total_value > 0.0, "Pair has no liquidity");
+
+        Ok(value_x / total_value)
+    }
+
+    // Gini coefficient of the bin liquidity, valued in token Y, across the bins between the lowest and highest bin with liquidity.
+    // 0 means liquidity is evenly distributed, and it approaches 1 when liquidity is concentrated in few bins.
+    fn liquidity_concentration<'a>(
+        &self,
+        bin_arrays: impl IntoIterator<Item = &'a BinArray>,
+    ) -> Result<f64> {
+        let mut bin_liquidities = vec![];
+
+        for bin_array in bin_arrays {
+            for (bin_id, bin) in bin_array.iter_bins()? {
+                bin_liquidities.push((bin_id, bin.value_in_y(bin_id, self.bin_step)?));
+            }
+        }
+
+        let bin_ids_with_liquidity = bin_liquidities
+            .iter()
+            .filter(|(_, liquidity)| *liquidity > 0.0)
+            .map(|(bin_id, _)| *bin_id);
+
+        let (Some(min_bin_id), Some(max_bin_id)) = (
+            bin_ids_with_liquidity.clone().min(),
+            bin_ids_with_liquidity.max(),
+        ) else {
+            return Ok(0.0);
+        };
+
+        let mut liquidities = bin_liquidities
+            .into_iter()
+            .filter(|(bin_id, _)| (min_bin_id..=max_bin_id).contains(bin_id))
+            .map(|(_, liquidity)| liquidity)
+            .collect::<Vec<_>>();
+        liquidities.sort_by(f64::total_cmp);
+
+        let n = liquidities.len() as f64;
+        let total_liquidity: f64 = liquidities.iter().sum();
+        let weighted_liquidity: f64 = liquidities
+            .iter()
+            .enumerate()
+            .map(|(i, liquidity)| (i + 1) as f64 * liquidity)
+            .sum();
+
+        Ok(2.0 * weighted_liquidity / (n * total_liquidity) - (n + 1.0) / n)
+    }
+
     fn pair_type(&self) -> Result<PairTypeWrapper> {
         Ok(self.pair_type.try_into()?)
     }
@@ -68,6 +191,81 @@ impl LbPairExtension for LbPair {
         Ok(self.activation_type.try_into()?)
     }
 
+    fn activation_countdown(&self, clock: &Clock) -> Result<Option<ActivationCountdown>> {
+        if !self.pair_type()?.eq(&PairType::Permission) {
+            return Ok(None);
+        }
+
+        let activation_type = self.activation_type()?;
+        let countdown = match activation_type.deref() {
+            ActivationType::Slot => self
+                .activation_point
+                .checked_sub(clock.slot)
+                .filter(|remaining| *remaining > 0)
+                .map(ActivationCountdown::Slots),
+            ActivationType::Timestamp => self
+                .activation_point
+                .checked_sub(clock.unix_timestamp as u64)
+                .filter(|remaining| *remaining > 0)
+                .map(ActivationCountdown::Seconds),
+        };
+
+        Ok(countdown)
+    }
+
+    // Slot at which the pair activates. Timestamp activated pairs are estimated with the target slot time, so the actual slot may drift
+    fn activation_slot(&self, clock: &Clock) -> Result<Option<u64>> {
+        let activation_slot = match self.activation_countdown(clock)? {
+            Some(ActivationCountdown::Slots(_)) => Some(self.activation_point),
+            Some(ActivationCountdown::Seconds(seconds)) => {
+                let remaining_slots = seconds
+                    .checked_mul(1000)
+                    .context("overflow")?
+                    .div_ceil(DEFAULT_MS_PER_SLOT);
+                Some(
+                    clock
+                        .slot
+                        .checked_add(remaining_slots)
+                        .context("overflow")?,
+                )
+            }
+            None => None,
+        };
+
+        Ok(activation_slot)
+    }
+
+    fn launch_info(&self, clock: &Clock) -> Result<LaunchInfo> {
+        Ok(LaunchInfo {
+            pair_type: self.pair_type()?.deref().clone(),
+            activation_type: self.activation_type()?.deref().clone(),
+            activation_point: self.activation_point,
+            activation_countdown: self.activation_countdown(clock)?,
+            is_liquidity_seeded: self.bin_array_bitmap.iter().any(|limb| *limb != 0),
+        })
+    }
+
+    // Liquidity supply of the active bin, which is the liquidity small swaps trade against
+    fn active_liquidity(&self, active_bin_array: &BinArray) -> Result<u128> {
+        Ok(active_bin_array.get_bin(self.active_id)?.liquidity_supply)
+    }
+
+    fn oracle(&self) -> Pubkey {
+        self.oracle
+    }
+
+    fn oracle_initialized(&self) -> bool {
+        self.oracle != Pubkey::default()
+    }
+
+    // The account only keeps the protocol fee not yet claimed. Lifetime LP fee totals are not stored on-chain.
+    fn protocol_fee_ui(&self, x_decimals: u8, y_decimals: u8) -> (f64, f64) {
+        (
+            self.protocol_fee.amount_x as f64 / 10f64.powi(x_decimals.into()),
+            self.protocol_fee.amount_y as f64 / 10f64.powi(y_decimals.into()),
+        )
+    }
+
     // 更新用于计算动态费用的参考值
     // 在 Meteora DLMM 中，交易手续费的一部分是根据市场波动性动态调整的，而这个函数就是实现该动态调整机制的关键一步
     fn update_references(&mut self, current_timestamp: i64) -> Result<()> {
@@ -201,6 +399,33 @@ impl LbPairExtension for LbPair {
         Ok(total_fee_rate_cap)
     }
 
+    // Total fee rate in bps, capped at the max fee rate and rounded up to the next bps
+    fn get_total_fee_bps(&self) -> Result<u64> {
+        fee_rate_to_bps(self.get_total_fee()?)
+    }
+
+    // Fee range between no volatility, and volatility accumulator at maximum
+    fn dynamic_fee_bounds(&self) -> Result<(u64, u64)> {
+        let base_fee = self.get_base_fee()?;
+        let max_fee = base_fee
+            .checked_add(self.compute_variable_fee(self.parameters.max_volatility_accumulator)?)
+            .context("overflow")?;
+
+        Ok((
+            fee_rate_to_bps(std::cmp::min(base_fee, MAX_FEE_RATE.into()))?,
+            fee_rate_to_bps(std::cmp::min(max_fee, MAX_FEE_RATE.into()))?,
+        ))
+    }
+
+    // Round trip of a dust amount pays the total fee in both directions, and the opposite side of the book starts 1 bin away
+    fn effective_spread_bps(&self) -> Result<u64> {
+        self.get_total_fee_bps()?
+            .checked_mul(2)
+            .context("overflow")?
+            .checked_add(self.bin_step.into())
+            .context("overflow")
+    }
+
     fn compute_fee(&self, amount: u64) -> Result<u64> {
         let total_fee_rate = self.get_total_fee()?;
         let denominator = u128::from(FEE_PRECISION)
@@ -239,6 +464,22 @@ impl LbPairExtension for LbPair {
         Ok(())
     }
 
+    // Place the active bin for simulation. Index reference is moved along, so the jump doesn't count as volatility in the next swap
+    fn set_active_id(&mut self, id: i32) -> Result<()> {
+        ensure!(
+            (MIN_BIN_ID..=MAX_BIN_ID).contains(&id),
+            "Bin id {} is out of range [{}, {}]",
+            id,
+            MIN_BIN_ID,
+            MAX_BIN_ID
+        );
+
+        self.active_id = id;
+        self.v_parameters.index_reference = id;
+
+        Ok(())
+    }
+
     fn compute_protocol_fee(&self, fee_amount: u64) -> Result<u64> {
         let protocol_fee = u128::from(fee_amount)
             .checked_mul(self.parameters.protocol_share.into())
@@ -283,9 +524,19 @@ impl LbPairExtension for LbPair {
         swap_for_y: bool,
         start_array_index: i32,
     ) -> Result<(i32, bool)> {
+        let (min_bitmap_id, max_bitmap_id) = LbPair::bitmap_range();
+
+        // Start index is beyond the internal bitmap, there's no liquidity to search within the range.
+        if self.is_overflow_default_bin_array_bitmap(start_array_index) {
+            if swap_for_y {
+                return Ok((min_bitmap_id.checked_sub(1).context("overflow")?, false));
+            } else {
+                return Ok((max_bitmap_id.checked_add(1).context("overflow")?, false));
+            }
+        }
+
         let bin_array_bitmap = U1024::from_limbs(self.bin_array_bitmap);
         let array_offset: usize = Self::get_bin_array_offset(start_array_index);
-        let (min_bitmap_id, max_bitmap_id) = LbPair::bitmap_range();
         if swap_for_y {
             let bitmap_range: usize = max_bitmap_id
                 .checked_sub(min_bitmap_id)
@@ -322,3 +573,763 @@ impl LbPairExtension for LbPair {
         }
     }
 }
+
+/// Implied yearly LP fee APR of the pair, from a daily swap volume and the TVL, both in token Y.
+/// Assumes every swap only pays the base fee, so the variable fee collected during volatile periods is not included, and the APR is a lower bound.
+/// Protocol share of the fee is excluded, and the daily fee is annualized without compounding.
+pub fn implied_fee_apr(lb_pair: &LbPair, daily_volume_in_y: u128, tvl_in_y: u128) -> Result<f64> {
+    ensure!(tvl_in_y > 0, "TVL must be greater than 0");
+
+    let base_fee_rate = lb_pair.get_base_fee()? as f64 / FEE_PRECISION as f64;
+    let lp_share = 1.0 - f64::from(lb_pair.parameters.protocol_share) / f64::from(BASIS_POINT_MAX);
+
+    let daily_lp_fee = daily_volume_in_y as f64 * base_fee_rate * lp_share;
+
+    Ok(daily_lp_fee * 365.0 / tvl_in_y as f64)
+}
+
+/// Token ratio of an equal value deposit into the active bin, as (x_per_unit, y_per_unit) in Q64.64.
+/// Depositing `amount_x` requires `amount_x * y_per_unit / x_per_unit` of token Y, as 1 token X is worth `price` token Y in the active bin.
+pub fn active_bin_deposit_ratio(lb_pair: &LbPair) -> Result<(u128, u128)> {
+    let price = get_price_from_id(lb_pair.active_id, lb_pair.bin_step)?;
+    Ok((ONE, price))
+}
+
+// Rounded up to the next bps
+fn fee_rate_to_bps(fee_rate: u128) -> Result<u64> {
+    safe_mul_div_cast(
+        fee_rate,
+        BASIS_POINT_MAX as u128,
+        FEE_PRECISION.into(),
+        Rounding::Up,
+    )
+}
+
+/// Imbalance between the bid and ask liquidity within `window_bins` of the active bin, valued in token Y, in [-1, 1].
+/// Bids are token Y in the active bin and below, asks are token X in the active bin and above.
+/// Positive when there is more bid than ask liquidity, and 0 when the window has no liquidity.
+pub fn liquidity_imbalance<'a>(
+    lb_pair: &LbPair,
+    bin_arrays: impl IntoIterator<Item = &'a BinArray>,
+    window_bins: u32,
+) -> Result<f64> {
+    let active_id = i64::from(lb_pair.active_id);
+    let lower_bin_id = active_id - i64::from(window_bins);
+    let upper_bin_id = active_id + i64::from(window_bins);
+
+    let mut bid_liquidity = 0f64;
+    let mut ask_liquidity = 0f64;
+
+    for bin_array in bin_arrays {
+        for (bin_id, bin) in bin_array.iter_bins()? {
+            if !(lower_bin_id..=upper_bin_id).contains(&i64::from(bin_id)) {
+                continue;
+            }
+
+            if bin_id <= lb_pair.active_id {
+                bid_liquidity += bin.amount_y as f64;
+            }
+
+            if bin_id >= lb_pair.active_id {
+                ask_liquidity += bin.value_x_in_y(bin_id, lb_pair.bin_step)?;
+            }
+        }
+    }
+
+    let total_liquidity = bid_liquidity + ask_liquidity;
+    if total_liquidity == 0.0 {
+        return Ok(0.0);
+    }
+
+    Ok((bid_liquidity - ask_liquidity) / total_liquidity)
+}
+
+/// Number of bins holding liquidity within `window_bins` of the active bin, active bin included. Bins of bin arrays not provided are not counted.
+/// Complements [`crate::liquidity_span`], which measures the width of the whole liquidity range.
+pub fn active_bin_density<'a>(
+    lb_pair: &LbPair,
+    bin_arrays: impl IntoIterator<Item = &'a BinArray>,
+    window_bins: u32,
+) -> Result<u32> {
+    let active_id = i64::from(lb_pair.active_id);
+    let lower_bin_id = active_id - i64::from(window_bins);
+    let upper_bin_id = active_id + i64::from(window_bins);
+
+    let mut density = 0u32;
+
+    for bin_array in bin_arrays {
+        for (bin_id, bin) in bin_array.iter_bins()? {
+            if (lower_bin_id..=upper_bin_id).contains(&i64::from(bin_id))
+                && (bin.amount_x > 0 || bin.amount_y > 0)
+            {
+                density = density.checked_add(1).context("overflow")?;
+            }
+        }
+    }
+
+    Ok(density)
+}
+
+/// Liquidity of the pair as an implied distribution of price. Returns the Q64.64 price of every bin holding liquidity, in ascending order, with
+/// the share of the pair value it holds, valued in token Y. Weights sum to 1.
+pub fn implied_price_distribution<'a>(
+    lb_pair: &LbPair,
+    bin_arrays: impl IntoIterator<Item = &'a BinArray>,
+) -> Result<Vec<(u128, f64)>> {
+    let mut bin_values = vec![];
+
+    for bin_array in bin_arrays {
+        for (bin_id, bin) in bin_array.iter_bins()? {
+            if bin.amount_x == 0 && bin.amount_y == 0 {
+                continue;
+            }
+
+            let price = get_price_from_id(bin_id, lb_pair.bin_step)?;
+            bin_values.push((price, bin.value_in_y(bin_id, lb_pair.bin_step)?));
+        }
+    }
+
+    let total_value: f64 = bin_values.iter().map(|(_, value)| value).sum();
+    ensure!(total_value > 0.0, "Pair has no liquidity");
+
+    bin_values.sort_by_key(|(price, _)| *price);
+
+    Ok(bin_values
+        .into_iter()
+        .map(|(price, value)| (price, value / total_value))
+        .collect())
+}
+
+/// Ensure the pair account data has the layout this SDK was built for, before deserializing it into [`LbPair`].
+/// The account has no version field, and deserialization ignores trailing bytes, so a pair account of an upgraded program with a
+/// larger layout would otherwise be read silently, producing wrong quotes.
+pub fn validate_lb_pair_account_data(data: &[u8]) -> Result<()> {
+    let expected_len = LB_PAIR_ACCOUNT_DISCM.len() + std::mem::size_of::<LbPair>();
+
+    ensure!(
+        data.starts_with(&LB_PAIR_ACCOUNT_DISCM),
+        "Account is not a pair account"
+    );
+
+    if data.len() != expected_len {
+        return Err(QuoteError::UnsupportedPoolVersion {
+            len: data.len(),
+            expected_len,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Resolve the swap direction of swapping `amount_in` of `input_mint` for `output_mint`. Both mints must be the 2 distinct mints of the pair.
+pub fn resolve_swap_intent(
+    lb_pair: &LbPair,
+    input_mint: Pubkey,
+    output_mint: Pubkey,
+    amount_in: u64,
+) -> Result<SwapIntent> {
+    ensure!(
+        input_mint != output_mint,
+        "Input and output mint must be different"
+    );
+
+    let swap_for_y = lb_pair.swap_for_y(&input_mint)?;
+    let expected_output_mint = if swap_for_y {
+        lb_pair.token_y_mint
+    } else {
+        lb_pair.token_x_mint
+    };
+
+    ensure!(
+        output_mint == expected_output_mint,
+        "Mint {} is not part of the pair",
+        output_mint
+    );
+
+    Ok(SwapIntent {
+        swap_for_y,
+        amount_in,
+    })
+}
+
+/// Current total fee in bps of each pool. Error of a pool doesn't affect the others.
+pub fn fee_rates_for_pools(pools: &[&LbPair]) -> Vec<Result<u64>> {
+    pools.iter().map(|pool| pool.get_total_fee_bps()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_test_lb_pair(bin_array_indexes: &[i32]) -> LbPair {
+        let mut lb_pair: LbPair = bytemuck::Zeroable::zeroed();
+        for &bin_array_index in bin_array_indexes {
+            let offset = LbPair::get_bin_array_offset(bin_array_index);
+            lb_pair.bin_array_bitmap[offset / 64] |= 1 << (offset % 64);
+        }
+        lb_pair
+    }
+
+    #[test]
+    fn test_next_bin_array_index_with_liquidity_internal_at_max_boundary() {
+        let (min_bitmap_id, max_bitmap_id) = LbPair::bitmap_range();
+
+        let lb_pair = new_test_lb_pair(&[max_bitmap_id]);
+        assert_eq!(
+            lb_pair
+                .next_bin_array_index_with_liquidity_internal(true, max_bitmap_id)
+                .unwrap(),
+            (max_bitmap_id, true)
+        );
+        assert_eq!(
+            lb_pair
+                .next_bin_array_index_with_liquidity_internal(false, max_bitmap_id)
+                .unwrap(),
+            (max_bitmap_id, true)
+        );
+
+        let lb_pair = new_test_lb_pair(&[]);
+        assert_eq!(
+            lb_pair
+                .next_bin_array_index_with_liquidity_internal(true, max_bitmap_id)
+                .unwrap(),
+            (min_bitmap_id - 1, false)
+        );
+
+        // Beyond the boundary
+        let lb_pair = new_test_lb_pair(&[max_bitmap_id]);
+        assert_eq!(
+            lb_pair
+                .next_bin_array_index_with_liquidity_internal(true, max_bitmap_id + 1)
+                .unwrap(),
+            (min_bitmap_id - 1, false)
+        );
+        assert_eq!(
+            lb_pair
+                .next_bin_array_index_with_liquidity_internal(false, max_bitmap_id + 1)
+                .unwrap(),
+            (max_bitmap_id + 1, false)
+        );
+    }
+
+    #[test]
+    fn test_next_bin_array_index_with_liquidity_internal_at_min_boundary() {
+        let (min_bitmap_id, max_bitmap_id) = LbPair::bitmap_range();
+
+        let lb_pair = new_test_lb_pair(&[min_bitmap_id]);
+        assert_eq!(
+            lb_pair
+                .next_bin_array_index_with_liquidity_internal(true, min_bitmap_id)
+                .unwrap(),
+            (min_bitmap_id, true)
+        );
+        assert_eq!(
+            lb_pair
+                .next_bin_array_index_with_liquidity_internal(false, min_bitmap_id)
+                .unwrap(),
+            (min_bitmap_id, true)
+        );
+
+        let lb_pair = new_test_lb_pair(&[]);
+        assert_eq!(
+            lb_pair
+                .next_bin_array_index_with_liquidity_internal(false, min_bitmap_id)
+                .unwrap(),
+            (max_bitmap_id + 1, false)
+        );
+
+        // Beyond the boundary
+        let lb_pair = new_test_lb_pair(&[min_bitmap_id]);
+        assert_eq!(
+            lb_pair
+                .next_bin_array_index_with_liquidity_internal(true, min_bitmap_id - 1)
+                .unwrap(),
+            (min_bitmap_id - 1, false)
+        );
+        assert_eq!(
+            lb_pair
+                .next_bin_array_index_with_liquidity_internal(false, min_bitmap_id - 1)
+                .unwrap(),
+            (max_bitmap_id + 1, false)
+        );
+    }
+
+    #[test]
+    fn test_surrounding_bin_prices() {
+        let mut lb_pair = new_test_lb_pair(&[]);
+        lb_pair.bin_step = 1;
+        lb_pair.active_id = 100;
+
+        let prices = lb_pair.surrounding_bin_prices(2).unwrap();
+        let bin_ids = prices.iter().map(|(bin_id, _)| *bin_id).collect::<Vec<_>>();
+        assert_eq!(bin_ids, vec![98, 99, 100, 101, 102]);
+        assert!(prices.windows(2).all(|w| w[0].1 < w[1].1));
+        assert_eq!(prices[2].1, get_price_from_id(100, 1).unwrap());
+
+        // Clamped to MAX_BIN_ID
+        lb_pair.active_id = MAX_BIN_ID;
+        let prices = lb_pair.surrounding_bin_prices(2).unwrap();
+        let bin_ids = prices.iter().map(|(bin_id, _)| *bin_id).collect::<Vec<_>>();
+        assert_eq!(bin_ids, vec![MAX_BIN_ID - 2, MAX_BIN_ID - 1, MAX_BIN_ID]);
+    }
+
+    #[test]
+    fn test_activation_countdown() {
+        let mut lb_pair = new_test_lb_pair(&[]);
+        lb_pair.activation_point = 1_000;
+
+        let clock = Clock {
+            slot: 200,
+            unix_timestamp: 400,
+            ..Default::default()
+        };
+
+        // Permissionless pair
+        assert_eq!(lb_pair.activation_countdown(&clock).unwrap(), None);
+
+        lb_pair.pair_type = 1; // Permission
+        lb_pair.activation_type = 0; // Slot
+        assert_eq!(
+            lb_pair.activation_countdown(&clock).unwrap(),
+            Some(ActivationCountdown::Slots(800))
+        );
+
+        lb_pair.activation_type = 1; // Timestamp
+        assert_eq!(
+            lb_pair.activation_countdown(&clock).unwrap(),
+            Some(ActivationCountdown::Seconds(600))
+        );
+
+        // Activated
+        lb_pair.activation_point = 400;
+        assert_eq!(lb_pair.activation_countdown(&clock).unwrap(), None);
+    }
+
+    #[test]
+    fn test_activation_slot() {
+        let mut lb_pair = new_test_lb_pair(&[]);
+        lb_pair.activation_point = 1_000;
+
+        let clock = Clock {
+            slot: 200,
+            unix_timestamp: 400,
+            ..Default::default()
+        };
+
+        // Permissionless pair
+        assert_eq!(lb_pair.activation_slot(&clock).unwrap(), None);
+
+        lb_pair.pair_type = 1; // Permission
+        lb_pair.activation_type = 0; // Slot
+        assert_eq!(lb_pair.activation_slot(&clock).unwrap(), Some(1_000));
+
+        // 600 seconds at 400ms per slot
+        lb_pair.activation_type = 1; // Timestamp
+        assert_eq!(lb_pair.activation_slot(&clock).unwrap(), Some(200 + 1_500));
+
+        // Rounded up to the first slot at or after the activation time
+        lb_pair.activation_point = 401;
+        assert_eq!(lb_pair.activation_slot(&clock).unwrap(), Some(203));
+
+        // Activated
+        lb_pair.activation_point = 400;
+        assert_eq!(lb_pair.activation_slot(&clock).unwrap(), None);
+    }
+
+    #[test]
+    fn test_liquidity_concentration() {
+        let mut lb_pair = new_test_lb_pair(&[]);
+        lb_pair.bin_step = 10;
+
+        let mut bin_array: BinArray = bytemuck::Zeroable::zeroed();
+        assert_eq!(lb_pair.liquidity_concentration([&bin_array]).unwrap(), 0.0);
+
+        // Evenly distributed
+        for bin_id in 0..10 {
+            bin_array.get_bin_mut(bin_id).unwrap().amount_y = 1_000;
+        }
+        let concentration = lb_pair.liquidity_concentration([&bin_array]).unwrap();
+        assert!(concentration.abs() < 1e-9);
+
+        // Concentrated in a single bin, with empty bins in between
+        for bin_id in 1..9 {
+            bin_array.get_bin_mut(bin_id).unwrap().amount_y = 0;
+        }
+        bin_array.get_bin_mut(9).unwrap().amount_y = 1;
+        let concentration = lb_pair.liquidity_concentration([&bin_array]).unwrap();
+        assert!(concentration > 0.85);
+    }
+
+    #[test]
+    fn test_inventory_skew() {
+        let mut lb_pair = new_test_lb_pair(&[]);
+        lb_pair.bin_step = 10;
+
+        let mut bin_arrays: Vec<BinArray> = vec![];
+        for index in [0, -1] {
+            let mut bin_array: BinArray = bytemuck::Zeroable::zeroed();
+            bin_array.index = index;
+            bin_arrays.push(bin_array);
+        }
+        assert!(lb_pair.inventory_skew(&bin_arrays).is_err());
+
+        bin_arrays[1].get_bin_mut(-1).unwrap().amount_y = 3_000;
+        assert_eq!(lb_pair.inventory_skew(&bin_arrays).unwrap(), 0.0);
+
+        // 1 X = 1 Y in bin 0
+        bin_arrays[0].get_bin_mut(0).unwrap().amount_x = 1_000;
+        assert_eq!(lb_pair.inventory_skew(&bin_arrays).unwrap(), 0.25);
+
+        // 1 X = 1.001^10 Y in bin 10
+        bin_arrays[0].get_bin_mut(10).unwrap().amount_x = 1_000;
+        let value_x = 1_000.0 + 1_000.0 * 1.001f64.powi(10);
+        let skew = lb_pair.inventory_skew(&bin_arrays).unwrap();
+        assert!((skew - value_x / (value_x + 3_000.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_swap_for_y() {
+        let mut lb_pair = new_test_lb_pair(&[]);
+        lb_pair.token_x_mint = Pubkey::new_unique();
+        lb_pair.token_y_mint = Pubkey::new_unique();
+
+        // Selling token X buys token Y
+        assert!(lb_pair.swap_for_y(&lb_pair.token_x_mint).unwrap());
+        assert!(!lb_pair.swap_for_y(&lb_pair.token_y_mint).unwrap());
+        assert!(lb_pair.swap_for_y(&Pubkey::new_unique()).is_err());
+    }
+
+    #[test]
+    fn test_effective_spread_bps() {
+        let mut lb_pair = new_test_lb_pair(&[]);
+        lb_pair.bin_step = 10;
+        // Base fee 0.1% = 10 bps
+        lb_pair.parameters.base_factor = 10_000;
+        assert_eq!(lb_pair.effective_spread_bps().unwrap(), 30);
+
+        // Fee rounds up to the next bps
+        lb_pair.parameters.base_factor = 10_001;
+        assert_eq!(lb_pair.effective_spread_bps().unwrap(), 32);
+    }
+
+    #[test]
+    fn test_launch_info() {
+        let mut lb_pair = new_test_lb_pair(&[]);
+        lb_pair.pair_type = 1;
+        lb_pair.activation_type = 1;
+        lb_pair.activation_point = 1_000;
+
+        let clock = Clock {
+            unix_timestamp: 400,
+            ..Default::default()
+        };
+
+        let launch_info = lb_pair.launch_info(&clock).unwrap();
+        assert_eq!(launch_info.pair_type, PairType::Permission);
+        assert_eq!(launch_info.activation_type, ActivationType::Timestamp);
+        assert_eq!(launch_info.activation_point, 1_000);
+        assert_eq!(
+            launch_info.activation_countdown,
+            Some(ActivationCountdown::Seconds(600))
+        );
+        assert!(!launch_info.is_liquidity_seeded);
+
+        let mut seeded_lb_pair = new_test_lb_pair(&[-3]);
+        seeded_lb_pair.pair_type = lb_pair.pair_type;
+        assert!(
+            seeded_lb_pair
+                .launch_info(&clock)
+                .unwrap()
+                .is_liquidity_seeded
+        );
+    }
+
+    #[test]
+    fn test_implied_fee_apr() {
+        let mut lb_pair = new_test_lb_pair(&[]);
+        lb_pair.bin_step = 10;
+        // Base fee 0.1%
+        lb_pair.parameters.base_factor = 10_000;
+        lb_pair.parameters.protocol_share = 2_000;
+
+        // 1_000 volume pays 1 fee, 0.8 goes to LPs
+        let apr = implied_fee_apr(&lb_pair, 1_000, 365).unwrap();
+        assert!((apr - 0.8).abs() < 1e-9);
+
+        assert!(implied_fee_apr(&lb_pair, 1_000, 0).is_err());
+    }
+
+    #[test]
+    fn test_active_bin_deposit_ratio() {
+        let mut lb_pair = new_test_lb_pair(&[]);
+        lb_pair.bin_step = 10;
+
+        assert_eq!(active_bin_deposit_ratio(&lb_pair).unwrap(), (ONE, ONE));
+
+        lb_pair.active_id = 100;
+        let (x_per_unit, y_per_unit) = active_bin_deposit_ratio(&lb_pair).unwrap();
+        assert_eq!(x_per_unit, ONE);
+        assert_eq!(y_per_unit, get_price_from_id(100, 10).unwrap());
+
+        // 1_000 token X at price ~1.105 pairs with ~1_105 token Y
+        let amount_y: u64 =
+            safe_mul_div_cast(1_000, y_per_unit, x_per_unit, Rounding::Down).unwrap();
+        assert_eq!(amount_y, 1_105);
+    }
+
+    #[test]
+    fn test_protocol_fee_ui() {
+        let mut lb_pair = new_test_lb_pair(&[]);
+        lb_pair.protocol_fee.amount_x = 1_500_000_000;
+        lb_pair.protocol_fee.amount_y = 2_500_000;
+
+        assert_eq!(lb_pair.protocol_fee_ui(9, 6), (1.5, 2.5));
+    }
+
+    #[test]
+    fn test_active_liquidity() {
+        let mut lb_pair = new_test_lb_pair(&[]);
+        lb_pair.active_id = 5;
+
+        let mut bin_array: BinArray = bytemuck::Zeroable::zeroed();
+        bin_array.get_bin_mut(5).unwrap().liquidity_supply = 1_000;
+        assert_eq!(lb_pair.active_liquidity(&bin_array).unwrap(), 1_000);
+
+        bin_array.index = 1;
+        assert!(lb_pair.active_liquidity(&bin_array).is_err());
+    }
+
+    #[test]
+    fn test_fee_rates_for_pools() {
+        let mut lb_pair = new_test_lb_pair(&[]);
+        lb_pair.bin_step = 10;
+        lb_pair.parameters.base_factor = 10_000;
+
+        let mut high_fee_lb_pair = lb_pair;
+        high_fee_lb_pair.bin_step = 100;
+
+        // Base fee overflows
+        let mut invalid_lb_pair = lb_pair;
+        invalid_lb_pair.bin_step = u16::MAX;
+        invalid_lb_pair.parameters.base_factor = u16::MAX;
+        invalid_lb_pair.parameters.base_fee_power_factor = 30;
+
+        let fee_rates = fee_rates_for_pools(&[&lb_pair, &invalid_lb_pair, &high_fee_lb_pair]);
+        assert_eq!(fee_rates.len(), 3);
+        assert_eq!(fee_rates[0].as_ref().unwrap(), &10);
+        assert!(fee_rates[1].is_err());
+        assert_eq!(fee_rates[2].as_ref().unwrap(), &100);
+    }
+
+    #[test]
+    fn test_oracle() {
+        let mut lb_pair = new_test_lb_pair(&[]);
+        assert!(!lb_pair.oracle_initialized());
+
+        let oracle = Pubkey::new_unique();
+        lb_pair.oracle = oracle;
+        assert!(lb_pair.oracle_initialized());
+        assert_eq!(LbPairExtension::oracle(&lb_pair), oracle);
+    }
+
+    #[test]
+    fn test_dynamic_fee_bounds() {
+        let mut lb_pair = new_test_lb_pair(&[]);
+        lb_pair.bin_step = 10;
+        lb_pair.parameters.base_factor = 10_000;
+        assert_eq!(lb_pair.dynamic_fee_bounds().unwrap(), (10, 10));
+
+        lb_pair.parameters.variable_fee_control = 40_000;
+        lb_pair.parameters.max_volatility_accumulator = 350_000;
+        // Variable fee rate = 40_000 * (350_000 * 10)^2 / 10^11 = 4_900_000, which is 49 bps
+        let (min_fee_bps, max_fee_bps) = lb_pair.dynamic_fee_bounds().unwrap();
+        assert_eq!(min_fee_bps, 10);
+        assert_eq!(max_fee_bps, 10 + 49);
+
+        // Capped at MAX_FEE_RATE
+        lb_pair.parameters.max_volatility_accumulator = u32::MAX;
+        assert_eq!(lb_pair.dynamic_fee_bounds().unwrap(), (10, 1_000));
+    }
+
+    #[test]
+    fn test_liquidity_imbalance() {
+        let mut lb_pair = new_test_lb_pair(&[]);
+        lb_pair.bin_step = 10;
+
+        let mut bin_arrays: Vec<BinArray> = vec![];
+        for index in [0, -1] {
+            let mut bin_array: BinArray = bytemuck::Zeroable::zeroed();
+            bin_array.index = index;
+            bin_arrays.push(bin_array);
+        }
+        assert_eq!(liquidity_imbalance(&lb_pair, &bin_arrays, 10).unwrap(), 0.0);
+
+        bin_arrays[1].get_bin_mut(-1).unwrap().amount_y = 3_000;
+        bin_arrays[0].get_bin_mut(0).unwrap().amount_y = 1_000;
+        bin_arrays[0].get_bin_mut(0).unwrap().amount_x = 1_000;
+        bin_arrays[0].get_bin_mut(5).unwrap().amount_x = 1_000;
+
+        // Asks of bin 5 are outside of the window
+        let imbalance = liquidity_imbalance(&lb_pair, &bin_arrays, 1).unwrap();
+        assert!((imbalance - 3_000.0 / 5_000.0).abs() < 1e-12);
+
+        let ask_liquidity = 1_000.0 + 1_000.0 * 1.001f64.powi(5);
+        let imbalance = liquidity_imbalance(&lb_pair, &bin_arrays, 5).unwrap();
+        let expected = (4_000.0 - ask_liquidity) / (4_000.0 + ask_liquidity);
+        assert!((imbalance - expected).abs() < 1e-9);
+
+        // Bids of bin array -1 are not provided
+        let imbalance = liquidity_imbalance(&lb_pair, &bin_arrays[..1], 10).unwrap();
+        let expected = (1_000.0 - ask_liquidity) / (1_000.0 + ask_liquidity);
+        assert!((imbalance - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_active_bin_density() {
+        let lb_pair = new_test_lb_pair(&[]);
+
+        let mut bin_arrays: Vec<BinArray> = vec![];
+        for index in [0, -1] {
+            let mut bin_array: BinArray = bytemuck::Zeroable::zeroed();
+            bin_array.index = index;
+            bin_arrays.push(bin_array);
+        }
+        assert_eq!(active_bin_density(&lb_pair, &bin_arrays, 10).unwrap(), 0);
+
+        bin_arrays[1].get_bin_mut(-3).unwrap().amount_y = 1_000;
+        bin_arrays[1].get_bin_mut(-1).unwrap().amount_y = 1_000;
+        bin_arrays[0].get_bin_mut(0).unwrap().amount_x = 1_000;
+        bin_arrays[0].get_bin_mut(0).unwrap().amount_y = 1_000;
+        bin_arrays[0].get_bin_mut(2).unwrap().amount_x = 1_000;
+
+        assert_eq!(active_bin_density(&lb_pair, &bin_arrays, 0).unwrap(), 1);
+        assert_eq!(active_bin_density(&lb_pair, &bin_arrays, 2).unwrap(), 3);
+        assert_eq!(active_bin_density(&lb_pair, &bin_arrays, 3).unwrap(), 4);
+        assert_eq!(
+            active_bin_density(&lb_pair, &bin_arrays[..1], 3).unwrap(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_implied_price_distribution() {
+        let mut lb_pair = new_test_lb_pair(&[]);
+        lb_pair.bin_step = 10;
+
+        let mut bin_arrays: Vec<BinArray> = vec![];
+        for index in [0, -1] {
+            let mut bin_array: BinArray = bytemuck::Zeroable::zeroed();
+            bin_array.index = index;
+            bin_arrays.push(bin_array);
+        }
+        assert!(implied_price_distribution(&lb_pair, &bin_arrays).is_err());
+
+        bin_arrays[0].get_bin_mut(0).unwrap().amount_x = 1_000;
+        bin_arrays[0].get_bin_mut(0).unwrap().amount_y = 1_000;
+        bin_arrays[0].get_bin_mut(10).unwrap().amount_x = 1_000;
+        bin_arrays[1].get_bin_mut(-1).unwrap().amount_y = 3_000;
+
+        let distribution = implied_price_distribution(&lb_pair, &bin_arrays).unwrap();
+        let prices = distribution
+            .iter()
+            .map(|(price, _)| *price)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            prices,
+            [-1, 0, 10]
+                .into_iter()
+                .map(|bin_id| get_price_from_id(bin_id, lb_pair.bin_step).unwrap())
+                .collect::<Vec<_>>()
+        );
+
+        // 1 X = 1.001^10 Y in bin 10
+        let value_10 = 1_000.0 * 1.001f64.powi(10);
+        let total_value = 3_000.0 + 2_000.0 + value_10;
+        let expected_weights = [
+            3_000.0 / total_value,
+            2_000.0 / total_value,
+            value_10 / total_value,
+        ];
+        for ((_, weight), expected_weight) in distribution.iter().zip(expected_weights) {
+            assert!((weight - expected_weight).abs() < 1e-9);
+        }
+
+        let total_weight: f64 = distribution.iter().map(|(_, weight)| weight).sum();
+        assert!((total_weight - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_validate_lb_pair_account_data() {
+        let data = LbPairAccount(new_test_lb_pair(&[])).try_to_vec().unwrap();
+        assert!(validate_lb_pair_account_data(&data).is_ok());
+
+        let expected_len = data.len();
+
+        let err = validate_lb_pair_account_data(&[data.as_slice(), &[0; 8]].concat()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<QuoteError>(),
+            Some(&QuoteError::UnsupportedPoolVersion {
+                len: expected_len + 8,
+                expected_len,
+            })
+        );
+
+        let err = validate_lb_pair_account_data(&data[..data.len() - 1]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<QuoteError>(),
+            Some(&QuoteError::UnsupportedPoolVersion {
+                len: expected_len - 1,
+                expected_len,
+            })
+        );
+
+        // Not a pair account
+        let err = validate_lb_pair_account_data(&data[8..]).unwrap_err();
+        assert!(err.downcast_ref::<QuoteError>().is_none());
+    }
+
+    #[test]
+    fn test_set_active_id() {
+        let mut lb_pair = new_test_lb_pair(&[]);
+        lb_pair.v_parameters.index_reference = 10;
+
+        lb_pair.set_active_id(-100).unwrap();
+        assert_eq!(lb_pair.active_id, -100);
+        assert_eq!(lb_pair.v_parameters.index_reference, -100);
+
+        lb_pair.set_active_id(MAX_BIN_ID).unwrap();
+        assert_eq!(lb_pair.active_id, MAX_BIN_ID);
+
+        assert!(lb_pair.set_active_id(MAX_BIN_ID + 1).is_err());
+        assert!(lb_pair.set_active_id(MIN_BIN_ID - 1).is_err());
+        assert_eq!(lb_pair.active_id, MAX_BIN_ID);
+    }
+
+    #[test]
+    fn test_resolve_swap_intent() {
+        let mut lb_pair = new_test_lb_pair(&[]);
+        lb_pair.token_x_mint = Pubkey::new_unique();
+        lb_pair.token_y_mint = Pubkey::new_unique();
+        let (mint_x, mint_y) = (lb_pair.token_x_mint, lb_pair.token_y_mint);
+
+        assert_eq!(
+            resolve_swap_intent(&lb_pair, mint_x, mint_y, 100).unwrap(),
+            SwapIntent {
+                swap_for_y: true,
+                amount_in: 100
+            }
+        );
+        assert_eq!(
+            resolve_swap_intent(&lb_pair, mint_y, mint_x, 100).unwrap(),
+            SwapIntent {
+                swap_for_y: false,
+                amount_in: 100
+            }
+        );
+
+        assert!(resolve_swap_intent(&lb_pair, mint_x, mint_x, 100).is_err());
+        assert!(resolve_swap_intent(&lb_pair, mint_x, Pubkey::new_unique(), 100).is_err());
+        assert!(resolve_swap_intent(&lb_pair, Pubkey::new_unique(), mint_y, 100).is_err());
+    }
+}
@@ -16,6 +16,9 @@ pub use pda::*;
 pub mod quote;
 pub use quote::*;
 
+pub mod quote_session;
+pub use quote_session::*;
+
 pub mod seeds;
 pub use seeds::*;
 
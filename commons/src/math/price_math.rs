@@ -1,4 +1,5 @@
 use crate::*;
+use ruint::aliases::U256;
 
 pub fn get_price_from_id(active_id: i32, bin_step: u16) -> Result<u128> {
     let bps = u128::from(bin_step)
@@ -11,3 +12,328 @@ pub fn get_price_from_id(active_id: i32, bin_step: u16) -> Result<u128> {
 
     pow(base, active_id).context("overflow")
 }
+
+/// Convert Q64.64 price to f64, including the fractional bits below SCALE_OFFSET. This is price per lamport.
+pub fn q64x64_price_to_f64(q64x64_price: u128) -> f64 {
+    let integer = (q64x64_price >> SCALE_OFFSET) as f64;
+    let fraction = (q64x64_price & (ONE - 1)) as f64 / ONE as f64;
+    integer + fraction
+}
+
+/// Price of the bin id in token Y per token X, adjusted by the token decimals.
+pub fn price_from_id_ui(id: i32, bin_step: u16, x_decimals: u8, y_decimals: u8) -> Result<f64> {
+    let price_per_lamport = q64x64_price_to_f64(get_price_from_id(id, bin_step)?);
+    let decimals_diff = i32::from(x_decimals) - i32::from(y_decimals);
+    Ok(price_per_lamport * 10f64.powi(decimals_diff))
+}
+
+/// Nearest bin id of a price in token Y per token X, adjusted by the token decimals. Inverse of [`price_from_id_ui`].
+pub fn ui_price_to_bin_id(
+    ui_price: f64,
+    bin_step: u16,
+    x_decimals: u8,
+    y_decimals: u8,
+) -> Result<i32> {
+    ensure!(
+        ui_price.is_finite() && ui_price > 0.0,
+        "Price must be a positive number"
+    );
+    ensure!(bin_step > 0, "Bin step must be greater than 0");
+
+    let decimals_diff = i32::from(y_decimals) - i32::from(x_decimals);
+    let price_per_lamport = ui_price * 10f64.powi(decimals_diff);
+
+    let estimated_id = (price_per_lamport.ln() / bin_step_to_price_factor(bin_step).ln()).round();
+    let estimated_id = estimated_id.clamp(MIN_BIN_ID as f64, MAX_BIN_ID as f64) as i32;
+
+    // f64 estimation might be off by 1 bin, pick the closest in ratio with the Q64.64 price of the bin
+    let mut nearest_id = estimated_id;
+    let mut nearest_distance = f64::MAX;
+
+    for id in estimated_id.saturating_sub(1)..=estimated_id.saturating_add(1) {
+        if !(MIN_BIN_ID..=MAX_BIN_ID).contains(&id) {
+            continue;
+        }
+
+        let price = q64x64_price_to_f64(get_price_from_id(id, bin_step)?);
+        let distance = (price / price_per_lamport).ln().abs();
+
+        if distance < nearest_distance {
+            nearest_id = id;
+            nearest_distance = distance;
+        }
+    }
+
+    Ok(nearest_id)
+}
+
+/// Snap a Q64.64 price to a bin. [`Rounding::Down`] picks the highest bin price not above `price`, and [`Rounding::Up`] picks the lowest bin price not below `price`.
+/// Returns the bin id and its exact Q64.64 price.
+pub fn snap_price_to_bin(price: u128, bin_step: u16, rounding: Rounding) -> Result<(i32, u128)> {
+    ensure!(price > 0, "Price must be greater than 0");
+    ensure!(bin_step > 0, "Bin step must be greater than 0");
+
+    let estimated_id =
+        (q64x64_price_to_f64(price).ln() / bin_step_to_price_factor(bin_step).ln()).round();
+    let mut bin_id = estimated_id.clamp(MIN_BIN_ID as f64, MAX_BIN_ID as f64) as i32;
+
+    // f64 estimation might be off by a few bins, walk to the exact bin with the Q64.64 price
+    match rounding {
+        Rounding::Down => {
+            while get_price_from_id(bin_id, bin_step)? > price {
+                ensure!(bin_id > MIN_BIN_ID, "Price is below the minimum bin price");
+                bin_id -= 1;
+            }
+            while bin_id < MAX_BIN_ID && get_price_from_id(bin_id + 1, bin_step)? <= price {
+                bin_id += 1;
+            }
+        }
+        Rounding::Up => {
+            while get_price_from_id(bin_id, bin_step)? < price {
+                ensure!(bin_id < MAX_BIN_ID, "Price is above the maximum bin price");
+                bin_id += 1;
+            }
+            while bin_id > MIN_BIN_ID && get_price_from_id(bin_id - 1, bin_step)? >= price {
+                bin_id -= 1;
+            }
+        }
+    }
+
+    Ok((bin_id, get_price_from_id(bin_id, bin_step)?))
+}
+
+/// Price ratio between 2 adjacent bins. 1 + bin_step / 10000
+pub fn bin_step_to_price_factor(bin_step: u16) -> f64 {
+    1.0 + bin_step as f64 / BASIS_POINT_MAX as f64
+}
+
+/// Number of bins spanning a 2x price move. log(2) / log(price_factor)
+pub fn price_factor_to_bin_ids_per_doubling(bin_step: u16) -> f64 {
+    2f64.ln() / bin_step_to_price_factor(bin_step).ln()
+}
+
+/// Price per lamport linearly interpolated between the bin price and the next bin price, by how much of the bin is filled.
+/// Swaps within a bin are executed at the constant bin price. This is for visualization only.
+pub fn interpolated_price(bin_id: i32, bin_step: u16, fraction_filled: f64) -> Result<f64> {
+    ensure!(
+        (0.0..=1.0).contains(&fraction_filled),
+        "Fraction filled must be within [0, 1]"
+    );
+
+    let next_bin_id = bin_id.checked_add(1).context("overflow")?;
+    let price = q64x64_price_to_f64(get_price_from_id(bin_id, bin_step)?);
+    let next_price = q64x64_price_to_f64(get_price_from_id(next_bin_id, bin_step)?);
+
+    Ok(price + (next_price - price) * fraction_filled)
+}
+
+/// Average Q64.64 price of a range deposit, which is the bin prices weighted by the value of the deposit in each bin, valued in token Y.
+/// `amounts` is (bin id, amount x, amount y), and every bin id must be within [`lower_bin_id`, `upper_bin_id`].
+pub fn range_order_average_price(
+    lower_bin_id: i32,
+    upper_bin_id: i32,
+    bin_step: u16,
+    amounts: &[(i32, u64, u64)],
+) -> Result<u128> {
+    ensure!(lower_bin_id <= upper_bin_id, "Invalid bin range");
+
+    let mut weighted_price_sum = U256::ZERO;
+    let mut value_sum = U256::ZERO;
+
+    for &(bin_id, amount_x, amount_y) in amounts {
+        ensure!(
+            (lower_bin_id..=upper_bin_id).contains(&bin_id),
+            "Bin {} is outside of the range",
+            bin_id
+        );
+
+        let price = get_price_from_id(bin_id, bin_step)?;
+        let value = Bin::get_liquidity(amount_x, amount_y, price)?;
+
+        weighted_price_sum = weighted_price_sum
+            .checked_add(U256::from(price) * U256::from(value))
+            .context("overflow")?;
+        value_sum = value_sum
+            .checked_add(U256::from(value))
+            .context("overflow")?;
+    }
+
+    ensure!(value_sum > U256::ZERO, "Range has no liquidity");
+
+    Ok((weighted_price_sum / value_sum)
+        .try_into()
+        .ok()
+        .context("overflow")?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_price_from_id_ui() {
+        // SOL (9 decimals) / USDC (6 decimals), 10 bps bin step
+        let price = price_from_id_ui(-1897, 10, 9, 6).unwrap();
+        let expected = 150.1602635076695;
+        assert!((price - expected).abs() / expected < 1e-12);
+
+        // Price per lamport below 1, integer shift alone loses all the precision
+        let price = price_from_id_ui(-3000, 80, 9, 6).unwrap();
+        let expected = 4.153399167148438e-8;
+        assert!((price - expected).abs() / expected < 1e-9);
+
+        assert_eq!(price_from_id_ui(0, 10, 6, 6).unwrap(), 1.0);
+    }
+
+    #[test]
+    fn test_bin_step_price_factor() {
+        assert_eq!(bin_step_to_price_factor(0), 1.0);
+        assert_eq!(bin_step_to_price_factor(25), 1.0025);
+        assert_eq!(bin_step_to_price_factor(100), 1.01);
+
+        // 1.0001^6931.81... = 2
+        let bin_ids = price_factor_to_bin_ids_per_doubling(1);
+        assert!((bin_ids - 6931.8183).abs() < 1e-3);
+
+        let bin_ids = price_factor_to_bin_ids_per_doubling(100);
+        assert!((bin_step_to_price_factor(100).powf(bin_ids) - 2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_range_order_average_price() {
+        let price_0 = get_price_from_id(0, 10).unwrap();
+        let price_1 = get_price_from_id(1, 10).unwrap();
+
+        // Single bin is the bin price
+        assert_eq!(
+            range_order_average_price(0, 1, 10, &[(1, 500, 0)]).unwrap(),
+            price_1
+        );
+
+        // Equal value in both bins is the mean price
+        let amount_x = (1_000u128 << SCALE_OFFSET) / price_1;
+        let average_price =
+            range_order_average_price(0, 1, 10, &[(0, 0, 1_000), (1, amount_x as u64, 0)]).unwrap();
+        assert!(average_price.abs_diff((price_0 + price_1) / 2) < price_0 / 100_000);
+
+        // Value skewed to the higher bin
+        let average_price =
+            range_order_average_price(0, 1, 10, &[(0, 0, 1_000), (1, 0, 3_000)]).unwrap();
+        assert_eq!(average_price, (price_0 + 3 * price_1) / 4);
+
+        assert!(range_order_average_price(0, 1, 10, &[(2, 0, 1_000)]).is_err());
+        assert!(range_order_average_price(1, 0, 10, &[]).is_err());
+        assert!(range_order_average_price(0, 1, 10, &[(0, 0, 0)]).is_err());
+    }
+
+    #[test]
+    fn test_interpolated_price() {
+        assert_eq!(interpolated_price(0, 10, 0.0).unwrap(), 1.0);
+        assert_eq!(interpolated_price(0, 10, 1.0).unwrap(), 1.001);
+        assert!((interpolated_price(0, 10, 0.5).unwrap() - 1.0005).abs() < 1e-12);
+        assert!(interpolated_price(0, 10, 1.5).is_err());
+        assert!(interpolated_price(0, 10, f64::NAN).is_err());
+    }
+
+    #[test]
+    fn test_ui_price_to_bin_id() {
+        // SOL (9 decimals) / USDC (6 decimals), 10 bps bin step
+        assert_eq!(
+            ui_price_to_bin_id(150.1602635076695, 10, 9, 6).unwrap(),
+            -1897
+        );
+        assert_eq!(ui_price_to_bin_id(150.0, 10, 9, 6).unwrap(), -1898);
+        assert_eq!(ui_price_to_bin_id(1.0, 10, 6, 6).unwrap(), 0);
+
+        for id in [-3000, -1, 0, 1, 3000] {
+            let ui_price = price_from_id_ui(id, 80, 9, 6).unwrap();
+            assert_eq!(ui_price_to_bin_id(ui_price, 80, 9, 6).unwrap(), id);
+        }
+
+        assert!(ui_price_to_bin_id(0.0, 10, 9, 6).is_err());
+        assert!(ui_price_to_bin_id(f64::NAN, 10, 9, 6).is_err());
+    }
+
+    #[test]
+    fn test_snap_price_to_bin() {
+        for bin_id in [-1897, -1, 0, 1, 3000] {
+            let bin_price = get_price_from_id(bin_id, 10).unwrap();
+
+            // Exact bin price
+            for rounding in [Rounding::Down, Rounding::Up] {
+                assert_eq!(
+                    snap_price_to_bin(bin_price, 10, rounding).unwrap(),
+                    (bin_id, bin_price)
+                );
+            }
+
+            // Just above the bin price
+            let (snapped_id, _) = snap_price_to_bin(bin_price + 1, 10, Rounding::Down).unwrap();
+            assert_eq!(snapped_id, bin_id);
+            let (snapped_id, snapped_price) =
+                snap_price_to_bin(bin_price + 1, 10, Rounding::Up).unwrap();
+            assert_eq!(snapped_id, bin_id + 1);
+            assert_eq!(snapped_price, get_price_from_id(bin_id + 1, 10).unwrap());
+
+            // Just below the bin price
+            let (snapped_id, _) = snap_price_to_bin(bin_price - 1, 10, Rounding::Down).unwrap();
+            assert_eq!(snapped_id, bin_id - 1);
+            let (snapped_id, _) = snap_price_to_bin(bin_price - 1, 10, Rounding::Up).unwrap();
+            assert_eq!(snapped_id, bin_id);
+        }
+
+        assert!(snap_price_to_bin(0, 10, Rounding::Down).is_err());
+        assert!(snap_price_to_bin(ONE, 0, Rounding::Down).is_err());
+    }
+
+    #[test]
+    fn test_price_from_id_against_rational() {
+        use num_bigint::BigUint;
+        use num_traits::ToPrimitive;
+
+        for bin_step in [1u16, 10, 25, 100, 400] {
+            for bin_id in (-20_000..=20_000).step_by(997) {
+                let Some(price) = get_price_from_id(bin_id, bin_step).ok() else {
+                    continue;
+                };
+
+                // Exact price = ONE * ((BASIS_POINT_MAX + bin_step) / BASIS_POINT_MAX) ^ bin_id
+                let exponent = bin_id.unsigned_abs();
+                let base_numerator = BigUint::from(BASIS_POINT_MAX as u32 + bin_step as u32);
+                let base_denominator = BigUint::from(BASIS_POINT_MAX as u32);
+                let (numerator, denominator) = if bin_id >= 0 {
+                    (base_numerator.pow(exponent), base_denominator.pow(exponent))
+                } else {
+                    (base_denominator.pow(exponent), base_numerator.pow(exponent))
+                };
+
+                let price_scaled = BigUint::from(price) * &denominator;
+                let exact_scaled = BigUint::from(ONE) * &numerator;
+                let error = if price_scaled > exact_scaled {
+                    price_scaled - &exact_scaled
+                } else {
+                    &exact_scaled - price_scaled
+                };
+                let relative_error = (error * BigUint::from(10u64.pow(18)) / exact_scaled)
+                    .to_u64()
+                    .unwrap() as f64
+                    / 1e18;
+
+                // Truncation of each squaring adds up with the exponent. Positive bin ids are the inverse of the negative bin id price,
+                // which loses the precision of the smaller of the price and its inverse.
+                let inverse_price = u128::MAX / price;
+                let bound = exponent as f64 * 2f64.powi(-62)
+                    + 2.0 / std::cmp::min(price, inverse_price) as f64;
+
+                assert!(
+                    relative_error <= bound,
+                    "bin step {} bin id {} relative error {} above {}",
+                    bin_step,
+                    bin_id,
+                    relative_error,
+                    bound
+                );
+            }
+        }
+    }
+}
@@ -1,19 +1,368 @@
 use crate::*;
 use anchor_client::solana_sdk::pubkey::Pubkey;
+use borsh::{BorshDeserialize, BorshSerialize};
 use core::result::Result::Ok;
 use solana_sdk::{account::Account, clock::Clock};
 use std::{collections::HashMap, ops::Deref};
 
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
 pub struct SwapExactInQuote {
     pub amount_out: u64,
     pub fee: u64,
+    /// Part of fee, summed from the protocol fee of each bin
+    pub protocol_fee: u64,
+    /// Amount of token deposited into the bins, excludes fee and transfer fee
+    pub amount_into_pool: u64,
+    /// Amount of token withdrawn from the bins, includes transfer fee
+    pub amount_out_of_pool: u64,
+    /// Whether the swap stopped in a bin which still holds the output token, instead of draining the last bin.
+    /// The active bin after the swap is then partially filled, and the next swap starts at the same price.
+    pub final_bin_partially_filled: bool,
+    /// Volatility accumulator of the pair at the last bin swapped. The next swap within the filter period starts from it, so it pays the
+    /// variable fee the swap raised. See [`Self::fee_rate_after_swap`].
+    pub post_swap_volatility_accumulator: u32,
 }
 
-#[derive(Debug)]
+impl SwapExactInQuote {
+    /// Fee collected by LPs, which is the fee excluding protocol fee.
+    pub fn lp_fee(&self) -> Result<u64> {
+        self.fee
+            .checked_sub(self.protocol_fee)
+            .context("MathOverflow")
+    }
+
+    /// Protocol fee of the quote, computed once from the total fee.
+    /// The program computes the protocol fee of each bin and accumulates it into the pair, which is what `protocol_fee` holds.
+    /// Rounding down per bin makes `protocol_fee` up to 1 smaller per bin crossed than this, so use `protocol_fee` to reconcile with the pool.
+    pub fn total_protocol_fee(&self, lb_pair: &LbPair) -> Result<u64> {
+        lb_pair.compute_protocol_fee(self.fee)
+    }
+
+    /// Total fee in bps the next swap pays at the post swap active bin, given the volatility accumulated by this swap.
+    /// `lb_pair` is the pair quoted. Assumes the next swap happens within the filter period, before the volatility decays.
+    pub fn fee_rate_after_swap(&self, lb_pair: &LbPair) -> Result<u64> {
+        let mut lb_pair = *lb_pair;
+        lb_pair.v_parameters.volatility_accumulator = self.post_swap_volatility_accumulator;
+        lb_pair.get_total_fee_bps()
+    }
+
+    /// Convert the fee, which is denominated in the input token, to output token at Q64.64 `price` (token Y per token X).
+    pub fn fee_in_output_terms(&self, price: u128, swap_for_y: bool) -> Result<u64> {
+        Bin::get_amount_out(self.fee, price, swap_for_y)
+    }
+
+    /// Fee in input token, and its value in output token at the average execution price of the quote, which is `amount_out_of_pool / amount_into_pool`.
+    /// Use [`Self::fee_in_output_terms`] to value the fee at another price, such as the spot price.
+    pub fn fee_both_terms(&self) -> Result<(u64, u64)> {
+        if self.amount_into_pool == 0 {
+            return Ok((self.fee, 0));
+        }
+
+        let fee_out = safe_mul_div_cast(
+            self.fee.into(),
+            self.amount_out_of_pool.into(),
+            self.amount_into_pool.into(),
+            Rounding::Down,
+        )?;
+
+        Ok((self.fee, fee_out))
+    }
+
+    /// Size of the swap in token Y, for volume tracking in both directions. The size is the amount in swapped within the pool, fee included.
+    /// Token X input is converted at Q64.64 `price` (token Y per token X), rounded down.
+    pub fn notional_y(&self, swap_for_y: bool, price: u128) -> Result<u64> {
+        let amount_in = self
+            .amount_into_pool
+            .checked_add(self.fee)
+            .context("MathOverflow")?;
+
+        if swap_for_y {
+            Bin::get_amount_out(amount_in, price, true)
+        } else {
+            Ok(amount_in)
+        }
+    }
+
+    /// Rate observed by the user wallet in UI amounts, which is output token received per input token sent.
+    /// `gross_amount_in` is the amount in passed to the quote, before input transfer fee, and `amount_out` is after output transfer fee,
+    /// so both transfer fee legs are included. 0 when `gross_amount_in` is 0.
+    pub fn all_in_rate(&self, gross_amount_in: u64, in_decimals: u8, out_decimals: u8) -> f64 {
+        if gross_amount_in == 0 {
+            return 0.0;
+        }
+
+        let amount_in = gross_amount_in as f64 / 10f64.powi(in_decimals.into());
+        let amount_out = self.amount_out as f64 / 10f64.powi(out_decimals.into());
+
+        amount_out / amount_in
+    }
+
+    /// Borsh encoded quote, for passing the exact amounts between services.
+    pub fn to_borsh_bytes(&self) -> Result<Vec<u8>> {
+        Ok(borsh::to_vec(self)?)
+    }
+
+    pub fn from_borsh_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(borsh::from_slice(bytes)?)
+    }
+
+    /// Human readable quote in UI amounts. Eg: "1.5 -> 225.3 (fee: 0.0045)"
+    /// Both sides are pool side amounts, so they are on the same basis. The input is `amount_into_pool` plus fee, which is after the input transfer fee,
+    /// and the output is `amount_out_of_pool`, which is withdrawn from the bins before the output transfer fee.
+    pub fn describe(&self, in_decimals: u8, out_decimals: u8) -> String {
+        let in_token_multiplier = 10f64.powi(in_decimals.into());
+        let out_token_multiplier = 10f64.powi(out_decimals.into());
+        let amount_in = self.amount_into_pool.saturating_add(self.fee);
+
+        format!(
+            "{} -> {} (fee: {})",
+            amount_in as f64 / in_token_multiplier,
+            self.amount_out_of_pool as f64 / out_token_multiplier,
+            self.fee as f64 / in_token_multiplier
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
 pub struct SwapExactOutQuote {
     pub amount_in: u64,
     pub fee: u64,
+    /// Amount of token deposited into the bins, excludes fee and transfer fee
+    pub amount_into_pool: u64,
+    /// Amount of token withdrawn from the bins, includes transfer fee
+    pub amount_out_of_pool: u64,
+}
+
+impl SwapExactOutQuote {
+    /// Borsh encoded quote, for passing the exact amounts between services.
+    pub fn to_borsh_bytes(&self) -> Result<Vec<u8>> {
+        Ok(borsh::to_vec(self)?)
+    }
+
+    pub fn from_borsh_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(borsh::from_slice(bytes)?)
+    }
+}
+
+/// Swap result of a single bin traversed by the quote.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BinSwapBreakdown {
+    pub bin_id: i32,
+    /// Amount of token swap into the bin, includes fee
+    pub amount_in: u64,
+    /// Amount of token swap out from the bin
+    pub amount_out: u64,
+    /// `amount_out` in Q64.64, before the truncation to integer. For analytics only, the bin transfers `amount_out`
+    pub precise_amount_out: u128,
+    /// Swap fee collected by the bin, includes protocol fee
+    pub fee: u64,
+    /// Portion of the fee that goes to the protocol
+    pub protocol_fee: u64,
+    /// Total fee rate in bps charged by the bin. Rises along the swap as the volatility accumulates
+    pub fee_bps: u64,
+    /// Amount of token X left in the bin after the swap
+    pub remaining_amount_x: u64,
+    /// Amount of token Y left in the bin after the swap
+    pub remaining_amount_y: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwapExactInQuoteWithBreakdown {
+    pub quote: SwapExactInQuote,
+    /// Bins in the order they were traversed
+    pub breakdown: Vec<BinSwapBreakdown>,
+}
+
+impl SwapExactInQuoteWithBreakdown {
+    /// Amount out of pool in Q64.64, summing the bin amounts out before their truncation to integer. Chaining hypothetical swaps on it doesn't compound
+    /// the rounding of every bin. Transfer fee isn't deducted.
+    pub fn precise_amount_out(&self) -> Result<u128> {
+        self.breakdown.iter().try_fold(0u128, |total, bin| {
+            total
+                .checked_add(bin.precise_amount_out)
+                .context("MathOverflow")
+        })
+    }
+}
+
+/// Bin liquidity exceeds the actual reserve balance. Amount out of the quote was capped at `reserve_balance`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReserveShortfall {
+    /// Amount out of the bins before capping, includes transfer fee
+    pub bin_amount_out: u64,
+    pub reserve_balance: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwapExactInQuoteWithReserveCap {
+    pub quote: SwapExactInQuote,
+    pub reserve_shortfall: Option<ReserveShortfall>,
+}
+
+/// Fee parameters to replace when quoting with [`quote_exact_in_with_fee_override`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FeeOverride {
+    pub base_factor: Option<u16>,
+    pub variable_fee_control: Option<u32>,
+    pub protocol_share: Option<u16>,
+}
+
+/// Accounts of a pool to quote against, for the functions quoting across pools like [`optimal_split`].
+#[derive(Debug, Clone, Copy)]
+pub struct PoolQuoteAccounts<'a> {
+    pub lb_pair_pubkey: Pubkey,
+    pub lb_pair: &'a LbPair,
+    pub bin_arrays: &'a HashMap<Pubkey, BinArray>,
+    pub bitmap_extension: Option<&'a BinArrayBitmapExtension>,
+}
+
+/// Sum the fee collected by bins within [lower_bin_id, upper_bin_id].
+pub fn fees_in_range(breakdown: &[BinSwapBreakdown], lower_bin_id: i32, upper_bin_id: i32) -> u64 {
+    breakdown
+        .iter()
+        .filter(|bin| (lower_bin_id..=upper_bin_id).contains(&bin.bin_id))
+        .map(|bin| bin.fee)
+        .sum()
+}
+
+/// Pro-rata LP fee earned by `liquidity_share` of `bin_total_liquidity_supply` in bin `bin_id` from the swap. Protocol fee is excluded.
+pub fn lp_fee_share(
+    breakdown: &[BinSwapBreakdown],
+    bin_id: i32,
+    liquidity_share: u128,
+    bin_total_liquidity_supply: u128,
+) -> Result<u64> {
+    if bin_total_liquidity_supply == 0 {
+        return Ok(0);
+    }
+
+    let mut lp_fee = 0u64;
+    for bin in breakdown.iter().filter(|bin| bin.bin_id == bin_id) {
+        let bin_lp_fee = bin
+            .fee
+            .checked_sub(bin.protocol_fee)
+            .context("MathOverflow")?;
+        lp_fee = lp_fee.checked_add(bin_lp_fee).context("MathOverflow")?;
+    }
+
+    safe_mul_div_cast(
+        liquidity_share,
+        lp_fee.into(),
+        bin_total_liquidity_supply,
+        Rounding::Down,
+    )
+}
+
+/// Total swap fee of a multi hop route in the output token of the last hop. `hops` are the quotes of the route in order, where each hop swaps
+/// the output token of the previous one. Fee of each hop is in its input token, and is carried through the remaining hops at their average
+/// execution price `amount_out_of_pool / amount_into_pool`, as [`SwapExactInQuote::fee_both_terms`] does for a single hop.
+pub fn route_total_fee(hops: &[SwapExactInQuote]) -> Result<u64> {
+    let mut total_fee = 0u64;
+
+    for (i, hop) in hops.iter().enumerate() {
+        ensure!(hop.amount_into_pool > 0, "Hop {} swapped nothing", i);
+
+        total_fee = safe_mul_div_cast(
+            u128::from(total_fee)
+                .checked_add(hop.fee.into())
+                .context("MathOverflow")?,
+            hop.amount_out_of_pool.into(),
+            hop.amount_into_pool.into(),
+            Rounding::Down,
+        )?;
+    }
+
+    Ok(total_fee)
+}
+
+/// Error when no bin array with liquidity is left in the swap direction.
+/// Swap which haven't filled anything on a pair without liquidity in the other direction either is swapping into an empty pool.
+fn out_of_liquidity_error(
+    lb_pair: &LbPair,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    swap_for_y: bool,
+    filled_out: u64,
+) -> Result<QuoteError> {
+    if filled_out == 0
+        && get_bin_array_indexes_for_swap(lb_pair, bitmap_extension, !swap_for_y, 1)?.is_empty()
+    {
+        return Ok(QuoteError::EmptyPool);
+    }
+
+    Ok(QuoteError::InsufficientLiquidity { filled_out })
+}
+
+/// Ensure the clock used for quoting is at most `max_clock_age_slots` behind `current_slot`. No check when `max_clock_age_slots` is None.
+/// Dynamic fee decays with time, so quotes against a cached clock drift from the on-chain result.
+pub fn validate_clock_age(
+    clock: &Clock,
+    current_slot: u64,
+    max_clock_age_slots: Option<u64>,
+) -> Result<()> {
+    let Some(max_clock_age_slots) = max_clock_age_slots else {
+        return Ok(());
+    };
+
+    if current_slot.saturating_sub(clock.slot) > max_clock_age_slots {
+        return Err(QuoteError::StaleClock {
+            clock_slot: clock.slot,
+            current_slot,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Ensure every bin array belongs to the pair `lb_pair_pubkey`, and is keyed by its PDA. The quote functions look up bin arrays by PDA only,
+/// so bin arrays of another pair under the wrong keys would be swapped through silently.
+pub fn validate_bin_arrays(
+    lb_pair_pubkey: Pubkey,
+    bin_arrays: &HashMap<Pubkey, BinArray>,
+) -> Result<()> {
+    for (bin_array_pubkey, bin_array) in bin_arrays {
+        validate_bin_array(lb_pair_pubkey, *bin_array_pubkey, bin_array)?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn validate_bin_array(
+    lb_pair_pubkey: Pubkey,
+    bin_array_pubkey: Pubkey,
+    bin_array: &BinArray,
+) -> Result<()> {
+    let expected_pubkey = derive_bin_array_pda(lb_pair_pubkey, bin_array.index).0;
+
+    if bin_array.lb_pair != lb_pair_pubkey || bin_array_pubkey != expected_pubkey {
+        return Err(QuoteError::MismatchedBinArray {
+            bin_array: bin_array_pubkey,
+            bin_array_lb_pair: bin_array.lb_pair,
+            expected_lb_pair: lb_pair_pubkey,
+            expected_pubkey,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Ensure `bitmap_extension` belongs to the pair. The bitmap extension of another pair would silently route the swap through the wrong bin arrays.
+pub(crate) fn validate_bitmap_extension(
+    lb_pair_pubkey: Pubkey,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+) -> Result<()> {
+    if let Some(bitmap_extension) = bitmap_extension {
+        if bitmap_extension.lb_pair != lb_pair_pubkey {
+            return Err(QuoteError::MismatchedBitmapExtension {
+                bitmap_extension_lb_pair: bitmap_extension.lb_pair,
+                lb_pair: lb_pair_pubkey,
+            }
+            .into());
+        }
+    }
+
+    Ok(())
 }
 
 fn validate_swap_activation(
@@ -43,18 +392,166 @@ fn validate_swap_activation(
     Ok(())
 }
 
+/// Guard against the traversal spinning on a bin array which doesn't contain the active bin.
+/// This happens when the bitmap claims liquidity in a bin array ahead of the active bin, while the bin arrays in between have no liquidity.
+fn ensure_swap_progress(
+    lb_pair: &LbPair,
+    active_id_before: i32,
+    amount_left: u64,
+    bin_array_index: i64,
+) -> Result<()> {
+    if amount_left > 0 && lb_pair.active_id == active_id_before {
+        return Err(QuoteError::NoProgress {
+            active_id: lb_pair.active_id,
+            bin_array_index: bin_array_index.try_into().context("overflow")?,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn quote_exact_out(
     lb_pair_pubkey: Pubkey,
     lb_pair: &LbPair,
-    mut amount_out: u64,
+    amount_out: u64,
+    swap_for_y: bool,
+    bin_arrays: HashMap<Pubkey, BinArray>,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<SwapExactOutQuote> {
+    quote_exact_out_with_transfer_fee_mode(
+        lb_pair_pubkey,
+        lb_pair,
+        amount_out,
+        swap_for_y,
+        bin_arrays,
+        bitmap_extension,
+        TransferFeeMode::Net,
+        clock,
+        mint_x_account,
+        mint_y_account,
+    )
+}
+
+/// Same as [`quote_exact_out`], but `transfer_fee_mode` decides whether `amount_out` and the returned `amount_in` are adjusted by transfer fee.
+#[allow(clippy::too_many_arguments)]
+pub fn quote_exact_out_with_transfer_fee_mode(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    amount_out: u64,
     swap_for_y: bool,
     bin_arrays: HashMap<Pubkey, BinArray>,
     bitmap_extension: Option<&BinArrayBitmapExtension>,
+    transfer_fee_mode: TransferFeeMode,
     clock: &Clock,
     mint_x_account: &Account,
     mint_y_account: &Account,
 ) -> Result<SwapExactOutQuote> {
+    let (quote, _) = quote_exact_out_internal(
+        lb_pair_pubkey,
+        lb_pair,
+        amount_out,
+        swap_for_y,
+        bin_arrays,
+        bitmap_extension,
+        transfer_fee_mode,
+        None,
+        clock,
+        mint_x_account,
+        mint_y_account,
+        |_| {},
+    )?;
+
+    Ok(quote)
+}
+
+/// Same as [`quote_exact_out`], but stops once the next bin price is worse than `price_limit`, which is a Q64.64 price of token Y per token X.
+/// When swap for Y, the price must not go below the limit. Else, it must not go above the limit.
+/// Returns the quote of the amount out delivered, and the amount out which couldn't be delivered within the price limit.
+#[allow(clippy::too_many_arguments)]
+pub fn quote_exact_out_with_price_limit(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    amount_out: u64,
+    swap_for_y: bool,
+    bin_arrays: HashMap<Pubkey, BinArray>,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    price_limit: u128,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<(SwapExactOutQuote, u64)> {
+    quote_exact_out_internal(
+        lb_pair_pubkey,
+        lb_pair,
+        amount_out,
+        swap_for_y,
+        bin_arrays,
+        bitmap_extension,
+        TransferFeeMode::Net,
+        Some(price_limit),
+        clock,
+        mint_x_account,
+        mint_y_account,
+        |_| {},
+    )
+}
+
+/// Ids of the bins an exact out swap of `amount_out` consumes, in the order they are swapped. Bins without liquidity are skipped over.
+/// The bin arrays the swap needs can be derived from it, instead of fetching a fixed number of bin arrays.
+#[allow(clippy::too_many_arguments)]
+pub fn exact_out_bin_path(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    amount_out: u64,
+    swap_for_y: bool,
+    bin_arrays: HashMap<Pubkey, BinArray>,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<Vec<i32>> {
+    let mut bin_ids = vec![];
+
+    quote_exact_out_internal(
+        lb_pair_pubkey,
+        lb_pair,
+        amount_out,
+        swap_for_y,
+        bin_arrays,
+        bitmap_extension,
+        TransferFeeMode::Net,
+        None,
+        clock,
+        mint_x_account,
+        mint_y_account,
+        |bin_id| bin_ids.push(bin_id),
+    )?;
+
+    Ok(bin_ids)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn quote_exact_out_internal(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    amount_out: u64,
+    swap_for_y: bool,
+    bin_arrays: HashMap<Pubkey, BinArray>,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    transfer_fee_mode: TransferFeeMode,
+    price_limit: Option<u128>,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+    mut on_bin_swapped: impl FnMut(i32),
+) -> Result<(SwapExactOutQuote, u64)> {
+    validate_bitmap_extension(lb_pair_pubkey, bitmap_extension)?;
+
     let current_timestamp = clock.unix_timestamp as u64;
     let current_slot = clock.slot;
     let epoch = clock.epoch;
@@ -73,83 +570,147 @@ pub fn quote_exact_out(
         (mint_y_account, mint_x_account)
     };
 
-    amount_out =
-        calculate_transfer_fee_included_amount(out_mint_account, amount_out, epoch)?.amount;
+    let requested_amount_out = amount_out;
+    let mut amount_out =
+        transfer_fee_mode.transfer_fee_included_amount(out_mint_account, amount_out, epoch)?;
 
-    while amount_out > 0 {
-        let active_bin_array_pubkey = get_bin_array_pubkeys_for_swap(
-            lb_pair_pubkey,
-            &lb_pair,
-            bitmap_extension,
-            swap_for_y,
-            1,
-        )?
-        .pop()
-        .context("Pool out of liquidity")?;
+    let requested_amount_out_of_pool = amount_out;
 
-        let mut active_bin_array = bin_arrays
-            .get(&active_bin_array_pubkey)
-            .cloned()
-            .context("Active bin array not found")?;
+    'swap: while amount_out > 0 {
+        let Some(active_bin_array_index) =
+            get_bin_array_indexes_for_swap(&lb_pair, bitmap_extension, swap_for_y, 1)?.pop()
+        else {
+            let filled_out = requested_amount_out_of_pool
+                .checked_sub(amount_out)
+                .context("MathOverflow")?;
+            return Err(out_of_liquidity_error(
+                &lb_pair,
+                bitmap_extension,
+                swap_for_y,
+                filled_out,
+            )?
+            .into());
+        };
+
+        let active_bin_array_pubkey =
+            derive_bin_array_pda(lb_pair_pubkey, active_bin_array_index.into()).0;
+
+        let Some(mut active_bin_array) = bin_arrays.get(&active_bin_array_pubkey).cloned() else {
+            return Err(QuoteError::MissingBinArray {
+                bin_array_index: active_bin_array_index,
+                consumed_in: total_amount_in
+                    .checked_add(total_fee)
+                    .context("MathOverflow")?,
+                produced_out: requested_amount_out_of_pool
+                    .checked_sub(amount_out)
+                    .context("MathOverflow")?,
+                last_bin_id: lb_pair.active_id,
+            }
+            .into());
+        };
+
+        let active_id_before = lb_pair.active_id;
 
         loop {
             if !active_bin_array.is_bin_id_within_range(lb_pair.active_id)? || amount_out == 0 {
                 break;
             }
 
-            lb_pair.update_volatility_accumulator()?;
+            // Static fee pair, volatility accumulator doesn't affect the fee
+            if lb_pair.parameters.variable_fee_control > 0 {
+                lb_pair.update_volatility_accumulator()?;
+            }
 
             let active_bin = active_bin_array.get_bin_mut(lb_pair.active_id)?;
             let price = active_bin.get_or_store_bin_price(lb_pair.active_id, lb_pair.bin_step)?;
 
-            if !active_bin.is_empty(!swap_for_y) {
-                let bin_max_amount_out = active_bin.get_max_amount_out(swap_for_y);
-                if amount_out >= bin_max_amount_out {
-                    let max_amount_in = active_bin.get_max_amount_in(price, swap_for_y)?;
-                    let max_fee = lb_pair.compute_fee(max_amount_in)?;
+            if let Some(price_limit) = price_limit {
+                let is_price_limit_crossed = if swap_for_y {
+                    price < price_limit
+                } else {
+                    price > price_limit
+                };
 
-                    total_amount_in = total_amount_in
-                        .checked_add(max_amount_in)
-                        .context("MathOverflow")?;
+                if is_price_limit_crossed {
+                    break 'swap;
+                }
+            }
 
-                    total_fee = total_fee.checked_add(max_fee).context("MathOverflow")?;
+            if !active_bin.is_empty(!swap_for_y) {
+                let SwapResult {
+                    amount_in_with_fees,
+                    amount_out: bin_amount_out,
+                    fee,
+                    ..
+                } = active_bin.swap_exact_out(amount_out, price, swap_for_y, &lb_pair, None)?;
 
-                    amount_out = amount_out
-                        .checked_sub(bin_max_amount_out)
-                        .context("MathOverflow")?;
-                } else {
-                    let amount_in = Bin::get_amount_in(amount_out, price, swap_for_y)?;
-                    let fee = lb_pair.compute_fee(amount_in)?;
+                total_amount_in = total_amount_in
+                    .checked_add(
+                        amount_in_with_fees
+                            .checked_sub(fee)
+                            .context("MathOverflow")?,
+                    )
+                    .context("MathOverflow")?;
 
-                    total_amount_in = total_amount_in
-                        .checked_add(amount_in)
-                        .context("MathOverflow")?;
+                total_fee = total_fee.checked_add(fee).context("MathOverflow")?;
 
-                    total_fee = total_fee.checked_add(fee).context("MathOverflow")?;
+                amount_out = amount_out
+                    .checked_sub(bin_amount_out)
+                    .context("MathOverflow")?;
 
-                    amount_out = 0;
-                }
+                on_bin_swapped(lb_pair.active_id);
             }
 
             if amount_out > 0 {
                 lb_pair.advance_active_bin(swap_for_y)?;
             }
         }
+
+        ensure_swap_progress(
+            &lb_pair,
+            active_id_before,
+            amount_out,
+            active_bin_array.index,
+        )?;
     }
 
+    let amount_into_pool = total_amount_in;
+
     total_amount_in = total_amount_in
         .checked_add(total_fee)
         .context("MathOverflow")?;
 
     total_amount_in =
-        calculate_transfer_fee_included_amount(in_mint_account, total_amount_in, epoch)?.amount;
+        transfer_fee_mode.transfer_fee_included_amount(in_mint_account, total_amount_in, epoch)?;
 
-    Ok(SwapExactOutQuote {
-        amount_in: total_amount_in,
-        fee: total_fee,
-    })
+    let amount_out_of_pool = requested_amount_out_of_pool
+        .checked_sub(amount_out)
+        .context("MathOverflow")?;
+
+    let unfulfilled_amount_out = if amount_out > 0 {
+        let delivered_amount_out = transfer_fee_mode.transfer_fee_excluded_amount(
+            out_mint_account,
+            amount_out_of_pool,
+            epoch,
+        )?;
+        requested_amount_out.saturating_sub(delivered_amount_out)
+    } else {
+        0
+    };
+
+    Ok((
+        SwapExactOutQuote {
+            amount_in: total_amount_in,
+            fee: total_fee,
+            amount_into_pool,
+            amount_out_of_pool,
+        },
+        unfulfilled_amount_out,
+    ))
 }
 
+/// Quote swapping `amount_in` against the pair. When `lb_pair` is deserialized from account data, check the data with
+/// [`validate_lb_pair_account_data`] first, as a pair account of an upgraded program layout would be read silently.
 #[allow(clippy::too_many_arguments)]
 pub fn quote_exact_in(
     lb_pair_pubkey: Pubkey,
@@ -162,459 +723,5325 @@ pub fn quote_exact_in(
     mint_x_account: &Account,
     mint_y_account: &Account,
 ) -> Result<SwapExactInQuote> {
-    let current_timestamp = clock.unix_timestamp as u64;
-    let current_slot = clock.slot;
-    let epoch = clock.epoch;
-
-    validate_swap_activation(lb_pair, current_timestamp, current_slot)?;
-
-    let mut lb_pair = *lb_pair;
-    lb_pair.update_references(current_timestamp as i64)?;
+    let SwapExactInQuoteWithBreakdown { quote, .. } = quote_exact_in_with_breakdown(
+        lb_pair_pubkey,
+        lb_pair,
+        amount_in,
+        swap_for_y,
+        bin_arrays,
+        bitmap_extension,
+        clock,
+        mint_x_account,
+        mint_y_account,
+    )?;
 
-    let mut total_amount_out: u64 = 0;
-    let mut total_fee: u64 = 0;
-
-    let (in_mint_account, out_mint_account) = if swap_for_y {
-        (mint_x_account, mint_y_account)
-    } else {
-        (mint_y_account, mint_x_account)
-    };
+    Ok(quote)
+}
 
-    let transfer_fee_excluded_amount_in =
-        calculate_transfer_fee_excluded_amount(in_mint_account, amount_in, epoch)?.amount;
+/// Same as [`quote_exact_in`], but aborts with [`QuoteError::SlippageExceeded`] as soon as the slippage of the amount swapped so far exceeds `max_slippage_bps`.
+/// Slippage is the shortfall of the amount out from the bins against swapping the same amount, excluding swap fee and transfer fee, at the price of the active bin when the quote starts.
+#[allow(clippy::too_many_arguments)]
+pub fn quote_exact_in_max_slippage(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    amount_in: u64,
+    swap_for_y: bool,
+    bin_arrays: HashMap<Pubkey, BinArray>,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    max_slippage_bps: u16,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<SwapExactInQuote> {
+    validate_bitmap_extension(lb_pair_pubkey, bitmap_extension)?;
 
-    let mut amount_left = transfer_fee_excluded_amount_in;
+    let start_price = get_price_from_id(lb_pair.active_id, lb_pair.bin_step)?;
 
-    while amount_left > 0 {
-        //找到有流动性的流动性仓位数组（BinArray）的地址（Pubkey）
-        let active_bin_array_pubkey = get_bin_array_pubkeys_for_swap(
-            lb_pair_pubkey,
-            &lb_pair,
-            bitmap_extension,
-            swap_for_y,
-            1,
-        )?
-        .pop()
-        .context("Pool out of liquidity")?;
+    let SwapExactInQuoteWithBreakdown { quote, .. } = quote_exact_in_internal_with_bin_check(
+        lb_pair,
+        amount_in,
+        swap_for_y,
+        bitmap_extension,
+        TransferFeeMode::Net,
+        clock,
+        mint_x_account,
+        mint_y_account,
+        |bin_array_index| {
+            bin_arrays
+                .get(&derive_bin_array_pda(lb_pair_pubkey, bin_array_index.into()).0)
+                .cloned()
+        },
+        false,
+        |bin_id, quote| {
+            let ideal_amount_out =
+                Bin::get_amount_out(quote.amount_into_pool, start_price, swap_for_y)?;
+            if ideal_amount_out <= quote.amount_out_of_pool {
+                return Ok(());
+            }
 
-        //拿到 BinArray 的地址后，代码会从传入的 bin_arrays 这个 HashMap 中取出对应的 BinArray 数据。
-        //这个 HashMap 相当于一个缓存，预先加载了可能用到的所有 BinArray。
-        let mut active_bin_array = bin_arrays
-            .get(&active_bin_array_pubkey)
-            .cloned()
-            .context("Active bin array not found")?;
+            let realized_bps: u64 = safe_mul_div_cast(
+                (ideal_amount_out - quote.amount_out_of_pool).into(),
+                BASIS_POINT_MAX as u128,
+                ideal_amount_out.into(),
+                Rounding::Down,
+            )?;
 
-        //这个循环负责在当前找到的 BinArray (大箱子) 内部，逐个 Bin (小格子) 地进行兑换。
-        loop {
-            //第一个循环退出条件:检查当前池子活跃的 Bin ID (lb_pair.active_id) 是否还在这个 BinArray 的范围内。
-            //如果不在，说明这个“大箱子”里的流动性已经用完了，需要 break 掉内层循环，让外层循环去寻找下一个 BinArray。
-            //第二个循环退出条件:amount_left == 0: 如果钱已经花完了，也就没必要继续了，直接 break。
-            if !active_bin_array.is_bin_id_within_range(lb_pair.active_id)? || amount_left == 0 {
-                break;
+            if realized_bps > max_slippage_bps.into() {
+                return Err(QuoteError::SlippageExceeded {
+                    at_bin_id: bin_id,
+                    realized_bps,
+                }
+                .into());
             }
 
-            lb_pair.update_volatility_accumulator()?;
+            Ok(())
+        },
+    )?;
 
-            //首先，代码从当前的 BinArray（大货柜）中取出当前活跃的那个 Bin（小货架）。
-            let active_bin = active_bin_array.get_bin_mut(lb_pair.active_id)?;
-            //它计算出这个 Bin 的确切价格 price。在Meteora中，每个 Bin 都代表一个固定的价格区间
-            let price = active_bin.get_or_store_bin_price(lb_pair.active_id, lb_pair.bin_step)?;
+    Ok(quote)
+}
 
-            //这行代码检查这个“bin”上是否还有你想要的代币库存。如果没有，就跳过这个bin，直接去下一个。
-            if !active_bin.is_empty(!swap_for_y) {
-                let SwapResult {
-                    amount_in_with_fees,
-                    amount_out,
-                    fee,
-                    ..
-                } = active_bin.swap(amount_left, price, swap_for_y, &lb_pair, None)?;
+/// Same as [`quote_exact_in`], but transfer fees are computed at `epoch` instead of the clock epoch.
+/// Useful to simulate a swap around a scheduled Token 2022 transfer fee change.
+#[allow(clippy::too_many_arguments)]
+pub fn quote_exact_in_at(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    amount_in: u64,
+    swap_for_y: bool,
+    bin_arrays: HashMap<Pubkey, BinArray>,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    clock: &Clock,
+    epoch: u64,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<SwapExactInQuote> {
+    let clock = Clock {
+        epoch,
+        ..clock.clone()
+    };
 
-                amount_left = amount_left
-                    .checked_sub(amount_in_with_fees)
-                    .context("MathOverflow")?;
+    quote_exact_in(
+        lb_pair_pubkey,
+        lb_pair,
+        amount_in,
+        swap_for_y,
+        bin_arrays,
+        bitmap_extension,
+        &clock,
+        mint_x_account,
+        mint_y_account,
+    )
+}
 
-                total_amount_out = total_amount_out
-                    .checked_add(amount_out)
-                    .context("MathOverflow")?;
-                total_fee = total_fee.checked_add(fee).context("MathOverflow")?;
-            }
+/// Same as [`quote_exact_in`], but `skip_reference_update` skips the decay of the volatility reference to the clock timestamp, quoting with the
+/// volatility state stored in the pair as is, with no decay for the time passed since the last swap. This is not what the program executes,
+/// as it always updates the references before a swap. To reconcile a past swap, pass the pair state and clock of that swap to [`quote_exact_in`].
+#[allow(clippy::too_many_arguments)]
+pub fn quote_exact_in_with_skip_reference_update(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    amount_in: u64,
+    swap_for_y: bool,
+    bin_arrays: HashMap<Pubkey, BinArray>,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    skip_reference_update: bool,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<SwapExactInQuote> {
+    validate_bitmap_extension(lb_pair_pubkey, bitmap_extension)?;
 
-            if amount_left > 0 {
-                lb_pair.advance_active_bin(swap_for_y)?;
-            }
-        }
-    }
+    let SwapExactInQuoteWithBreakdown { quote, .. } = quote_exact_in_internal_with_bin_check(
+        lb_pair,
+        amount_in,
+        swap_for_y,
+        bitmap_extension,
+        TransferFeeMode::Net,
+        clock,
+        mint_x_account,
+        mint_y_account,
+        |bin_array_index| {
+            bin_arrays
+                .get(&derive_bin_array_pda(lb_pair_pubkey, bin_array_index.into()).0)
+                .cloned()
+        },
+        skip_reference_update,
+        |_, _| Ok(()),
+    )?;
 
-    let transfer_fee_excluded_amount_out =
-        calculate_transfer_fee_excluded_amount(out_mint_account, total_amount_out, epoch)?.amount;
+    Ok(quote)
+}
 
-    Ok(SwapExactInQuote {
-        amount_out: transfer_fee_excluded_amount_out,
-        fee: total_fee,
-    })
+/// Same as [`quote_exact_in`], but `transfer_fee_mode` decides whether `amount_in` and the returned `amount_out` are adjusted by transfer fee.
+#[allow(clippy::too_many_arguments)]
+pub fn quote_exact_in_with_transfer_fee_mode(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    amount_in: u64,
+    swap_for_y: bool,
+    bin_arrays: HashMap<Pubkey, BinArray>,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    transfer_fee_mode: TransferFeeMode,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<SwapExactInQuote> {
+    validate_bitmap_extension(lb_pair_pubkey, bitmap_extension)?;
+
+    let SwapExactInQuoteWithBreakdown { quote, .. } = quote_exact_in_internal(
+        lb_pair,
+        amount_in,
+        swap_for_y,
+        bitmap_extension,
+        transfer_fee_mode,
+        clock,
+        mint_x_account,
+        mint_y_account,
+        |bin_array_index| {
+            bin_arrays
+                .get(&derive_bin_array_pda(lb_pair_pubkey, bin_array_index.into()).0)
+                .cloned()
+        },
+    )?;
+
+    Ok(quote)
 }
 
-///为一笔即将发生的交易（Swap）找到接下来有流动性的流动性仓位数组（BinArray）的地址（Pubkey）
-/// 由于 bitmap 的大小有限，Meteora 设计了一套扩展机制：
-/// 内部 bitmap: LbPair 账户自身带有一个大小固定的 bitmap。
-/// 扩展 bitmap (bitmap_extension): 如果流动性分布范围很广，超出了内部 bitmap 能表示的范围，就可以启用一个或多个 BinArrayBitmapExtension 账户来存储额外的 bitmap。
-/// 这段代码的作用就是智能地在这两种 bitmap 中进行搜索，找到我们需要的 BinArray 地址。
-pub fn get_bin_array_pubkeys_for_swap(
+/// Quote exact in both with transfer fee adjustments, as [`quote_exact_in`], and without, as the pool math alone. Returns the quotes in that order.
+/// The gap between the amounts out is the drag of the Token 2022 transfer fees.
+#[allow(clippy::too_many_arguments)]
+pub fn quote_exact_in_with_and_without_transfer_fee(
     lb_pair_pubkey: Pubkey,
     lb_pair: &LbPair,
+    amount_in: u64,
+    swap_for_y: bool,
+    bin_arrays: HashMap<Pubkey, BinArray>,
     bitmap_extension: Option<&BinArrayBitmapExtension>,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<(SwapExactInQuote, SwapExactInQuote)> {
+    validate_bitmap_extension(lb_pair_pubkey, bitmap_extension)?;
+
+    let quote = |transfer_fee_mode: TransferFeeMode| -> Result<SwapExactInQuote> {
+        let SwapExactInQuoteWithBreakdown { quote, .. } = quote_exact_in_internal(
+            lb_pair,
+            amount_in,
+            swap_for_y,
+            bitmap_extension,
+            transfer_fee_mode,
+            clock,
+            mint_x_account,
+            mint_y_account,
+            |bin_array_index| {
+                bin_arrays
+                    .get(&derive_bin_array_pda(lb_pair_pubkey, bin_array_index.into()).0)
+                    .cloned()
+            },
+        )?;
+
+        Ok(quote)
+    };
+
+    Ok((quote(TransferFeeMode::Net)?, quote(TransferFeeMode::Gross)?))
+}
+
+/// Same as [`quote_exact_in`], but also returns the swap result of every bin traversed.
+#[allow(clippy::too_many_arguments)]
+pub fn quote_exact_in_with_breakdown(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    amount_in: u64,
     swap_for_y: bool,
-    take_count: u8,
-) -> Result<Vec<Pubkey>> {
-    //根据当前活跃的 Bin ID (lb_pair.active_id) 计算出它所在的 BinArray 的索引。搜索就从这个索引开始
-    let mut start_bin_array_idx = BinArray::bin_id_to_bin_array_index(lb_pair.active_id)?;
+    bin_arrays: HashMap<Pubkey, BinArray>,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<SwapExactInQuoteWithBreakdown> {
+    validate_bitmap_extension(lb_pair_pubkey, bitmap_extension)?;
 
-    //需要注意的是 这里的index并不是bitmap上这个bin_array的位置,而是BinArray 的索引值
-    //用来存放找到的、有流动性的 BinArray 的索引
-    let mut bin_array_idx = vec![];
-    //increment: 搜索方向。如果是用 X 换 Y (swap_for_y is true)，价格是下降的，所以索引要递减 (-1)；反之则递增 (+1)。
-    let increment = if swap_for_y { -1 } else { 1 };
+    quote_exact_in_internal(
+        lb_pair,
+        amount_in,
+        swap_for_y,
+        bitmap_extension,
+        TransferFeeMode::Net,
+        clock,
+        mint_x_account,
+        mint_y_account,
+        |bin_array_index| {
+            bin_arrays
+                .get(&derive_bin_array_pda(lb_pair_pubkey, bin_array_index.into()).0)
+                .cloned()
+        },
+    )
+}
 
-    //循环的目的是找到 take_count 个符合条件的 BinArray 索引
-    loop {
-        if bin_array_idx.len() == take_count as usize {
-            break;
-        }
+/// Same as [`quote_exact_in`], but takes bin arrays as a slice of (bin array index, bin array) sorted by index.
+/// Avoids the bin array PDA derivation and hashing of the `HashMap` lookup.
+#[allow(clippy::too_many_arguments)]
+pub fn quote_exact_in_with_sorted_bin_arrays(
+    lb_pair: &LbPair,
+    amount_in: u64,
+    swap_for_y: bool,
+    bin_arrays: &[(i32, BinArray)],
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<SwapExactInQuote> {
+    let SwapExactInQuoteWithBreakdown { quote, .. } = quote_exact_in_internal(
+        lb_pair,
+        amount_in,
+        swap_for_y,
+        bitmap_extension,
+        TransferFeeMode::Net,
+        clock,
+        mint_x_account,
+        mint_y_account,
+        |bin_array_index| {
+            bin_arrays
+                .binary_search_by_key(&bin_array_index, |(index, _)| *index)
+                .ok()
+                .map(|pos| bin_arrays[pos].1)
+        },
+    )?;
 
-        //它检查当前的 start_bin_array_idx 是否已经超出了 LbPair 内部 bitmap 所能管理的范围。
-        if lb_pair.is_overflow_default_bin_array_bitmap(start_bin_array_idx) {
-            //超出内部范围，需要在扩展 bitmap 中搜索
-            //确认 bitmap_extension 账户存在。如果不存在但又需要搜索它，说明没有更多流动性了，直接退出循环
-            let Some(bitmap_extension) = bitmap_extension else {
-                break;
-            };
+    Ok(quote)
+}
 
-            //调用扩展 bitmap 的搜索方法，从 start_bin_array_idx 开始按指定方向 (swap_for_y) 寻找下一个为 1 的位。
-            let Ok((next_bin_array_idx, has_liquidity)) = bitmap_extension
-                .next_bin_array_index_with_liquidity(swap_for_y, start_bin_array_idx)
-            else {
-                // Out of search range. No liquidity.
-                //如果没搜索到 说明没有流动性了
-                break;
-            };
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn quote_exact_in_internal(
+    lb_pair: &LbPair,
+    amount_in: u64,
+    swap_for_y: bool,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    transfer_fee_mode: TransferFeeMode,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+    get_bin_array: impl Fn(i32) -> Option<BinArray>,
+) -> Result<SwapExactInQuoteWithBreakdown> {
+    quote_exact_in_internal_with_bin_check(
+        lb_pair,
+        amount_in,
+        swap_for_y,
+        bitmap_extension,
+        transfer_fee_mode,
+        clock,
+        mint_x_account,
+        mint_y_account,
+        get_bin_array,
+        false,
+        |_, _| Ok(()),
+    )
+}
 
-            // 如果找到了 (has_liquidity 为 true)，就把返回的 next_bin_array_idx 存入结果列表，并更新下一次搜索的起始点 start_bin_array_idx。
-            if has_liquidity {
-                bin_array_idx.push(next_bin_array_idx);
-                start_bin_array_idx = next_bin_array_idx + increment; //这里实在bin_array_index上进行加减
-            } else {
-                //如果没找到，说明在这个 bitmap_extension 中搜索完了。返回的 next_bin_array_idx 会是这个 bitmap 的边界，
-                //代码将 start_bin_array_idx 更新为这个边界值，以便在下一次循环中可以切换回内部 bitmap 或者另一个 bitmap_extension 继续搜索
-                start_bin_array_idx = next_bin_array_idx;
-            }
-        //在内部 bitmap 范围内搜索
-        } else {
-            //调用 LbPair 自身的搜索方法，在其内部 bitmap 中寻找
-            //逻辑和情况一非常相似：找到就记录并更新下一次的起点；没找到就将 start_bin_array_idx 更新到内部 bitmap 的边界，以便下次循环可以切换到扩展 bitmap。
-            let Ok((next_bin_array_idx, has_liquidity)) = lb_pair
-                .next_bin_array_index_with_liquidity_internal(swap_for_y, start_bin_array_idx)
-            else {
-                break;
-            };
+/// Same as [`quote_exact_in_internal`], but calls `on_bin_swapped` with the bin id and the quote so far after every bin swapped.
+/// An error returned by `on_bin_swapped` aborts the quote. `skip_reference_update` quotes with the stored volatility reference, without decaying it to `clock`.
+#[allow(clippy::too_many_arguments)]
+fn quote_exact_in_internal_with_bin_check(
+    lb_pair: &LbPair,
+    amount_in: u64,
+    swap_for_y: bool,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    transfer_fee_mode: TransferFeeMode,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+    get_bin_array: impl Fn(i32) -> Option<BinArray>,
+    skip_reference_update: bool,
+    on_bin_swapped: impl FnMut(i32, &SwapExactInQuote) -> Result<()>,
+) -> Result<SwapExactInQuoteWithBreakdown> {
+    let current_timestamp = clock.unix_timestamp as u64;
+    let current_slot = clock.slot;
+    let epoch = clock.epoch;
 
-            if has_liquidity {
-                bin_array_idx.push(next_bin_array_idx);
-                start_bin_array_idx = next_bin_array_idx + increment;
-            //场景
-            // 我们要向右搜索（swap_for_y = false），所以 increment = 1。
-            // 我们需要找到 3 个有流动性的 BinArray（take_count = 3）。
-            // 假设当前活跃的 bin_array_index 是 10。
-            // 循环过程
-            // 第一次循环:
-            // 起始点: start_bin_array_idx = 10。
-            // 搜索: next_bin_array_index_with_liquidity_internal 从索引 10 开始向右搜索。
-            // 结果: 假设它找到了第一个有流动性的 BinArray 在索引 15。所以 next_bin_array_idx = 15。
-            // 记录: 我们将 15 添加到结果列表 bin_array_idx 中。bin_array_idx 现在是 [15]。
-            // 更新起始点:
-            // 如果我们不更新 start_bin_array_idx，下一次循环还会从 10 开始，又会找到 15，陷入死循环。
-            // 如果我们只设置为 start_bin_array_idx = next_bin_array_idx (也就是 15)，下一次循环从 15 开始，还是会找到 15，同样陷入死循环。
-            // 正确的做法: 我们需要从刚刚找到的位置的下一个位置开始搜索。所以我们执行 start_bin_array_idx = 15 + 1 = 16。
-            // 第二次循环:
-            // 起始点: start_bin_array_idx = 16。
-            // 搜索: 从索引 16 开始向右搜索。
-            // 结果: 假设找到了下一个有流动性的 BinArray 在索引 18。next_bin_array_idx = 18。
-            // 记录: bin_array_idx 现在是 [15, 18]。
-            // 更新起始点: start_bin_array_idx = 18 + 1 = 19。
-            // 第三次循环:
-            // 起始点: start_bin_array_idx = 19。
-            // 搜索: 从索引 19 开始向右搜索。
-            // 结果: 假设找到了下一个在索引 25。next_bin_array_idx = 25。
-            // 记录: bin_array_idx 现在是 [15, 18, 25]。
-            // 更新起始点: start_bin_array_idx = 25 + 1 = 26。
-            } else {
-                // Switch to external bitmap
-                start_bin_array_idx = next_bin_array_idx;
-            }
-        }
+    validate_swap_activation(lb_pair, current_timestamp, current_slot)?;
+
+    let mut lb_pair = *lb_pair;
+    if !skip_reference_update {
+        lb_pair.update_references(current_timestamp as i64)?;
     }
 
-    //循环结束后，bin_array_idx 里就存放了所有找到的 BinArray 的索引
-    let bin_array_pubkeys = bin_array_idx
-        .into_iter()
-        .map(|idx| derive_bin_array_pda(lb_pair_pubkey, idx.into()).0)
-        .collect();
+    let mut quote = SwapExactInQuote::default();
+    let mut breakdown = vec![];
 
-    Ok(bin_array_pubkeys)
+    let (in_mint_account, out_mint_account) = if swap_for_y {
+        (mint_x_account, mint_y_account)
+    } else {
+        (mint_y_account, mint_x_account)
+    };
+
+    let transfer_fee_excluded_amount_in =
+        transfer_fee_mode.transfer_fee_excluded_amount(in_mint_account, amount_in, epoch)?;
+
+    swap_exact_in_through_bin_arrays(
+        &mut lb_pair,
+        transfer_fee_excluded_amount_in,
+        swap_for_y,
+        bitmap_extension,
+        get_bin_array,
+        |_| {},
+        on_bin_swapped,
+        &mut quote,
+        &mut breakdown,
+    )?;
+
+    quote.amount_out = transfer_fee_mode.transfer_fee_excluded_amount(
+        out_mint_account,
+        quote.amount_out_of_pool,
+        epoch,
+    )?;
+
+    Ok(SwapExactInQuoteWithBreakdown { quote, breakdown })
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use anchor_client::solana_sdk::clock::Clock;
-    use anchor_client::{
-        solana_client::nonblocking::rpc_client::RpcClient, solana_sdk::pubkey::Pubkey, Cluster,
+/// Quote `amount_in` with only the active bin array, for swaps small enough to stay within it.
+/// Returns the quote of the part swapped within the bin array, and the amount in left unfilled when the swap would continue into the next bin array.
+/// The unfilled amount is transfer fee excluded. Quote again with more bin arrays when it is non zero.
+pub fn quote_exact_in_single_array(
+    lb_pair: &LbPair,
+    active_bin_array: &BinArray,
+    amount_in: u64,
+    swap_for_y: bool,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<(SwapExactInQuote, u64)> {
+    let current_timestamp = clock.unix_timestamp as u64;
+    let current_slot = clock.slot;
+    let epoch = clock.epoch;
+
+    validate_swap_activation(lb_pair, current_timestamp, current_slot)?;
+
+    ensure!(
+        active_bin_array.is_bin_id_within_range(lb_pair.active_id)?,
+        "Active bin is not in the bin array"
+    );
+
+    let mut lb_pair = *lb_pair;
+    lb_pair.update_references(current_timestamp as i64)?;
+
+    let mut active_bin_array = *active_bin_array;
+    let mut quote = SwapExactInQuote::default();
+
+    let (in_mint_account, out_mint_account) = if swap_for_y {
+        (mint_x_account, mint_y_account)
+    } else {
+        (mint_y_account, mint_x_account)
     };
-    use std::str::FromStr;
 
-    /// Get on chain clock
-    async fn get_clock(rpc_client: RpcClient) -> Result<Clock> {
-        let clock_account = rpc_client
-            .get_account(&anchor_client::solana_sdk::sysvar::clock::ID)
-            .await?;
+    let transfer_fee_excluded_amount_in =
+        calculate_transfer_fee_excluded_amount(in_mint_account, amount_in, epoch)?.amount;
+
+    let unfilled_amount_in = swap_exact_in_through_bin_array(
+        &mut lb_pair,
+        &mut active_bin_array,
+        transfer_fee_excluded_amount_in,
+        swap_for_y,
+        &mut |_, _| Ok(()),
+        &mut quote,
+        &mut vec![],
+    )?;
+
+    quote.amount_out =
+        calculate_transfer_fee_excluded_amount(out_mint_account, quote.amount_out_of_pool, epoch)?
+            .amount;
+
+    Ok((quote, unfilled_amount_in))
+}
+
+/// Swap `amount_in` (transfer fee excluded) through the bins, starting from the pair active bin.
+/// Accumulates the pool side amounts and fees into `quote`. `quote.amount_out` is left untouched for the caller to apply transfer fee.
+#[allow(clippy::too_many_arguments)]
+fn swap_exact_in_through_bin_arrays(
+    lb_pair: &mut LbPair,
+    amount_in: u64,
+    swap_for_y: bool,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    get_bin_array: impl Fn(i32) -> Option<BinArray>,
+    mut on_bin_array_swapped: impl FnMut(BinArray),
+    mut on_bin_swapped: impl FnMut(i32, &SwapExactInQuote) -> Result<()>,
+    quote: &mut SwapExactInQuote,
+    breakdown: &mut Vec<BinSwapBreakdown>,
+) -> Result<()> {
+    let mut amount_left = amount_in;
+
+    while amount_left > 0 {
+        //找到有流动性的流动性仓位数组（BinArray）的索引
+        let Some(active_bin_array_index) =
+            get_bin_array_indexes_for_swap(lb_pair, bitmap_extension, swap_for_y, 1)?.pop()
+        else {
+            return Err(out_of_liquidity_error(
+                lb_pair,
+                bitmap_extension,
+                swap_for_y,
+                quote.amount_out_of_pool,
+            )?
+            .into());
+        };
+
+        //拿到 BinArray 的索引后，代码会从传入的 bin_arrays 中取出对应的 BinArray 数据。
+        //bin_arrays 相当于一个缓存，预先加载了可能用到的所有 BinArray。
+        let Some(mut active_bin_array) = get_bin_array(active_bin_array_index) else {
+            return Err(QuoteError::MissingBinArray {
+                bin_array_index: active_bin_array_index,
+                consumed_in: quote
+                    .amount_into_pool
+                    .checked_add(quote.fee)
+                    .context("MathOverflow")?,
+                produced_out: quote.amount_out_of_pool,
+                last_bin_id: lb_pair.active_id,
+            }
+            .into());
+        };
+
+        let active_id_before = lb_pair.active_id;
+
+        amount_left = swap_exact_in_through_bin_array(
+            lb_pair,
+            &mut active_bin_array,
+            amount_left,
+            swap_for_y,
+            &mut on_bin_swapped,
+            quote,
+            breakdown,
+        )?;
+
+        ensure_swap_progress(
+            lb_pair,
+            active_id_before,
+            amount_left,
+            active_bin_array.index,
+        )?;
+
+        on_bin_array_swapped(active_bin_array);
+    }
+
+    Ok(())
+}
+
+/// Swap `amount_in` (transfer fee excluded) through the bins of `active_bin_array`, starting from the pair active bin, until the amount is consumed or the
+/// active bin leaves the bin array. Accumulates into `quote` like [`swap_exact_in_through_bin_arrays`]. Returns the amount in left.
+fn swap_exact_in_through_bin_array(
+    lb_pair: &mut LbPair,
+    active_bin_array: &mut BinArray,
+    amount_in: u64,
+    swap_for_y: bool,
+    on_bin_swapped: &mut impl FnMut(i32, &SwapExactInQuote) -> Result<()>,
+    quote: &mut SwapExactInQuote,
+    breakdown: &mut Vec<BinSwapBreakdown>,
+) -> Result<u64> {
+    let mut amount_left = amount_in;
+
+    //这个循环负责在当前找到的 BinArray (大箱子) 内部，逐个 Bin (小格子) 地进行兑换。
+    loop {
+        //第一个循环退出条件:检查当前池子活跃的 Bin ID (lb_pair.active_id) 是否还在这个 BinArray 的范围内。
+        //如果不在，说明这个“大箱子”里的流动性已经用完了，需要 break 掉内层循环，让外层循环去寻找下一个 BinArray。
+        //第二个循环退出条件:amount_left == 0: 如果钱已经花完了，也就没必要继续了，直接 break。
+        if !active_bin_array.is_bin_id_within_range(lb_pair.active_id)? || amount_left == 0 {
+            break;
+        }
+
+        // Static fee pair, volatility accumulator doesn't affect the fee
+        if lb_pair.parameters.variable_fee_control > 0 {
+            lb_pair.update_volatility_accumulator()?;
+        }
+
+        //首先，代码从当前的 BinArray（大货柜）中取出当前活跃的那个 Bin（小货架）。
+        let active_bin = active_bin_array.get_bin_mut(lb_pair.active_id)?;
+        //它计算出这个 Bin 的确切价格 price。在Meteora中，每个 Bin 都代表一个固定的价格区间
+        let price = active_bin.get_or_store_bin_price(lb_pair.active_id, lb_pair.bin_step)?;
+
+        //这行代码检查这个“bin”上是否还有你想要的代币库存。如果没有，就跳过这个bin，直接去下一个。
+        if !active_bin.is_empty(!swap_for_y) {
+            let fee_bps = lb_pair.get_total_fee_bps()?;
+            let SwapResult {
+                amount_in_with_fees,
+                amount_out,
+                fee,
+                protocol_fee_after_host_fee,
+                ..
+            } = active_bin.swap(amount_left, price, swap_for_y, lb_pair, None)?;
+
+            amount_left = amount_left
+                .checked_sub(amount_in_with_fees)
+                .context("MathOverflow")?;
+
+            quote.amount_out_of_pool = quote.amount_out_of_pool.checked_add(amount_out).ok_or(
+                QuoteError::OutputExceedsU64 {
+                    bin_id: lb_pair.active_id,
+                },
+            )?;
+            quote.fee = quote.fee.checked_add(fee).context("MathOverflow")?;
+            quote.final_bin_partially_filled = !active_bin.is_empty(!swap_for_y);
+            quote.post_swap_volatility_accumulator = lb_pair.v_parameters.volatility_accumulator;
+
+            // No host fee, protocol fee after host fee is the full protocol fee
+            quote.protocol_fee = quote
+                .protocol_fee
+                .checked_add(protocol_fee_after_host_fee)
+                .context("MathOverflow")?;
+
+            quote.amount_into_pool = quote
+                .amount_into_pool
+                .checked_add(
+                    amount_in_with_fees
+                        .checked_sub(fee)
+                        .context("MathOverflow")?,
+                )
+                .context("MathOverflow")?;
+
+            // A drained bin gives out its whole balance, which is less than the precise amount of the rounded up amount in
+            let precise_amount_out = if active_bin.is_empty(!swap_for_y) {
+                u128::from(amount_out) << SCALE_OFFSET
+            } else {
+                Bin::get_precise_amount_out(
+                    amount_in_with_fees
+                        .checked_sub(fee)
+                        .context("MathOverflow")?,
+                    price,
+                    swap_for_y,
+                )?
+            };
+
+            breakdown.push(BinSwapBreakdown {
+                bin_id: lb_pair.active_id,
+                amount_in: amount_in_with_fees,
+                amount_out,
+                precise_amount_out,
+                fee,
+                protocol_fee: protocol_fee_after_host_fee,
+                fee_bps,
+                remaining_amount_x: active_bin.amount_x,
+                remaining_amount_y: active_bin.amount_y,
+            });
+
+            on_bin_swapped(lb_pair.active_id, quote)?;
+        } else {
+            // Skip to the last empty bin in a row within the bin array, so the advance below lands on the next bin with liquidity,
+            // or leaves the bin array. Volatility accumulator only depends on the active id, so skipped bins don't affect the fee.
+            let offset = active_bin_array.get_bin_index_in_array(lb_pair.active_id)?;
+            let is_empty = |bin: &&Bin| bin.is_empty(!swap_for_y);
+
+            let skipped_bins = if swap_for_y {
+                let empty_bins = active_bin_array.bins[..=offset]
+                    .iter()
+                    .rev()
+                    .take_while(is_empty)
+                    .count();
+                -(empty_bins as i32 - 1)
+            } else {
+                let empty_bins = active_bin_array.bins[offset..]
+                    .iter()
+                    .take_while(is_empty)
+                    .count();
+                empty_bins as i32 - 1
+            };
+
+            lb_pair.active_id = lb_pair
+                .active_id
+                .checked_add(skipped_bins)
+                .context("MathOverflow")?;
+        }
+
+        if amount_left > 0 {
+            lb_pair.advance_active_bin(swap_for_y)?;
+        }
+    }
+
+    Ok(amount_left)
+}
+
+/// Partial state of an exact in quote. Used to quote a swap in chunks, resuming the bin traversal where the previous chunk stopped.
+#[derive(Debug, Clone)]
+pub struct QuoteCursor {
+    pub lb_pair_pubkey: Pubkey,
+    /// Pair state after the consumed input, which tracks the active_id and volatility_accumulator
+    pub lb_pair: LbPair,
+    pub swap_for_y: bool,
+    /// Bin arrays with the bin reserves after the consumed input
+    pub bin_arrays: HashMap<Pubkey, BinArray>,
+    /// Total amount in consumed so far, includes transfer fee
+    pub amount_in: u64,
+    /// Quote of `amount_in`
+    pub quote: SwapExactInQuote,
+}
+
+impl QuoteCursor {
+    /// Cursor which haven't consumed any input yet.
+    pub fn new(
+        lb_pair_pubkey: Pubkey,
+        lb_pair: &LbPair,
+        swap_for_y: bool,
+        bin_arrays: HashMap<Pubkey, BinArray>,
+        clock: &Clock,
+    ) -> Result<Self> {
+        let current_timestamp = clock.unix_timestamp as u64;
+        validate_swap_activation(lb_pair, current_timestamp, clock.slot)?;
+
+        let mut lb_pair = *lb_pair;
+        lb_pair.update_references(current_timestamp as i64)?;
+
+        Ok(Self {
+            lb_pair_pubkey,
+            lb_pair,
+            swap_for_y,
+            bin_arrays,
+            amount_in: 0,
+            quote: SwapExactInQuote::default(),
+        })
+    }
+}
+
+/// Continue the exact in quote of `cursor` with `additional_amount_in`. The returned cursor quotes the total amount in.
+/// Transfer fee is charged on the total amount, and bins are swapped in chunks, so the result might differ from a single [`quote_exact_in`] of the total amount by rounding.
+pub fn resume_quote_exact_in(
+    cursor: QuoteCursor,
+    additional_amount_in: u64,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<QuoteCursor> {
+    let QuoteCursor {
+        lb_pair_pubkey,
+        mut lb_pair,
+        swap_for_y,
+        mut bin_arrays,
+        amount_in,
+        mut quote,
+    } = cursor;
+
+    validate_bitmap_extension(lb_pair_pubkey, bitmap_extension)?;
+
+    let epoch = clock.epoch;
+
+    let (in_mint_account, out_mint_account) = if swap_for_y {
+        (mint_x_account, mint_y_account)
+    } else {
+        (mint_y_account, mint_x_account)
+    };
+
+    let amount_in = amount_in
+        .checked_add(additional_amount_in)
+        .context("MathOverflow")?;
+
+    let transfer_fee_excluded_amount_in =
+        calculate_transfer_fee_excluded_amount(in_mint_account, amount_in, epoch)?.amount;
+
+    let consumed_amount_in = quote
+        .amount_into_pool
+        .checked_add(quote.fee)
+        .context("MathOverflow")?;
+
+    let mut swapped_bin_arrays = vec![];
+
+    swap_exact_in_through_bin_arrays(
+        &mut lb_pair,
+        transfer_fee_excluded_amount_in.saturating_sub(consumed_amount_in),
+        swap_for_y,
+        bitmap_extension,
+        |bin_array_index| {
+            bin_arrays
+                .get(&derive_bin_array_pda(lb_pair_pubkey, bin_array_index.into()).0)
+                .cloned()
+        },
+        |bin_array| swapped_bin_arrays.push(bin_array),
+        |_, _| Ok(()),
+        &mut quote,
+        &mut vec![],
+    )?;
+
+    for bin_array in swapped_bin_arrays {
+        bin_arrays.insert(
+            derive_bin_array_pda(lb_pair_pubkey, bin_array.index).0,
+            bin_array,
+        );
+    }
+
+    quote.amount_out =
+        calculate_transfer_fee_excluded_amount(out_mint_account, quote.amount_out_of_pool, epoch)?
+            .amount;
+
+    Ok(QuoteCursor {
+        lb_pair_pubkey,
+        lb_pair,
+        swap_for_y,
+        bin_arrays,
+        amount_in,
+        quote,
+    })
+}
+
+/// Sum of token X and Y in the bins of `bin_arrays` after swapping `amount_in`.
+/// Only the given bin arrays are counted, so pass every bin array with liquidity to get the pool totals.
+#[allow(clippy::too_many_arguments)]
+pub fn post_swap_token_ratio(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    amount_in: u64,
+    swap_for_y: bool,
+    bin_arrays: HashMap<Pubkey, BinArray>,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<(u128, u128)> {
+    let cursor = QuoteCursor::new(lb_pair_pubkey, lb_pair, swap_for_y, bin_arrays, clock)?;
+    let cursor = resume_quote_exact_in(
+        cursor,
+        amount_in,
+        bitmap_extension,
+        clock,
+        mint_x_account,
+        mint_y_account,
+    )?;
+
+    let mut total_x = 0u128;
+    let mut total_y = 0u128;
+
+    for bin in cursor
+        .bin_arrays
+        .values()
+        .flat_map(|bin_array| bin_array.bins.iter())
+    {
+        total_x = total_x
+            .checked_add(bin.amount_x.into())
+            .context("MathOverflow")?;
+        total_y = total_y
+            .checked_add(bin.amount_y.into())
+            .context("MathOverflow")?;
+    }
+
+    Ok((total_x, total_y))
+}
+
+/// Slippage of swapping `amount_in`, in bps, which is the shortfall of the quoted amount out against swapping the whole amount at the active bin price.
+/// Includes the swap fee and the price impact. Transfer fee is excluded, as both amounts are compared within the pool. Rounded up.
+#[allow(clippy::too_many_arguments)]
+pub fn realized_slippage_bps(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    amount_in: u64,
+    swap_for_y: bool,
+    bin_arrays: HashMap<Pubkey, BinArray>,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<u64> {
+    let quote = quote_exact_in(
+        lb_pair_pubkey,
+        lb_pair,
+        amount_in,
+        swap_for_y,
+        bin_arrays,
+        bitmap_extension,
+        clock,
+        mint_x_account,
+        mint_y_account,
+    )?;
+
+    let spot_price = get_price_from_id(lb_pair.active_id, lb_pair.bin_step)?;
+    let amount_in_pool = quote
+        .amount_into_pool
+        .checked_add(quote.fee)
+        .context("MathOverflow")?;
+    let no_impact_amount_out = Bin::get_amount_out(amount_in_pool, spot_price, swap_for_y)?;
+
+    if no_impact_amount_out == 0 {
+        return Ok(0);
+    }
+
+    let shortfall = no_impact_amount_out.saturating_sub(quote.amount_out_of_pool);
+
+    safe_mul_div_cast(
+        shortfall.into(),
+        BASIS_POINT_MAX as u128,
+        no_impact_amount_out.into(),
+        Rounding::Up,
+    )
+}
+
+/// Re-run a quote against fresh accounts, and check whether the fresh amount out is still within `tolerance_bps` below the original amount out.
+/// A fresh quote with more amount out than the original is always valid.
+#[allow(clippy::too_many_arguments)]
+pub fn revalidate_quote(
+    original: &SwapExactInQuote,
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    amount_in: u64,
+    swap_for_y: bool,
+    bin_arrays: HashMap<Pubkey, BinArray>,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+    tolerance_bps: u16,
+) -> Result<bool> {
+    ensure!(
+        tolerance_bps as u64 <= BASIS_POINT_MAX as u64,
+        "Tolerance must be within {} bps",
+        BASIS_POINT_MAX
+    );
+
+    let fresh_quote = quote_exact_in(
+        lb_pair_pubkey,
+        lb_pair,
+        amount_in,
+        swap_for_y,
+        bin_arrays,
+        bitmap_extension,
+        clock,
+        mint_x_account,
+        mint_y_account,
+    )?;
+
+    let min_amount_out = safe_mul_div_cast(
+        original.amount_out.into(),
+        (BASIS_POINT_MAX as u128)
+            .checked_sub(tolerance_bps.into())
+            .context("MathOverflow")?,
+        BASIS_POINT_MAX as u128,
+        Rounding::Up,
+    )?;
+
+    Ok(fresh_quote.amount_out >= min_amount_out)
+}
+
+/// Quote exact in after depositing `added`, which is (bin_id, amount_x, amount_y), into the bins. Liquidity share is minted the same way as the program.
+/// Composition fee charged by the program on imbalanced deposits into the active bin is not deducted, so pass the amounts after it.
+#[allow(clippy::too_many_arguments)]
+pub fn quote_with_added_liquidity(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    mut bin_arrays: HashMap<Pubkey, BinArray>,
+    added: &[(i32, u64, u64)],
+    amount_in: u64,
+    swap_for_y: bool,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<SwapExactInQuote> {
+    let mut lb_pair = *lb_pair;
+    let mut bitmap_extension = bitmap_extension.cloned();
+
+    add_liquidity(
+        lb_pair_pubkey,
+        &mut lb_pair,
+        &mut bin_arrays,
+        bitmap_extension.as_mut(),
+        added,
+    )?;
+
+    quote_exact_in(
+        lb_pair_pubkey,
+        &lb_pair,
+        amount_in,
+        swap_for_y,
+        bin_arrays,
+        bitmap_extension.as_ref(),
+        clock,
+        mint_x_account,
+        mint_y_account,
+    )
+}
+
+// Deposit `added` into the bins, and mark the bin arrays which had no liquidity before in the bitmap.
+fn add_liquidity(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &mut LbPair,
+    bin_arrays: &mut HashMap<Pubkey, BinArray>,
+    mut bitmap_extension: Option<&mut BinArrayBitmapExtension>,
+    added: &[(i32, u64, u64)],
+) -> Result<()> {
+    for (bin_id, amount_x, amount_y) in added {
+        let bin_array_index = BinArray::bin_id_to_bin_array_index(*bin_id)?;
+        let bin_array = bin_arrays
+            .get_mut(&derive_bin_array_pda(lb_pair_pubkey, bin_array_index.into()).0)
+            .with_context(|| format!("Bin array {} not found", bin_array_index))?;
+
+        let bin = bin_array.get_bin_mut(*bin_id)?;
+        let price = bin.get_or_store_bin_price(*bin_id, lb_pair.bin_step)?;
+        let in_liquidity = Bin::get_liquidity(*amount_x, *amount_y, price)?;
+        let liquidity_share = bin.get_liquidity_share(in_liquidity, price)?;
+        bin.deposit(*amount_x, *amount_y, liquidity_share)?;
+
+        // Bin array might have had no liquidity before the deposit
+        if lb_pair.is_overflow_default_bin_array_bitmap(bin_array_index) {
+            let bitmap_extension = bitmap_extension
+                .as_mut()
+                .context("Bitmap extension is required for the bin array")?;
+            let (offset, mut bitmap) = bitmap_extension.get_bitmap(bin_array_index)?;
+            let bit = BinArrayBitmapExtension::bin_array_offset_in_bitmap(bin_array_index)?;
+            bitmap[bit / 64] |= 1 << (bit % 64);
+
+            if bin_array_index < 0 {
+                bitmap_extension.negative_bin_array_bitmap[offset] = bitmap;
+            } else {
+                bitmap_extension.positive_bin_array_bitmap[offset] = bitmap;
+            }
+        } else {
+            let offset = LbPair::get_bin_array_offset(bin_array_index);
+            lb_pair.bin_array_bitmap[offset / 64] |= 1 << (offset % 64);
+        }
+    }
+
+    Ok(())
+}
+
+/// Quote exact in with the pair fee parameters replaced by `fee_override`. Useful to analyze fee parameter changes before they are applied on-chain.
+#[allow(clippy::too_many_arguments)]
+pub fn quote_exact_in_with_fee_override(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    amount_in: u64,
+    swap_for_y: bool,
+    bin_arrays: HashMap<Pubkey, BinArray>,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    fee_override: FeeOverride,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<SwapExactInQuote> {
+    let mut lb_pair = *lb_pair;
+
+    if let Some(base_factor) = fee_override.base_factor {
+        lb_pair.parameters.base_factor = base_factor;
+    }
+
+    if let Some(variable_fee_control) = fee_override.variable_fee_control {
+        lb_pair.parameters.variable_fee_control = variable_fee_control;
+    }
+
+    if let Some(protocol_share) = fee_override.protocol_share {
+        lb_pair.parameters.protocol_share = protocol_share;
+    }
+
+    quote_exact_in(
+        lb_pair_pubkey,
+        &lb_pair,
+        amount_in,
+        swap_for_y,
+        bin_arrays,
+        bitmap_extension,
+        clock,
+        mint_x_account,
+        mint_y_account,
+    )
+}
+
+/// Quote exact in with the dynamic fee pegged at the pair maximum, ie: `volatility_accumulator` at `max_volatility_accumulator` for every bin traversed.
+/// This is the minimum amount out the swap could receive under the current liquidity.
+#[allow(clippy::too_many_arguments)]
+pub fn quote_exact_in_worst_case_fee(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    amount_in: u64,
+    swap_for_y: bool,
+    bin_arrays: HashMap<Pubkey, BinArray>,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<SwapExactInQuote> {
+    let mut lb_pair = *lb_pair;
+
+    // Pin the volatility reference at the maximum so update_references and update_volatility_accumulator keep the accumulator there
+    let max_volatility_accumulator = lb_pair.parameters.max_volatility_accumulator;
+    lb_pair.v_parameters.volatility_accumulator = max_volatility_accumulator;
+    lb_pair.v_parameters.volatility_reference = max_volatility_accumulator;
+    lb_pair.v_parameters.last_update_timestamp = clock.unix_timestamp;
+    lb_pair.parameters.reduction_factor = BASIS_POINT_MAX as u16;
+
+    quote_exact_in(
+        lb_pair_pubkey,
+        &lb_pair,
+        amount_in,
+        swap_for_y,
+        bin_arrays,
+        bitmap_extension,
+        clock,
+        mint_x_account,
+        mint_y_account,
+    )
+}
+
+/// Same as [`quote_exact_out`], but verifies with [`quote_exact_in`] that `amount_in` delivers at least `amount_out` after transfer fee.
+/// Transfer fee rounding on both mints can leave the exact out `amount_in` short, in which case it is raised to the smallest amount in meeting `amount_out`,
+/// and the other fields of the quote are taken from the exact in quote of the raised amount in.
+#[allow(clippy::too_many_arguments)]
+pub fn quote_exact_out_verified(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    amount_out: u64,
+    swap_for_y: bool,
+    bin_arrays: HashMap<Pubkey, BinArray>,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<SwapExactOutQuote> {
+    let quote = quote_exact_out(
+        lb_pair_pubkey,
+        lb_pair,
+        amount_out,
+        swap_for_y,
+        bin_arrays.clone(),
+        bitmap_extension,
+        clock,
+        mint_x_account,
+        mint_y_account,
+    )?;
+
+    raise_exact_out_quote(quote, amount_out, |amount_in| {
+        quote_exact_in(
+            lb_pair_pubkey,
+            lb_pair,
+            amount_in,
+            swap_for_y,
+            bin_arrays.clone(),
+            bitmap_extension,
+            clock,
+            mint_x_account,
+            mint_y_account,
+        )
+    })
+}
+
+// Raise the amount in of `quote` to the smallest amount in whose exact in quote meets `amount_out`, and rebuild the other fields from that exact in quote.
+// Returns `quote` as is when its amount in already meets `amount_out`.
+fn raise_exact_out_quote(
+    quote: SwapExactOutQuote,
+    amount_out: u64,
+    exact_in_quote: impl Fn(u64) -> Result<SwapExactInQuote>,
+) -> Result<SwapExactOutQuote> {
+    let is_amount_out_met = |amount_in: u64| -> Result<bool> {
+        Ok(exact_in_quote(amount_in)?.amount_out >= amount_out)
+    };
+
+    if is_amount_out_met(quote.amount_in)? {
+        return Ok(quote);
+    }
+
+    // Exponential search for an upper bound, then binary search for the smallest amount in
+    let mut low = quote.amount_in;
+    let mut step = 1u64;
+    let mut high = loop {
+        let high = low.checked_add(step).context("MathOverflow")?;
+        if is_amount_out_met(high)? {
+            break high;
+        }
+        low = high;
+        step = step.checked_mul(2).context("MathOverflow")?;
+    };
+
+    while high - low > 1 {
+        let mid = low + (high - low) / 2;
+        if is_amount_out_met(mid)? {
+            high = mid;
+        } else {
+            low = mid;
+        }
+    }
+
+    let SwapExactInQuote {
+        fee,
+        amount_into_pool,
+        amount_out_of_pool,
+        ..
+    } = exact_in_quote(high)?;
+
+    Ok(SwapExactOutQuote {
+        amount_in: high,
+        fee,
+        amount_into_pool,
+        amount_out_of_pool,
+    })
+}
+
+/// Quote exact in, then quote exact out for the resulting amount out against the same state.
+/// Returns both quotes, and the drift of the amount in required by the exact out quote from `amount_in`.
+#[allow(clippy::too_many_arguments)]
+pub fn quote_exact_in_with_reverse_check(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    amount_in: u64,
+    swap_for_y: bool,
+    bin_arrays: HashMap<Pubkey, BinArray>,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<(SwapExactInQuote, SwapExactOutQuote, i128)> {
+    let exact_in_quote = quote_exact_in(
+        lb_pair_pubkey,
+        lb_pair,
+        amount_in,
+        swap_for_y,
+        bin_arrays.clone(),
+        bitmap_extension,
+        clock,
+        mint_x_account,
+        mint_y_account,
+    )?;
+
+    let exact_out_quote = quote_exact_out(
+        lb_pair_pubkey,
+        lb_pair,
+        exact_in_quote.amount_out,
+        swap_for_y,
+        bin_arrays,
+        bitmap_extension,
+        clock,
+        mint_x_account,
+        mint_y_account,
+    )?;
+
+    let drift = i128::from(exact_out_quote.amount_in) - i128::from(amount_in);
+
+    Ok((exact_in_quote, exact_out_quote, drift))
+}
+
+/// Same as [`quote_exact_in`], but caps the amount out at the output token reserve balance when provided.
+/// Rounding dust across many bins can make the sum of bin liquidity exceed the reserve balance.
+#[allow(clippy::too_many_arguments)]
+pub fn quote_exact_in_with_reserve_cap(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    amount_in: u64,
+    swap_for_y: bool,
+    bin_arrays: HashMap<Pubkey, BinArray>,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    reserve_x_balance: Option<u64>,
+    reserve_y_balance: Option<u64>,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<SwapExactInQuoteWithReserveCap> {
+    let mut quote = quote_exact_in(
+        lb_pair_pubkey,
+        lb_pair,
+        amount_in,
+        swap_for_y,
+        bin_arrays,
+        bitmap_extension,
+        clock,
+        mint_x_account,
+        mint_y_account,
+    )?;
+
+    let (out_mint_account, reserve_out_balance) = if swap_for_y {
+        (mint_y_account, reserve_y_balance)
+    } else {
+        (mint_x_account, reserve_x_balance)
+    };
+
+    let reserve_shortfall = match reserve_out_balance {
+        Some(reserve_balance) if quote.amount_out_of_pool > reserve_balance => {
+            let bin_amount_out = quote.amount_out_of_pool;
+
+            quote.amount_out_of_pool = reserve_balance;
+            quote.amount_out = calculate_transfer_fee_excluded_amount(
+                out_mint_account,
+                reserve_balance,
+                clock.epoch,
+            )?
+            .amount;
+
+            Some(ReserveShortfall {
+                bin_amount_out,
+                reserve_balance,
+            })
+        }
+        _ => None,
+    };
+
+    Ok(SwapExactInQuoteWithReserveCap {
+        quote,
+        reserve_shortfall,
+    })
+}
+
+/// Quote exact in against `pool`. None when the pool runs out of liquidity.
+fn quote_pool_exact_in(
+    pool: &PoolQuoteAccounts,
+    amount_in: u64,
+    swap_for_y: bool,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<Option<SwapExactInQuote>> {
+    validate_bitmap_extension(pool.lb_pair_pubkey, pool.bitmap_extension)?;
+
+    let quote = quote_exact_in_internal(
+        pool.lb_pair,
+        amount_in,
+        swap_for_y,
+        pool.bitmap_extension,
+        TransferFeeMode::Net,
+        clock,
+        mint_x_account,
+        mint_y_account,
+        |bin_array_index| {
+            pool.bin_arrays
+                .get(&derive_bin_array_pda(pool.lb_pair_pubkey, bin_array_index.into()).0)
+                .cloned()
+        },
+    );
+
+    match quote {
+        Ok(SwapExactInQuoteWithBreakdown { quote, .. }) => Ok(Some(quote)),
+        Err(err) => match err.downcast_ref::<QuoteError>() {
+            Some(QuoteError::InsufficientLiquidity { .. } | QuoteError::EmptyPool) => Ok(None),
+            _ => Err(err),
+        },
+    }
+}
+
+/// Quote exact in against every pool of the same pair, and pick the best one. Returns the index of the pool in `pools`, and its quote.
+/// Pools without the liquidity for `amount_in` are skipped. Pools are ranked by, in order:
+/// 1. Higher `amount_out`
+/// 2. Lower `fee`, which pays less to the LPs and the protocol for the same output
+/// 3. Lower pair pubkey
+///
+/// So the pick doesn't depend on the order of `pools`, and replaying the same accounts picks the same pool.
+pub fn best_quote_exact_in(
+    pools: &[PoolQuoteAccounts],
+    amount_in: u64,
+    swap_for_y: bool,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<(usize, SwapExactInQuote)> {
+    let mut best: Option<(usize, SwapExactInQuote)> = None;
+
+    for (i, pool) in pools.iter().enumerate() {
+        ensure!(
+            pool.lb_pair.token_x_mint == pools[0].lb_pair.token_x_mint
+                && pool.lb_pair.token_y_mint == pools[0].lb_pair.token_y_mint,
+            "Pools are not of the same pair"
+        );
+
+        let Some(quote) = quote_pool_exact_in(
+            pool,
+            amount_in,
+            swap_for_y,
+            clock,
+            mint_x_account,
+            mint_y_account,
+        )?
+        else {
+            continue;
+        };
+
+        let is_better = match &best {
+            Some((best_index, best_quote)) => {
+                (
+                    std::cmp::Reverse(quote.amount_out),
+                    quote.fee,
+                    pool.lb_pair_pubkey,
+                ) < (
+                    std::cmp::Reverse(best_quote.amount_out),
+                    best_quote.fee,
+                    pools[*best_index].lb_pair_pubkey,
+                )
+            }
+            None => true,
+        };
+
+        if is_better {
+            best = Some((i, quote));
+        }
+    }
+
+    best.context("No pool has the liquidity for the amount in")
+}
+
+/// Split `total_amount_in` between 2 pools of the same pair to maximize the total amount out. Returns the amount in to pool A, the amount in
+/// to pool B, and the total amount out. Amount out of each pool is concave in its amount in, so the best split is ternary searched within the
+/// splits both pools have the liquidity for. Rounding of the bin swaps makes the result optimal within a few lamports.
+pub fn optimal_split(
+    pool_a: PoolQuoteAccounts,
+    pool_b: PoolQuoteAccounts,
+    total_amount_in: u64,
+    swap_for_y: bool,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<(u64, u64, u64)> {
+    ensure!(
+        pool_a.lb_pair.token_x_mint == pool_b.lb_pair.token_x_mint
+            && pool_a.lb_pair.token_y_mint == pool_b.lb_pair.token_y_mint,
+        "Pools are not of the same pair"
+    );
+
+    let amount_out = |pool: &PoolQuoteAccounts, amount_in: u64| -> Result<Option<u64>> {
+        Ok(quote_pool_exact_in(
+            pool,
+            amount_in,
+            swap_for_y,
+            clock,
+            mint_x_account,
+            mint_y_account,
+        )?
+        .map(|quote| quote.amount_out))
+    };
+
+    // Pools fill any amount up to their liquidity, so the feasible amounts to pool A are [lower, upper]
+    let mut lower = 0u64;
+    let mut upper = total_amount_in;
+
+    if amount_out(&pool_b, total_amount_in)?.is_none() {
+        // Smallest amount to pool A leaving an amount pool B can fill
+        let (mut infeasible, mut feasible) = (0u64, total_amount_in);
+        while feasible - infeasible > 1 {
+            let mid = infeasible + (feasible - infeasible) / 2;
+            if amount_out(&pool_b, total_amount_in - mid)?.is_some() {
+                feasible = mid;
+            } else {
+                infeasible = mid;
+            }
+        }
+        lower = feasible;
+    }
+
+    if amount_out(&pool_a, total_amount_in)?.is_none() {
+        // Largest amount pool A can fill
+        let (mut feasible, mut infeasible) = (0u64, total_amount_in);
+        while infeasible - feasible > 1 {
+            let mid = feasible + (infeasible - feasible) / 2;
+            if amount_out(&pool_a, mid)?.is_some() {
+                feasible = mid;
+            } else {
+                infeasible = mid;
+            }
+        }
+        upper = feasible;
+    }
+
+    let split_amount_out = |amount_to_a: u64| -> Result<Option<u64>> {
+        let (Some(amount_out_a), Some(amount_out_b)) = (
+            amount_out(&pool_a, amount_to_a)?,
+            amount_out(&pool_b, total_amount_in - amount_to_a)?,
+        ) else {
+            return Ok(None);
+        };
+
+        Ok(Some(
+            amount_out_a
+                .checked_add(amount_out_b)
+                .context("MathOverflow")?,
+        ))
+    };
+
+    ensure!(
+        lower <= upper,
+        "Pools don't have the liquidity for the total amount in"
+    );
+
+    // Rounding makes the total amount out only roughly concave, so the splits sending everything feasible to one pool are kept as candidates
+    let candidates = [lower, upper];
+
+    while upper - lower > 2 {
+        let third = (upper - lower) / 3;
+        let (m1, m2) = (lower + third, upper - third);
+
+        if split_amount_out(m1)? < split_amount_out(m2)? {
+            lower = m1 + 1;
+        } else {
+            upper = m2;
+        }
+    }
+
+    let mut best: Option<(u64, u64)> = None;
+    for amount_to_a in (lower..=upper).chain(candidates) {
+        if let Some(total_amount_out) = split_amount_out(amount_to_a)? {
+            let is_better = match best {
+                Some((_, best_amount_out)) => total_amount_out > best_amount_out,
+                None => true,
+            };
+            if is_better {
+                best = Some((amount_to_a, total_amount_out));
+            }
+        }
+    }
+
+    let (amount_to_a, total_amount_out) = best.context("No split within the liquidity")?;
+
+    Ok((amount_to_a, total_amount_in - amount_to_a, total_amount_out))
+}
+
+/// Compute the smallest `amount_in` (transfer fee included) which yields a non zero `amount_out` at the current active bin.
+/// Small inputs might be fully consumed by the swap fee, the `Rounding::Down` in `get_amount_out`, or the output token transfer fee.
+pub fn min_input_for_nonzero_output(
+    lb_pair: &LbPair,
+    active_bin_array: &BinArray,
+    swap_for_y: bool,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<u64> {
+    let current_timestamp = clock.unix_timestamp as u64;
+    let current_slot = clock.slot;
+    let epoch = clock.epoch;
+
+    validate_swap_activation(lb_pair, current_timestamp, current_slot)?;
+
+    let mut lb_pair = *lb_pair;
+    lb_pair.update_references(current_timestamp as i64)?;
+    lb_pair.update_volatility_accumulator()?;
+
+    let mut active_bin = *active_bin_array.get_bin(lb_pair.active_id)?;
+    let price = active_bin.get_or_store_bin_price(lb_pair.active_id, lb_pair.bin_step)?;
+
+    ensure!(!active_bin.is_empty(!swap_for_y), "Active bin is empty");
+
+    let (in_mint_account, out_mint_account) = if swap_for_y {
+        (mint_x_account, mint_y_account)
+    } else {
+        (mint_y_account, mint_x_account)
+    };
+
+    let amount_out_at = |amount_in: u64| -> Result<u64> {
+        let amount_in =
+            calculate_transfer_fee_excluded_amount(in_mint_account, amount_in, epoch)?.amount;
+        let fee = lb_pair.compute_fee_from_amount(amount_in)?;
+        let amount_in_after_fee = amount_in.checked_sub(fee).context("MathOverflow")?;
+        let amount_out = std::cmp::min(
+            Bin::get_amount_out(amount_in_after_fee, price, swap_for_y)?,
+            active_bin.get_max_amount_out(swap_for_y),
+        );
+        Ok(calculate_transfer_fee_excluded_amount(out_mint_account, amount_out, epoch)?.amount)
+    };
+
+    // Upper bound: the smallest amount out which survives the transfer fee, converted back to amount in with rounding up.
+    let min_amount_out = calculate_transfer_fee_included_amount(out_mint_account, 1, epoch)?.amount;
+    ensure!(
+        min_amount_out <= active_bin.get_max_amount_out(swap_for_y),
+        "Active bin liquidity too small"
+    );
+
+    let amount_in = Bin::get_amount_in(min_amount_out, price, swap_for_y)?;
+    let fee = lb_pair.compute_fee(amount_in)?;
+    let amount_in = amount_in.checked_add(fee).context("MathOverflow")?;
+    let mut upper =
+        calculate_transfer_fee_included_amount(in_mint_account, amount_in, epoch)?.amount;
+
+    ensure!(
+        amount_out_at(upper)? > 0,
+        "Unable to produce non zero amount out"
+    );
+
+    // amount_out_at is monotonic, binary search for the smallest amount in
+    let mut lower = 0;
+    while upper - lower > 1 {
+        let mid = lower + (upper - lower) / 2;
+        if amount_out_at(mid)? > 0 {
+            upper = mid;
+        } else {
+            lower = mid;
+        }
+    }
+
+    Ok(upper)
+}
+
+/// Compute the smallest `amount_y` when swap for Y, else `amount_x`, bin `bin_id` must hold to fully serve a swap of `amount_in` (swap fee included,
+/// transfer fee excluded) without crossing to the next bin. The swap fee is charged at the current fee rate of `lb_pair`.
+pub fn liquidity_to_absorb_without_crossing(
+    lb_pair: &LbPair,
+    bin_id: i32,
+    amount_in: u64,
+    swap_for_y: bool,
+) -> Result<u64> {
+    let price = get_price_from_id(bin_id, lb_pair.bin_step)?;
+
+    let fee = lb_pair.compute_fee_from_amount(amount_in)?;
+    let amount_in_after_fee = amount_in.checked_sub(fee).context("MathOverflow")?;
+
+    let mut bin: Bin = bytemuck::Zeroable::zeroed();
+    let mut liquidity = Bin::get_amount_out(amount_in_after_fee, price, swap_for_y)?;
+
+    // The bin is drained when amount in exceeds its max amount in, which rounds up. Holding the amount out might fall short by a few units.
+    loop {
+        if swap_for_y {
+            bin.amount_y = liquidity;
+        } else {
+            bin.amount_x = liquidity;
+        }
+
+        let max_amount_in = bin.get_max_amount_in(price, swap_for_y)?;
+        let max_fee = lb_pair.compute_fee(max_amount_in)?;
+
+        if amount_in <= max_amount_in.checked_add(max_fee).context("MathOverflow")? {
+            return Ok(liquidity);
+        }
+
+        liquidity = liquidity.checked_add(1).context("MathOverflow")?;
+    }
+}
+
+/// Amount in, including swap fee and transfer fee, which drains every remaining bin of the active bin array in the swap direction.
+/// Swaps of at most this amount only need the active bin array.
+pub fn amount_to_next_bin_array(
+    lb_pair: &LbPair,
+    active_bin_array: &BinArray,
+    swap_for_y: bool,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<u64> {
+    let current_timestamp = clock.unix_timestamp as u64;
+    let current_slot = clock.slot;
+    let epoch = clock.epoch;
+
+    validate_swap_activation(lb_pair, current_timestamp, current_slot)?;
+
+    ensure!(
+        active_bin_array.is_bin_id_within_range(lb_pair.active_id)?,
+        "Active bin is not in the bin array"
+    );
+
+    let mut lb_pair = *lb_pair;
+    lb_pair.update_references(current_timestamp as i64)?;
+
+    let mut amount_in = 0u64;
+
+    loop {
+        if lb_pair.parameters.variable_fee_control > 0 {
+            lb_pair.update_volatility_accumulator()?;
+        }
+
+        let mut active_bin = *active_bin_array.get_bin(lb_pair.active_id)?;
+        if !active_bin.is_empty(!swap_for_y) {
+            let price = active_bin.get_or_store_bin_price(lb_pair.active_id, lb_pair.bin_step)?;
+            let max_amount_in = active_bin.get_max_amount_in(price, swap_for_y)?;
+            let max_fee = lb_pair.compute_fee(max_amount_in)?;
+
+            amount_in = amount_in
+                .checked_add(max_amount_in)
+                .context("MathOverflow")?
+                .checked_add(max_fee)
+                .context("MathOverflow")?;
+        }
+
+        let next_bin_id = if swap_for_y {
+            lb_pair.active_id - 1
+        } else {
+            lb_pair.active_id + 1
+        };
+
+        if !active_bin_array.is_bin_id_within_range(next_bin_id)? {
+            break;
+        }
+
+        lb_pair.advance_active_bin(swap_for_y)?;
+    }
+
+    let in_mint_account = if swap_for_y {
+        mint_x_account
+    } else {
+        mint_y_account
+    };
+
+    Ok(calculate_transfer_fee_included_amount(in_mint_account, amount_in, epoch)?.amount)
+}
+
+/// Smallest amount in, including swap fee and transfer fee, which moves `active_id` by `n_bins` in the swap direction.
+/// Drains the `n_bins` bins starting from the active bin, plus 1 to step into the next bin. If that bin is empty, the swap keeps
+/// moving to the next bin with liquidity like the program does.
+#[allow(clippy::too_many_arguments)]
+pub fn amount_to_move_n_bins(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    bin_arrays: &HashMap<Pubkey, BinArray>,
+    n_bins: u32,
+    swap_for_y: bool,
+    clock: &Clock,
+    mint_x_account: &Account,
+    mint_y_account: &Account,
+) -> Result<u64> {
+    let current_timestamp = clock.unix_timestamp as u64;
+    let current_slot = clock.slot;
+    let epoch = clock.epoch;
+
+    validate_swap_activation(lb_pair, current_timestamp, current_slot)?;
+
+    if n_bins == 0 {
+        return Ok(0);
+    }
+
+    let mut lb_pair = *lb_pair;
+    lb_pair.update_references(current_timestamp as i64)?;
+
+    let mut amount_in = 0u64;
+    let mut amount_out = 0u64;
+
+    for i in 0..n_bins {
+        if i > 0 {
+            lb_pair.advance_active_bin(swap_for_y)?;
+        }
+
+        if lb_pair.parameters.variable_fee_control > 0 {
+            lb_pair.update_volatility_accumulator()?;
+        }
+
+        let bin_array_index = BinArray::bin_id_to_bin_array_index(lb_pair.active_id)?;
+        let Some(bin_array) =
+            bin_arrays.get(&derive_bin_array_pda(lb_pair_pubkey, bin_array_index.into()).0)
+        else {
+            return Err(QuoteError::MissingBinArray {
+                bin_array_index,
+                consumed_in: amount_in,
+                produced_out: amount_out,
+                last_bin_id: lb_pair.active_id,
+            }
+            .into());
+        };
+
+        let mut active_bin = *bin_array.get_bin(lb_pair.active_id)?;
+        if !active_bin.is_empty(!swap_for_y) {
+            let price = active_bin.get_or_store_bin_price(lb_pair.active_id, lb_pair.bin_step)?;
+            let max_amount_in = active_bin.get_max_amount_in(price, swap_for_y)?;
+            let max_fee = lb_pair.compute_fee(max_amount_in)?;
+
+            amount_in = amount_in
+                .checked_add(max_amount_in)
+                .context("MathOverflow")?
+                .checked_add(max_fee)
+                .context("MathOverflow")?;
+            amount_out = amount_out
+                .checked_add(active_bin.get_max_amount_out(swap_for_y))
+                .context("MathOverflow")?;
+        }
+    }
+
+    // Amount left after draining the last bin advances the active bin
+    let amount_in = amount_in.checked_add(1).context("MathOverflow")?;
+
+    let in_mint_account = if swap_for_y {
+        mint_x_account
+    } else {
+        mint_y_account
+    };
+
+    Ok(calculate_transfer_fee_included_amount(in_mint_account, amount_in, epoch)?.amount)
+}
+
+///为一笔即将发生的交易（Swap）找到接下来有流动性的流动性仓位数组（BinArray）的地址（Pubkey）
+/// 由于 bitmap 的大小有限，Meteora 设计了一套扩展机制：
+/// 内部 bitmap: LbPair 账户自身带有一个大小固定的 bitmap。
+/// 扩展 bitmap (bitmap_extension): 如果流动性分布范围很广，超出了内部 bitmap 能表示的范围，就可以启用一个或多个 BinArrayBitmapExtension 账户来存储额外的 bitmap。
+/// 这段代码的作用就是智能地在这两种 bitmap 中进行搜索，找到我们需要的 BinArray 地址。
+pub fn get_bin_array_pubkeys_for_swap(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    swap_for_y: bool,
+    take_count: u8,
+) -> Result<Vec<Pubkey>> {
+    let (bin_array_pubkeys, _) = get_bin_array_pubkeys_for_swap_with_limit(
+        lb_pair_pubkey,
+        lb_pair,
+        bitmap_extension,
+        swap_for_y,
+        take_count,
+        u32::MAX,
+    )?;
+
+    Ok(bin_array_pubkeys)
+}
+
+/// Same as [`get_bin_array_pubkeys_for_swap`], but gives up after `max_iterations` bitmap searches.
+/// Returns the bin arrays found so far, and whether the search was truncated before finding `take_count` bin arrays.
+pub fn get_bin_array_pubkeys_for_swap_with_limit(
+    lb_pair_pubkey: Pubkey,
+    lb_pair: &LbPair,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    swap_for_y: bool,
+    take_count: u8,
+    max_iterations: u32,
+) -> Result<(Vec<Pubkey>, bool)> {
+    validate_bitmap_extension(lb_pair_pubkey, bitmap_extension)?;
+
+    let (bin_array_idx, is_truncated) = get_bin_array_indexes_for_swap_with_limit(
+        lb_pair,
+        bitmap_extension,
+        swap_for_y,
+        take_count,
+        max_iterations,
+    )?;
+
+    let bin_array_pubkeys = bin_array_idx
+        .into_iter()
+        .map(|idx| derive_bin_array_pda(lb_pair_pubkey, idx.into()).0)
+        .collect();
+
+    Ok((bin_array_pubkeys, is_truncated))
+}
+
+/// Same as [`get_bin_array_pubkeys_for_swap`], but returns the bin array indexes instead of the pubkeys.
+pub fn get_bin_array_indexes_for_swap(
+    lb_pair: &LbPair,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    swap_for_y: bool,
+    take_count: u8,
+) -> Result<Vec<i32>> {
+    let (bin_array_idx, _) = get_bin_array_indexes_for_swap_with_limit(
+        lb_pair,
+        bitmap_extension,
+        swap_for_y,
+        take_count,
+        u32::MAX,
+    )?;
+
+    Ok(bin_array_idx)
+}
+
+/// Same as [`get_bin_array_pubkeys_for_swap_with_limit`], but returns the bin array indexes instead of the pubkeys.
+pub fn get_bin_array_indexes_for_swap_with_limit(
+    lb_pair: &LbPair,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    swap_for_y: bool,
+    take_count: u8,
+    max_iterations: u32,
+) -> Result<(Vec<i32>, bool)> {
+    //根据当前活跃的 Bin ID (lb_pair.active_id) 计算出它所在的 BinArray 的索引。搜索就从这个索引开始
+    let mut start_bin_array_idx = BinArray::bin_id_to_bin_array_index(lb_pair.active_id)?;
+
+    //需要注意的是 这里的index并不是bitmap上这个bin_array的位置,而是BinArray 的索引值
+    //用来存放找到的、有流动性的 BinArray 的索引
+    let mut bin_array_idx = vec![];
+    //increment: 搜索方向。如果是用 X 换 Y (swap_for_y is true)，价格是下降的，所以索引要递减 (-1)；反之则递增 (+1)。
+    let increment = if swap_for_y { -1 } else { 1 };
+
+    //循环的目的是找到 take_count 个符合条件的 BinArray 索引
+    let mut iterations = 0u32;
+    let mut is_truncated = false;
+
+    loop {
+        if bin_array_idx.len() == take_count as usize {
+            break;
+        }
+
+        if iterations == max_iterations {
+            is_truncated = true;
+            break;
+        }
+        iterations += 1;
+
+        //它检查当前的 start_bin_array_idx 是否已经超出了 LbPair 内部 bitmap 所能管理的范围。
+        if lb_pair.is_overflow_default_bin_array_bitmap(start_bin_array_idx) {
+            //超出内部范围，需要在扩展 bitmap 中搜索
+            //确认 bitmap_extension 账户存在。如果不存在但又需要搜索它，说明没有更多流动性了，直接退出循环
+            let Some(bitmap_extension) = bitmap_extension else {
+                break;
+            };
+
+            //调用扩展 bitmap 的搜索方法，从 start_bin_array_idx 开始按指定方向 (swap_for_y) 寻找下一个为 1 的位。
+            let Ok((next_bin_array_idx, has_liquidity)) = bitmap_extension
+                .next_bin_array_index_with_liquidity(swap_for_y, start_bin_array_idx)
+            else {
+                // Out of search range. No liquidity.
+                //如果没搜索到 说明没有流动性了
+                break;
+            };
+
+            // 如果找到了 (has_liquidity 为 true)，就把返回的 next_bin_array_idx 存入结果列表，并更新下一次搜索的起始点 start_bin_array_idx。
+            if has_liquidity {
+                bin_array_idx.push(next_bin_array_idx);
+                start_bin_array_idx = next_bin_array_idx + increment; //这里实在bin_array_index上进行加减
+            } else {
+                //如果没找到，说明在这个 bitmap_extension 中搜索完了。返回的 next_bin_array_idx 会是这个 bitmap 的边界，
+                //代码将 start_bin_array_idx 更新为这个边界值，以便在下一次循环中可以切换回内部 bitmap 或者另一个 bitmap_extension 继续搜索
+                start_bin_array_idx = next_bin_array_idx;
+            }
+        //在内部 bitmap 范围内搜索
+        } else {
+            //调用 LbPair 自身的搜索方法，在其内部 bitmap 中寻找
+            //逻辑和情况一非常相似：找到就记录并更新下一次的起点；没找到就将 start_bin_array_idx 更新到内部 bitmap 的边界，以便下次循环可以切换到扩展 bitmap。
+            let Ok((next_bin_array_idx, has_liquidity)) = lb_pair
+                .next_bin_array_index_with_liquidity_internal(swap_for_y, start_bin_array_idx)
+            else {
+                break;
+            };
+
+            if has_liquidity {
+                bin_array_idx.push(next_bin_array_idx);
+                start_bin_array_idx = next_bin_array_idx + increment;
+            //场景
+            // 我们要向右搜索（swap_for_y = false），所以 increment = 1。
+            // 我们需要找到 3 个有流动性的 BinArray（take_count = 3）。
+            // 假设当前活跃的 bin_array_index 是 10。
+            // 循环过程
+            // 第一次循环:
+            // 起始点: start_bin_array_idx = 10。
+            // 搜索: next_bin_array_index_with_liquidity_internal 从索引 10 开始向右搜索。
+            // 结果: 假设它找到了第一个有流动性的 BinArray 在索引 15。所以 next_bin_array_idx = 15。
+            // 记录: 我们将 15 添加到结果列表 bin_array_idx 中。bin_array_idx 现在是 [15]。
+            // 更新起始点:
+            // 如果我们不更新 start_bin_array_idx，下一次循环还会从 10 开始，又会找到 15，陷入死循环。
+            // 如果我们只设置为 start_bin_array_idx = next_bin_array_idx (也就是 15)，下一次循环从 15 开始，还是会找到 15，同样陷入死循环。
+            // 正确的做法: 我们需要从刚刚找到的位置的下一个位置开始搜索。所以我们执行 start_bin_array_idx = 15 + 1 = 16。
+            // 第二次循环:
+            // 起始点: start_bin_array_idx = 16。
+            // 搜索: 从索引 16 开始向右搜索。
+            // 结果: 假设找到了下一个有流动性的 BinArray 在索引 18。next_bin_array_idx = 18。
+            // 记录: bin_array_idx 现在是 [15, 18]。
+            // 更新起始点: start_bin_array_idx = 18 + 1 = 19。
+            // 第三次循环:
+            // 起始点: start_bin_array_idx = 19。
+            // 搜索: 从索引 19 开始向右搜索。
+            // 结果: 假设找到了下一个在索引 25。next_bin_array_idx = 25。
+            // 记录: bin_array_idx 现在是 [15, 18, 25]。
+            // 更新起始点: start_bin_array_idx = 25 + 1 = 26。
+            } else {
+                // Switch to external bitmap
+                start_bin_array_idx = next_bin_array_idx;
+            }
+        }
+    }
+
+    //循环结束后，bin_array_idx 里就存放了所有找到的 BinArray 的索引
+    Ok((bin_array_idx, is_truncated))
+}
+
+/// Upper bound of the number of bins a swap can cross, from the active bin, in every bin array with liquidity per the bitmaps.
+/// The bitmaps don't track the liquidity of each bin, so every bin of a liquid bin array is counted.
+pub fn theoretical_max_bins_reachable(
+    lb_pair: &LbPair,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+    swap_for_y: bool,
+) -> Result<u32> {
+    let active_bin_array_index = BinArray::bin_id_to_bin_array_index(lb_pair.active_id)?;
+    let mut search_lb_pair = *lb_pair;
+    let mut bins_reachable = 0u32;
+
+    loop {
+        let bin_array_indexes =
+            get_bin_array_indexes_for_swap(&search_lb_pair, bitmap_extension, swap_for_y, u8::MAX)?;
+
+        for bin_array_index in bin_array_indexes.iter() {
+            let (lower_bin_id, upper_bin_id) =
+                BinArray::get_bin_array_lower_upper_bin_id(*bin_array_index)?;
+
+            let bin_count = if *bin_array_index == active_bin_array_index {
+                if swap_for_y {
+                    lb_pair.active_id - lower_bin_id + 1
+                } else {
+                    upper_bin_id - lb_pair.active_id + 1
+                }
+            } else {
+                MAX_BIN_PER_ARRAY as i32
+            };
+
+            bins_reachable = bins_reachable
+                .checked_add(bin_count as u32)
+                .context("overflow")?;
+        }
+
+        let Some(last_bin_array_index) = bin_array_indexes.last() else {
+            break;
+        };
+
+        if bin_array_indexes.len() < u8::MAX as usize {
+            break;
+        }
+
+        // Continue the search after the last found bin array
+        let (lower_bin_id, upper_bin_id) =
+            BinArray::get_bin_array_lower_upper_bin_id(*last_bin_array_index)?;
+        let next_bin_id = if swap_for_y {
+            lower_bin_id - 1
+        } else {
+            upper_bin_id + 1
+        };
+
+        if !(MIN_BIN_ID..=MAX_BIN_ID).contains(&next_bin_id) {
+            break;
+        }
+        search_lb_pair.active_id = next_bin_id;
+    }
+
+    Ok(bins_reachable)
+}
+
+/// Lowest and highest bin of the bin arrays with liquidity per the bitmaps, and the number of bins in between, inclusive.
+/// None if the pair has no liquidity. The bitmaps don't track the liquidity of each bin, so the span covers whole bin arrays.
+pub fn liquidity_span(
+    lb_pair: &LbPair,
+    bitmap_extension: Option<&BinArrayBitmapExtension>,
+) -> Result<Option<(i32, i32, u32)>> {
+    let (min_bin_array_index, max_bin_array_index) = if bitmap_extension.is_some() {
+        BinArrayBitmapExtension::bitmap_range()
+    } else {
+        LbPair::bitmap_range()
+    };
+
+    // Search toward the pair from both ends of the bitmaps
+    let mut search_lb_pair = *lb_pair;
+
+    search_lb_pair.active_id = BinArray::get_bin_array_lower_upper_bin_id(min_bin_array_index)?.0;
+    let Some(lowest_bin_array_index) =
+        get_bin_array_indexes_for_swap(&search_lb_pair, bitmap_extension, false, 1)?.pop()
+    else {
+        return Ok(None);
+    };
+
+    search_lb_pair.active_id = BinArray::get_bin_array_lower_upper_bin_id(max_bin_array_index)?.1;
+    let highest_bin_array_index =
+        get_bin_array_indexes_for_swap(&search_lb_pair, bitmap_extension, true, 1)?
+            .pop()
+            .context("Highest bin array with liquidity not found")?;
+
+    let lowest_bin_id = std::cmp::max(
+        BinArray::get_bin_array_lower_upper_bin_id(lowest_bin_array_index)?.0,
+        MIN_BIN_ID,
+    );
+    let highest_bin_id = std::cmp::min(
+        BinArray::get_bin_array_lower_upper_bin_id(highest_bin_array_index)?.1,
+        MAX_BIN_ID,
+    );
+    let span = (highest_bin_id - lowest_bin_id + 1) as u32;
+
+    Ok(Some((lowest_bin_id, highest_bin_id, span)))
+}
+
+/// Number of `get_multiple_accounts` calls to fetch the accounts of a quote, which are the pair, both mints, the bitmap extension if needed, and
+/// `bin_arrays_needed` bin arrays, batched by `max_accounts_per_get_multiple` accounts per call. A batch limit of 0 is treated as 1.
+pub fn estimate_rpc_calls(
+    needs_bitmap_extension: bool,
+    bin_arrays_needed: u8,
+    max_accounts_per_get_multiple: usize,
+) -> usize {
+    let account_count = 3 + usize::from(needs_bitmap_extension) + usize::from(bin_arrays_needed);
+    account_count.div_ceil(max_accounts_per_get_multiple.max(1))
+}
+
+/// Account data bytes fetched to quote a swap through `bin_arrays_traversed` bin arrays, which includes the pair, the bin arrays,
+/// the bitmap extension and both mints. Token 2022 mints are sized with the transfer fee extension, mints with more extensions are larger.
+/// Excludes the RPC response encoding overhead.
+pub fn estimate_account_fetch_bytes(
+    bin_arrays_traversed: u8,
+    has_bitmap_extension: bool,
+    token_2022: bool,
+) -> usize {
+    use anchor_spl::token::spl_token;
+    use anchor_spl::token_2022::spl_token_2022;
+    use anchor_spl::token_2022::spl_token_2022::extension::ExtensionType;
+    use solana_sdk::program_pack::Pack;
+
+    let lb_pair_len = LB_PAIR_ACCOUNT_DISCM.len() + std::mem::size_of::<LbPair>();
+    let bin_array_len = BIN_ARRAY_ACCOUNT_DISCM.len() + std::mem::size_of::<BinArray>();
+    let bitmap_extension_len = BIN_ARRAY_BITMAP_EXTENSION_ACCOUNT_DISCM.len()
+        + std::mem::size_of::<BinArrayBitmapExtension>();
+
+    let mint_len = if token_2022 {
+        ExtensionType::try_calculate_account_len::<spl_token_2022::state::Mint>(&[
+            ExtensionType::TransferFeeConfig,
+        ])
+        .expect("Mint with a fixed extension list has a valid length")
+    } else {
+        spl_token::state::Mint::LEN
+    };
+
+    // At most 255 bin arrays, far from overflowing usize
+    let mut total_len =
+        lb_pair_len + bin_array_len * usize::from(bin_arrays_traversed) + mint_len * 2;
+
+    if has_bitmap_extension {
+        total_len += bitmap_extension_len;
+    }
+
+    total_len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_client::solana_sdk::clock::Clock;
+    use anchor_client::{
+        solana_client::nonblocking::rpc_client::RpcClient, solana_sdk::pubkey::Pubkey, Cluster,
+    };
+    use std::str::FromStr;
+
+    /// Get on chain clock
+    async fn get_clock(rpc_client: RpcClient) -> Result<Clock> {
+        let clock_account = rpc_client
+            .get_account(&anchor_client::solana_sdk::sysvar::clock::ID)
+            .await?;
+
+        let clock_state: Clock = bincode::deserialize(clock_account.data.as_ref())?;
+
+        Ok(clock_state)
+    }
+
+    #[tokio::test]
+    async fn test_swap_quote_exact_out() {
+        // RPC client. No gPA is required.
+        let rpc_client = RpcClient::new(Cluster::Mainnet.url().to_string());
+
+        let sol_usdc = Pubkey::from_str("HTvjzsfX3yU6BUodCjZ5vZkUrAxMDTrBs3CJaq43ashR").unwrap();
+
+        let lb_pair_account = rpc_client.get_account(&sol_usdc).await.unwrap();
+
+        let lb_pair = LbPairAccount::deserialize(&lb_pair_account.data).unwrap().0;
+
+        let mut mint_accounts = rpc_client
+            .get_multiple_accounts(&[lb_pair.token_x_mint, lb_pair.token_y_mint])
+            .await
+            .unwrap();
+
+        let mint_x_account = mint_accounts[0].take().unwrap();
+        let mint_y_account = mint_accounts[1].take().unwrap();
+
+        // 3 bin arrays to left, and right is enough to cover most of the swap, and stay under 1.4m CU constraint.
+        // Get 3 bin arrays to the left from the active bin
+        let left_bin_array_pubkeys =
+            get_bin_array_pubkeys_for_swap(sol_usdc, &lb_pair, None, true, 3).unwrap();
+
+        // Get 3 bin arrays to the right the from active bin
+        let right_bin_array_pubkeys =
+            get_bin_array_pubkeys_for_swap(sol_usdc, &lb_pair, None, false, 3).unwrap();
+
+        // Fetch bin arrays
+        let bin_array_pubkeys = left_bin_array_pubkeys
+            .into_iter()
+            .chain(right_bin_array_pubkeys.into_iter())
+            .collect::<Vec<Pubkey>>();
+
+        let accounts = rpc_client
+            .get_multiple_accounts(&bin_array_pubkeys)
+            .await
+            .unwrap();
+
+        let bin_arrays = accounts
+            .into_iter()
+            .zip(bin_array_pubkeys.into_iter())
+            .map(|(account, key)| {
+                (
+                    key,
+                    BinArrayAccount::deserialize(&account.unwrap().data)
+                        .unwrap()
+                        .0,
+                )
+            })
+            .collect::<HashMap<_, _>>();
+
+        let usdc_token_multiplier = 1_000_000.0;
+        let sol_token_multiplier = 1_000_000_000.0;
+
+        let out_sol_amount = 1_000_000_000;
+        let clock = get_clock(rpc_client).await.unwrap();
+
+        let quote_result = quote_exact_out(
+            sol_usdc,
+            &lb_pair,
+            out_sol_amount,
+            false,
+            bin_arrays.clone(),
+            None,
+            &clock,
+            &mint_x_account,
+            &mint_y_account,
+        )
+        .unwrap();
+
+        let in_amount = quote_result.amount_in + quote_result.fee;
+
+        println!(
+            "{} USDC -> exact 1 SOL",
+            in_amount as f64 / usdc_token_multiplier
+        );
+
+        let quote_result = quote_exact_in(
+            sol_usdc,
+            &lb_pair,
+            in_amount,
+            false,
+            bin_arrays.clone(),
+            None,
+            &clock,
+            &mint_x_account,
+            &mint_y_account,
+        )
+        .unwrap();
+
+        println!(
+            "{} USDC -> {} SOL",
+            in_amount as f64 / usdc_token_multiplier,
+            quote_result.amount_out as f64 / sol_token_multiplier
+        );
+
+        let out_usdc_amount = 200_000_000;
+
+        let quote_result = quote_exact_out(
+            sol_usdc,
+            &lb_pair,
+            out_usdc_amount,
+            true,
+            bin_arrays.clone(),
+            None,
+            &clock,
+            &mint_x_account,
+            &mint_y_account,
+        )
+        .unwrap();
+
+        let in_amount = quote_result.amount_in + quote_result.fee;
+
+        println!(
+            "{} SOL -> exact 200 USDC",
+            in_amount as f64 / sol_token_multiplier
+        );
+
+        let quote_result = quote_exact_in(
+            sol_usdc,
+            &lb_pair,
+            in_amount,
+            true,
+            bin_arrays,
+            None,
+            &clock,
+            &mint_x_account,
+            &mint_y_account,
+        )
+        .unwrap();
+
+        println!(
+            "{} SOL -> {} USDC",
+            in_amount as f64 / sol_token_multiplier,
+            quote_result.amount_out as f64 / usdc_token_multiplier
+        );
+    }
+
+    #[tokio::test]
+    async fn test_swap_quote_exact_in() {
+        // RPC client. No gPA is required.
+        let rpc_client = RpcClient::new(Cluster::Mainnet.url().to_string());
+
+        let sol_usdc = Pubkey::from_str("HTvjzsfX3yU6BUodCjZ5vZkUrAxMDTrBs3CJaq43ashR").unwrap();
+
+        let lb_pair_account = rpc_client.get_account(&sol_usdc).await.unwrap();
+
+        let lb_pair = LbPairAccount::deserialize(&lb_pair_account.data).unwrap().0;
+
+        let mut mint_accounts = rpc_client
+            .get_multiple_accounts(&[lb_pair.token_x_mint, lb_pair.token_y_mint])
+            .await
+            .unwrap();
+
+        let mint_x_account = mint_accounts[0].take().unwrap();
+        let mint_y_account = mint_accounts[1].take().unwrap();
+
+        // 3 bin arrays to left, and right is enough to cover most of the swap, and stay under 1.4m CU constraint.
+        // Get 3 bin arrays to the left from the active bin
+        let left_bin_array_pubkeys =
+            get_bin_array_pubkeys_for_swap(sol_usdc, &lb_pair, None, true, 3).unwrap();
+
+        // Get 3 bin arrays to the right the from active bin
+        let right_bin_array_pubkeys =
+            get_bin_array_pubkeys_for_swap(sol_usdc, &lb_pair, None, false, 3).unwrap();
+
+        // Fetch bin arrays
+        let bin_array_pubkeys = left_bin_array_pubkeys
+            .into_iter()
+            .chain(right_bin_array_pubkeys.into_iter())
+            .collect::<Vec<Pubkey>>();
+
+        let accounts = rpc_client
+            .get_multiple_accounts(&bin_array_pubkeys)
+            .await
+            .unwrap();
+
+        let bin_arrays = accounts
+            .into_iter()
+            .zip(bin_array_pubkeys.into_iter())
+            .map(|(account, key)| {
+                (
+                    key,
+                    BinArrayAccount::deserialize(&account.unwrap().data)
+                        .unwrap()
+                        .0,
+                )
+            })
+            .collect::<HashMap<_, _>>();
+
+        // 1 SOL -> USDC
+        let in_sol_amount = 1_000_000_000;
+
+        let clock = get_clock(rpc_client).await.unwrap();
+
+        let quote_result = quote_exact_in(
+            sol_usdc,
+            &lb_pair,
+            in_sol_amount,
+            true,
+            bin_arrays.clone(),
+            None,
+            &clock,
+            &mint_x_account,
+            &mint_y_account,
+        )
+        .unwrap();
+
+        println!(
+            "1 SOL -> {:?} USDC",
+            quote_result.amount_out as f64 / 1_000_000.0
+        );
+
+        // 100 USDC -> SOL
+        let in_usdc_amount = 100_000_000;
+
+        let quote_result = quote_exact_in(
+            sol_usdc,
+            &lb_pair,
+            in_usdc_amount,
+            false,
+            bin_arrays.clone(),
+            None,
+            &clock,
+            &mint_x_account,
+            &mint_y_account,
+        )
+        .unwrap();
+
+        println!(
+            "100 USDC -> {:?} SOL",
+            quote_result.amount_out as f64 / 1_000_000_000.0
+        );
+    }
+
+    fn new_test_lb_pair(bin_step: u16, base_factor: u16) -> LbPair {
+        let mut lb_pair: LbPair = bytemuck::Zeroable::zeroed();
+        lb_pair.bin_step = bin_step;
+        lb_pair.parameters.base_factor = base_factor;
+        lb_pair
+    }
+
+    fn new_test_bin_array(index: i64) -> BinArray {
+        let mut bin_array: BinArray = bytemuck::Zeroable::zeroed();
+        bin_array.index = index;
+        bin_array
+    }
+
+    fn new_test_mint_account() -> Account {
+        Account {
+            owner: anchor_spl::token::spl_token::ID,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_min_input_for_nonzero_output() {
+        // 10 bps bin step, 0.1% base fee
+        let mut lb_pair = new_test_lb_pair(10, 10_000);
+        lb_pair.active_id = 0;
+
+        let mut bin_array = new_test_bin_array(0);
+        let active_bin = bin_array.get_bin_mut(0).unwrap();
+        active_bin.amount_x = 1_000_000;
+        active_bin.amount_y = 1_000_000;
+
+        let mint_account = new_test_mint_account();
+        let clock = Clock::default();
+
+        for swap_for_y in [true, false] {
+            let min_amount_in = min_input_for_nonzero_output(
+                &lb_pair,
+                &bin_array,
+                swap_for_y,
+                &clock,
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap();
+
+            // 1 is fully consumed by the fee at price 1.0
+            assert_eq!(min_amount_in, 2);
+        }
+
+        // Active bin without the token to swap out
+        bin_array.get_bin_mut(0).unwrap().amount_y = 0;
+        assert!(min_input_for_nonzero_output(
+            &lb_pair,
+            &bin_array,
+            true,
+            &clock,
+            &mint_account,
+            &mint_account,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_liquidity_to_absorb_without_crossing() {
+        // 10 bps bin step, 0.1% base fee
+        let lb_pair = new_test_lb_pair(10, 10_000);
+
+        for (bin_id, amount_in, swap_for_y) in [
+            (0, 1_000, true),
+            (0, 1_000, false),
+            (25, 777, true),
+            (-25, 123_456, false),
+            (400, 1, true),
+        ] {
+            let liquidity =
+                liquidity_to_absorb_without_crossing(&lb_pair, bin_id, amount_in, swap_for_y)
+                    .unwrap();
+            let price = get_price_from_id(bin_id, lb_pair.bin_step).unwrap();
+
+            let amount_in_served = |liquidity: u64| {
+                let mut bin: Bin = bytemuck::Zeroable::zeroed();
+                if swap_for_y {
+                    bin.amount_y = liquidity;
+                } else {
+                    bin.amount_x = liquidity;
+                }
+                let SwapResult {
+                    amount_in_with_fees,
+                    ..
+                } = bin
+                    .swap(amount_in, price, swap_for_y, &lb_pair, None)
+                    .unwrap();
+                amount_in_with_fees
+            };
+
+            // One unit less drains the bin before the whole amount in is swapped
+            assert_eq!(amount_in_served(liquidity), amount_in);
+            assert!(liquidity == 0 || amount_in_served(liquidity - 1) < amount_in);
+        }
+    }
+
+    /// Pool with active bin 0 holding both tokens, token Y in bins [-3, -1] and token X in bins [1, 3].
+    fn new_test_pool() -> (Pubkey, LbPair, HashMap<Pubkey, BinArray>) {
+        let lb_pair_pubkey = Pubkey::new_unique();
+        let mut lb_pair = new_test_lb_pair(10, 10_000);
+        lb_pair.active_id = 0;
+
+        let mut bin_arrays = HashMap::new();
+        for bin_array_index in [-1, 0] {
+            let offset = LbPair::get_bin_array_offset(bin_array_index);
+            lb_pair.bin_array_bitmap[offset / 64] |= 1 << (offset % 64);
+
+            let mut bin_array = new_test_bin_array(bin_array_index.into());
+            bin_array.lb_pair = lb_pair_pubkey;
+            for bin_id in -3..=3 {
+                if !bin_array.is_bin_id_within_range(bin_id).unwrap() {
+                    continue;
+                }
+                let bin = bin_array.get_bin_mut(bin_id).unwrap();
+                if bin_id <= 0 {
+                    bin.amount_y = 1_000;
+                }
+                if bin_id >= 0 {
+                    bin.amount_x = 1_000;
+                }
+            }
+
+            bin_arrays.insert(
+                derive_bin_array_pda(lb_pair_pubkey, bin_array_index.into()).0,
+                bin_array,
+            );
+        }
+
+        (lb_pair_pubkey, lb_pair, bin_arrays)
+    }
+
+    #[test]
+    fn test_quote_exact_in_with_breakdown() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+
+        let SwapExactInQuoteWithBreakdown { quote, breakdown } = quote_exact_in_with_breakdown(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            false,
+            bin_arrays,
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        let bin_ids = breakdown.iter().map(|bin| bin.bin_id).collect::<Vec<_>>();
+        assert_eq!(bin_ids, vec![0, 1, 2]);
+
+        let amount_out = breakdown.iter().map(|bin| bin.amount_out).sum::<u64>();
+        assert_eq!(amount_out, quote.amount_out);
+        assert_eq!(amount_out, quote.amount_out_of_pool);
+        assert_eq!(quote.amount_into_pool + quote.fee, 2_500);
+        assert_eq!(fees_in_range(&breakdown, MIN_BIN_ID, MAX_BIN_ID), quote.fee);
+        assert_eq!(
+            fees_in_range(&breakdown, 0, 1),
+            breakdown[0].fee + breakdown[1].fee
+        );
+        assert_eq!(fees_in_range(&breakdown, 3, 10), 0);
+
+        // Bin 0 and 1 are drained, bin 2 is partially filled
+        assert_eq!(breakdown[0].remaining_amount_x, 0);
+        assert_eq!(
+            breakdown[0].remaining_amount_y,
+            1_000 + breakdown[0].amount_in - breakdown[0].fee
+        );
+        assert_eq!(breakdown[1].remaining_amount_x, 0);
+        assert_eq!(
+            breakdown[1].remaining_amount_y,
+            breakdown[1].amount_in - breakdown[1].fee
+        );
+        assert!(breakdown[2].remaining_amount_x > 0);
+    }
+
+    #[test]
+    fn test_quote_exact_in_skips_empty_bins() {
+        let (lb_pair_pubkey, lb_pair, mut bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+
+        // Liquidity of bin 0, and one far bin in each direction
+        for bin_id in [-3, -2, -1, 1, 2, 3] {
+            let bin_array = bin_arrays
+                .get_mut(&BinArray::bin_id_to_bin_array_key(lb_pair_pubkey, bin_id).unwrap())
+                .unwrap();
+            let bin = bin_array.get_bin_mut(bin_id).unwrap();
+            bin.amount_x = 0;
+            bin.amount_y = 0;
+        }
+        for (bin_id, amount_x, amount_y) in [(-65, 0, 1_000), (60, 1_000, 0)] {
+            let bin_array = bin_arrays
+                .get_mut(&BinArray::bin_id_to_bin_array_key(lb_pair_pubkey, bin_id).unwrap())
+                .unwrap();
+            let bin = bin_array.get_bin_mut(bin_id).unwrap();
+            bin.amount_x = amount_x;
+            bin.amount_y = amount_y;
+        }
+
+        for (swap_for_y, expected_bin_ids) in [(true, vec![0, -65]), (false, vec![0, 60])] {
+            let SwapExactInQuoteWithBreakdown { breakdown, .. } = quote_exact_in_with_breakdown(
+                lb_pair_pubkey,
+                &lb_pair,
+                1_500,
+                swap_for_y,
+                bin_arrays.clone(),
+                None,
+                &Clock::default(),
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap();
+
+            let bin_ids = breakdown.iter().map(|bin| bin.bin_id).collect::<Vec<_>>();
+            assert_eq!(bin_ids, expected_bin_ids);
+            assert_eq!(breakdown[0].amount_in + breakdown[1].amount_in, 1_500);
+
+            // Empty bins up to the end of the bin array are skipped too, before running out of liquidity
+            let err = quote_exact_in(
+                lb_pair_pubkey,
+                &lb_pair,
+                5_000,
+                swap_for_y,
+                bin_arrays.clone(),
+                None,
+                &Clock::default(),
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<QuoteError>(),
+                Some(QuoteError::InsufficientLiquidity { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_precise_amount_out() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+
+        for swap_for_y in [true, false] {
+            let quote_with_breakdown = quote_exact_in_with_breakdown(
+                lb_pair_pubkey,
+                &lb_pair,
+                2_500,
+                swap_for_y,
+                bin_arrays.clone(),
+                None,
+                &Clock::default(),
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap();
+            let SwapExactInQuoteWithBreakdown { quote, breakdown } = &quote_with_breakdown;
+
+            // Drained bins give out exactly their balance
+            for bin in &breakdown[..2] {
+                assert_eq!(
+                    bin.precise_amount_out,
+                    u128::from(bin.amount_out) << SCALE_OFFSET
+                );
+            }
+
+            // Partially filled bin keeps the fraction truncated by get_amount_out
+            let partial_bin = &breakdown[2];
+            assert_eq!(
+                partial_bin.precise_amount_out >> SCALE_OFFSET,
+                partial_bin.amount_out.into()
+            );
+            assert!(
+                partial_bin.precise_amount_out > u128::from(partial_bin.amount_out) << SCALE_OFFSET
+            );
+
+            let precise_amount_out = quote_with_breakdown.precise_amount_out().unwrap();
+            assert_eq!(
+                precise_amount_out >> SCALE_OFFSET,
+                quote.amount_out_of_pool.into()
+            );
+        }
+    }
+
+    #[test]
+    fn test_quote_exact_in_with_skip_reference_update() {
+        let (lb_pair_pubkey, mut lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+        lb_pair.parameters.variable_fee_control = 5_000_000;
+        lb_pair.parameters.max_volatility_accumulator = 350_000;
+        lb_pair.parameters.filter_period = 30;
+        lb_pair.parameters.decay_period = 600;
+        lb_pair.parameters.reduction_factor = 5_000;
+        // Volatility left by the last swap, which fully decays after the decay period
+        lb_pair.v_parameters.volatility_reference = 100_000;
+        lb_pair.v_parameters.volatility_accumulator = 100_000;
+
+        let clock = Clock {
+            unix_timestamp: 1_000,
+            ..Default::default()
+        };
+
+        let quote_with_skip = |skip_reference_update| {
+            quote_exact_in_with_skip_reference_update(
+                lb_pair_pubkey,
+                &lb_pair,
+                500,
+                true,
+                bin_arrays.clone(),
+                None,
+                skip_reference_update,
+                &clock,
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap()
+        };
+
+        assert_eq!(
+            quote_with_skip(false),
+            quote_exact_in(
+                lb_pair_pubkey,
+                &lb_pair,
+                500,
+                true,
+                bin_arrays.clone(),
+                None,
+                &clock,
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap()
+        );
+
+        // Stored volatility reference keeps the variable fee
+        assert!(quote_with_skip(true).fee > quote_with_skip(false).fee);
+    }
+
+    #[test]
+    fn test_quote_exact_in_breakdown_fee_bps() {
+        let (lb_pair_pubkey, mut lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+
+        let breakdown = quote_exact_in_with_breakdown(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            false,
+            bin_arrays.clone(),
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap()
+        .breakdown;
+
+        // Static fee, only the 10 bps base fee
+        let fee_bps = breakdown.iter().map(|bin| bin.fee_bps).collect::<Vec<_>>();
+        assert_eq!(fee_bps, vec![10, 10, 10]);
+
+        lb_pair.parameters.variable_fee_control = 5_000_000;
+        lb_pair.parameters.max_volatility_accumulator = 350_000;
+
+        let breakdown = quote_exact_in_with_breakdown(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            false,
+            bin_arrays,
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap()
+        .breakdown;
+
+        // Volatility accumulator grows by 10_000 per bin crossed, adding 5 bps then 20 bps of variable fee
+        let fee_bps = breakdown.iter().map(|bin| bin.fee_bps).collect::<Vec<_>>();
+        assert_eq!(fee_bps, vec![10, 15, 30]);
+    }
+
+    #[test]
+    fn test_fee_both_terms() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+
+        let quote = quote_exact_in(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            true,
+            bin_arrays,
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        let (fee_in, fee_out) = quote.fee_both_terms().unwrap();
+        assert_eq!(fee_in, quote.fee);
+        assert_eq!(
+            fee_out,
+            quote.fee * quote.amount_out_of_pool / quote.amount_into_pool
+        );
+        // Execution price is below 1 Y per X when swapping down from bin 0
+        assert!(fee_out > 0 && fee_out < fee_in);
+
+        let quote = SwapExactInQuote {
+            fee: 1,
+            ..Default::default()
+        };
+        assert_eq!(quote.fee_both_terms().unwrap(), (1, 0));
+    }
+
+    #[test]
+    fn test_quote_borsh_bytes() {
+        let quote = SwapExactInQuote {
+            amount_out: 225_300_000,
+            fee: 4_500_000,
+            protocol_fee: 900_000,
+            amount_into_pool: 1_495_500_000,
+            amount_out_of_pool: u64::MAX,
+            final_bin_partially_filled: true,
+            post_swap_volatility_accumulator: 350_000,
+        };
+        let bytes = quote.to_borsh_bytes().unwrap();
+        assert_eq!(bytes.len(), 5 * 8 + 1 + 4);
+        assert_eq!(SwapExactInQuote::from_borsh_bytes(&bytes).unwrap(), quote);
+
+        let quote = SwapExactOutQuote {
+            amount_in: 1_500_000_000,
+            fee: 4_500_000,
+            amount_into_pool: 1_495_500_000,
+            amount_out_of_pool: 225_300_000,
+        };
+        let bytes = quote.to_borsh_bytes().unwrap();
+        assert_eq!(bytes.len(), 4 * 8);
+        assert_eq!(SwapExactOutQuote::from_borsh_bytes(&bytes).unwrap(), quote);
+
+        // Trailing or missing bytes are rejected
+        assert!(SwapExactOutQuote::from_borsh_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+        assert!(SwapExactOutQuote::from_borsh_bytes(&bytes[1..]).is_err());
+    }
+
+    #[test]
+    fn test_notional_y() {
+        let quote = SwapExactInQuote {
+            fee: 3,
+            amount_into_pool: 997,
+            ..Default::default()
+        };
+
+        // 1 X = 2.5 Y
+        let price = 5 * ONE / 2;
+        assert_eq!(quote.notional_y(true, price).unwrap(), 2_500);
+        assert_eq!(quote.notional_y(false, price).unwrap(), 1_000);
+
+        // Rounded down
+        let price = ONE / 3;
+        assert_eq!(quote.notional_y(true, price).unwrap(), 333);
+    }
+
+    #[test]
+    fn test_all_in_rate() {
+        use crate::token_2022::tests::new_transfer_fee_mint_account;
+
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        // 1% transfer fee on both tokens
+        let mint_account = new_transfer_fee_mint_account(100, u64::MAX);
+
+        let quote = quote_exact_in(
+            lb_pair_pubkey,
+            &lb_pair,
+            1_000,
+            true,
+            bin_arrays,
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        let all_in_rate = quote.all_in_rate(1_000, 6, 6);
+        assert_eq!(all_in_rate, quote.amount_out as f64 / 1_000.0);
+
+        // Both transfer fee legs make the wallet rate worse than the rate within the pool
+        let pool_rate =
+            quote.amount_out_of_pool as f64 / (quote.amount_into_pool + quote.fee) as f64;
+        assert!(all_in_rate < pool_rate * 0.99);
+
+        assert!((quote.all_in_rate(1_000, 9, 6) - all_in_rate * 1_000.0).abs() < 1e-9);
+        assert_eq!(quote.all_in_rate(0, 6, 6), 0.0);
+    }
+
+    #[test]
+    fn test_fee_in_output_terms() {
+        let quote = SwapExactInQuote {
+            amount_out: 0,
+            fee: 100,
+            protocol_fee: 0,
+            amount_into_pool: 0,
+            amount_out_of_pool: 0,
+            final_bin_partially_filled: false,
+            post_swap_volatility_accumulator: 0,
+        };
+
+        // 1 X = 2 Y
+        let price = 2 * ONE;
+        assert_eq!(quote.fee_in_output_terms(price, true).unwrap(), 200);
+        assert_eq!(quote.fee_in_output_terms(price, false).unwrap(), 50);
+    }
+
+    #[test]
+    fn test_quote_exact_in_with_fee_override() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+        let clock = Clock::default();
+
+        let quote = quote_exact_in(
+            lb_pair_pubkey,
+            &lb_pair,
+            500,
+            true,
+            bin_arrays.clone(),
+            None,
+            &clock,
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        let no_override_quote = quote_exact_in_with_fee_override(
+            lb_pair_pubkey,
+            &lb_pair,
+            500,
+            true,
+            bin_arrays.clone(),
+            None,
+            FeeOverride::default(),
+            &clock,
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        assert_eq!(quote, no_override_quote);
+
+        let overridden_quote = quote_exact_in_with_fee_override(
+            lb_pair_pubkey,
+            &lb_pair,
+            500,
+            true,
+            bin_arrays,
+            None,
+            FeeOverride {
+                base_factor: Some(50_000),
+                ..Default::default()
+            },
+            &clock,
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        assert!(overridden_quote.fee > quote.fee);
+        assert!(overridden_quote.amount_out < quote.amount_out);
+    }
+
+    #[test]
+    fn test_lp_fee() {
+        let (lb_pair_pubkey, mut lb_pair, bin_arrays) = new_test_pool();
+        // 0.6% base fee, 25% protocol share
+        lb_pair.parameters.base_factor = 60_000;
+        lb_pair.parameters.protocol_share = 2_500;
+        let mint_account = new_test_mint_account();
+
+        let SwapExactInQuoteWithBreakdown { quote, breakdown } = quote_exact_in_with_breakdown(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            true,
+            bin_arrays,
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        let protocol_fee = breakdown
+            .iter()
+            .map(|bin| lb_pair.compute_protocol_fee(bin.fee).unwrap())
+            .sum::<u64>();
+
+        assert!(quote.protocol_fee > 0);
+        assert_eq!(quote.protocol_fee, protocol_fee);
+        assert_eq!(quote.lp_fee().unwrap(), quote.fee - protocol_fee);
+    }
+
+    #[test]
+    fn test_total_protocol_fee() {
+        let (lb_pair_pubkey, mut lb_pair, bin_arrays) = new_test_pool();
+        lb_pair.parameters.base_factor = 60_000;
+        lb_pair.parameters.protocol_share = 2_500;
+        let mint_account = new_test_mint_account();
+
+        let SwapExactInQuoteWithBreakdown { quote, breakdown } = quote_exact_in_with_breakdown(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            true,
+            bin_arrays,
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        let total_protocol_fee = quote.total_protocol_fee(&lb_pair).unwrap();
+
+        assert!(breakdown.len() > 1);
+        assert_eq!(
+            total_protocol_fee,
+            quote.fee * u64::from(lb_pair.parameters.protocol_share) / BASIS_POINT_MAX as u64
+        );
+        // Per bin rounding only loses dust against the total
+        assert!(total_protocol_fee >= quote.protocol_fee);
+        assert!(total_protocol_fee - quote.protocol_fee < breakdown.len() as u64);
+    }
+
+    #[test]
+    fn test_quote_exact_in_with_reserve_cap() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+        let clock = Clock::default();
+
+        let quote = quote_exact_in(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            true,
+            bin_arrays.clone(),
+            None,
+            &clock,
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        // Reserve covers the amount out
+        let SwapExactInQuoteWithReserveCap {
+            quote: capped_quote,
+            reserve_shortfall,
+        } = quote_exact_in_with_reserve_cap(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            true,
+            bin_arrays.clone(),
+            None,
+            Some(0),
+            Some(quote.amount_out),
+            &clock,
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        assert_eq!(capped_quote, quote);
+        assert_eq!(reserve_shortfall, None);
+
+        // Reserve is short by 1
+        let reserve_y_balance = quote.amount_out - 1;
+        let SwapExactInQuoteWithReserveCap {
+            quote: capped_quote,
+            reserve_shortfall,
+        } = quote_exact_in_with_reserve_cap(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            true,
+            bin_arrays,
+            None,
+            None,
+            Some(reserve_y_balance),
+            &clock,
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        assert_eq!(capped_quote.amount_out, reserve_y_balance);
+        assert_eq!(
+            reserve_shortfall,
+            Some(ReserveShortfall {
+                bin_amount_out: quote.amount_out,
+                reserve_balance: reserve_y_balance,
+            })
+        );
+    }
+
+    #[test]
+    fn test_quote_exact_in_with_sorted_bin_arrays() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+        let clock = Clock::default();
+
+        let mut sorted_bin_arrays = bin_arrays
+            .values()
+            .map(|bin_array| (bin_array.index as i32, *bin_array))
+            .collect::<Vec<_>>();
+        sorted_bin_arrays.sort_by_key(|(index, _)| *index);
+
+        for (amount_in, swap_for_y) in [(2_500, true), (2_500, false), (10, true)] {
+            let quote = quote_exact_in(
+                lb_pair_pubkey,
+                &lb_pair,
+                amount_in,
+                swap_for_y,
+                bin_arrays.clone(),
+                None,
+                &clock,
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap();
+
+            let sorted_quote = quote_exact_in_with_sorted_bin_arrays(
+                &lb_pair,
+                amount_in,
+                swap_for_y,
+                &sorted_bin_arrays,
+                None,
+                &clock,
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap();
+
+            assert_eq!(quote, sorted_quote);
+        }
+
+        // Missing bin array
+        assert!(quote_exact_in_with_sorted_bin_arrays(
+            &lb_pair,
+            2_500,
+            true,
+            &sorted_bin_arrays[1..],
+            None,
+            &clock,
+            &mint_account,
+            &mint_account,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_quote_exact_in_with_reverse_check() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+
+        for swap_for_y in [true, false] {
+            let (exact_in_quote, exact_out_quote, drift) = quote_exact_in_with_reverse_check(
+                lb_pair_pubkey,
+                &lb_pair,
+                2_500,
+                swap_for_y,
+                bin_arrays.clone(),
+                None,
+                &Clock::default(),
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap();
+
+            assert!(exact_in_quote.amount_out > 0);
+            assert_eq!(
+                exact_in_quote.amount_out,
+                exact_out_quote.amount_out_of_pool
+            );
+            assert_eq!(
+                drift,
+                i128::from(exact_out_quote.amount_in) - i128::from(2_500u64)
+            );
+            // Amount in of the exact in quote is enough to reproduce its amount out
+            assert!(drift <= 0);
+        }
+    }
+
+    #[test]
+    fn test_quote_no_progress() {
+        let lb_pair_pubkey = Pubkey::new_unique();
+        let mut lb_pair = new_test_lb_pair(10, 10_000);
+        lb_pair.active_id = 0;
+
+        // Bitmap claims liquidity in bin array 1, which is empty and doesn't contain the active bin
+        let offset = LbPair::get_bin_array_offset(1);
+        lb_pair.bin_array_bitmap[offset / 64] |= 1 << (offset % 64);
+
+        let bin_arrays = HashMap::from([(
+            derive_bin_array_pda(lb_pair_pubkey, 1).0,
+            new_test_bin_array(1),
+        )]);
+        let mint_account = new_test_mint_account();
+        let expected_err = QuoteError::NoProgress {
+            active_id: 0,
+            bin_array_index: 1,
+        };
+
+        let err = quote_exact_in(
+            lb_pair_pubkey,
+            &lb_pair,
+            1_000,
+            false,
+            bin_arrays.clone(),
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap_err();
+        assert_eq!(err.downcast_ref::<QuoteError>(), Some(&expected_err));
+
+        let err = quote_exact_out(
+            lb_pair_pubkey,
+            &lb_pair,
+            1_000,
+            false,
+            bin_arrays,
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap_err();
+        assert_eq!(err.downcast_ref::<QuoteError>(), Some(&expected_err));
+    }
+
+    #[test]
+    fn test_quote_with_transfer_fee_mode() {
+        use crate::token_2022::tests::new_transfer_fee_mint_account;
+
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        // 10% transfer fee on token X, 5% on token Y
+        let mint_x_account = new_transfer_fee_mint_account(1_000, u64::MAX);
+        let mint_y_account = new_transfer_fee_mint_account(500, u64::MAX);
+
+        let quote_exact_in_with = |transfer_fee_mode| {
+            quote_exact_in_with_transfer_fee_mode(
+                lb_pair_pubkey,
+                &lb_pair,
+                2_000,
+                true,
+                bin_arrays.clone(),
+                None,
+                transfer_fee_mode,
+                &Clock::default(),
+                &mint_x_account,
+                &mint_y_account,
+            )
+            .unwrap()
+        };
+
+        // Gross amounts are the amounts swapped within the pool. Bin 0 takes 1_000 + 2 fee, bin -1 takes the rest
+        let gross_quote = quote_exact_in_with(TransferFeeMode::Gross);
+        assert_eq!(gross_quote.amount_into_pool + gross_quote.fee, 2_000);
+        assert_eq!(gross_quote.amount_out_of_pool, 1_996);
+        assert_eq!(gross_quote.amount_out, 1_996);
+
+        // Net amount in loses 200 to the token X transfer fee, and amount out loses 90 to the token Y transfer fee
+        let net_quote = quote_exact_in_with(TransferFeeMode::Net);
+        assert_eq!(net_quote.amount_into_pool + net_quote.fee, 1_800);
+        assert_eq!(net_quote.amount_out_of_pool, 1_796);
+        assert_eq!(net_quote.amount_out, 1_706);
+
+        let quote_exact_out_with = |transfer_fee_mode| {
+            quote_exact_out_with_transfer_fee_mode(
+                lb_pair_pubkey,
+                &lb_pair,
+                1_000,
+                true,
+                bin_arrays.clone(),
+                None,
+                transfer_fee_mode,
+                &Clock::default(),
+                &mint_x_account,
+                &mint_y_account,
+            )
+            .unwrap()
+        };
+
+        // Bin 0 gives out 1_000 for 1_000 + 2 fee
+        let gross_quote = quote_exact_out_with(TransferFeeMode::Gross);
+        assert_eq!(gross_quote.amount_out_of_pool, 1_000);
+        assert_eq!(gross_quote.amount_in, 1_002);
+
+        // 1_053 leave the pool so 1_000 arrive after the token Y transfer fee. The extra 53 cost 55 in bin -1, and 1_175 must be sent
+        // so 1_057 arrive after the token X transfer fee
+        let net_quote = quote_exact_out_with(TransferFeeMode::Net);
+        assert_eq!(net_quote.amount_out_of_pool, 1_053);
+        assert_eq!(net_quote.amount_into_pool + net_quote.fee, 1_057);
+        assert_eq!(net_quote.amount_in, 1_175);
+    }
+
+    #[test]
+    fn test_quote_exact_in_with_and_without_transfer_fee() {
+        use crate::token_2022::tests::new_transfer_fee_mint_account;
+
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+        // 10% transfer fee on token X
+        let mint_x_account = new_transfer_fee_mint_account(1_000, u64::MAX);
+
+        let (net_quote, gross_quote) = quote_exact_in_with_and_without_transfer_fee(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_000,
+            true,
+            bin_arrays.clone(),
+            None,
+            &Clock::default(),
+            &mint_x_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        assert_eq!(
+            net_quote,
+            quote_exact_in(
+                lb_pair_pubkey,
+                &lb_pair,
+                2_000,
+                true,
+                bin_arrays,
+                None,
+                &Clock::default(),
+                &mint_x_account,
+                &mint_account,
+            )
+            .unwrap()
+        );
+        assert_eq!(net_quote.amount_into_pool + net_quote.fee, 1_800);
+        assert_eq!(gross_quote.amount_into_pool + gross_quote.fee, 2_000);
+        assert!(gross_quote.amount_out > net_quote.amount_out);
+    }
+
+    #[test]
+    fn test_quote_exact_in_at_epoch() {
+        use crate::token_2022::tests::new_transfer_fee_schedule_mint_account;
+        use anchor_spl::token_2022::spl_token_2022::extension::transfer_fee::TransferFee;
+
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+        // No transfer fee on token X until epoch 10, 10% after
+        let mint_x_account = new_transfer_fee_schedule_mint_account(
+            TransferFee {
+                epoch: 0.into(),
+                maximum_fee: u64::MAX.into(),
+                transfer_fee_basis_points: 0.into(),
+            },
+            TransferFee {
+                epoch: 10.into(),
+                maximum_fee: u64::MAX.into(),
+                transfer_fee_basis_points: 1_000.into(),
+            },
+        );
+        let clock = Clock::default();
+
+        let quote_at = |epoch| {
+            quote_exact_in_at(
+                lb_pair_pubkey,
+                &lb_pair,
+                1_000,
+                true,
+                bin_arrays.clone(),
+                None,
+                &clock,
+                epoch,
+                &mint_x_account,
+                &mint_account,
+            )
+            .unwrap()
+        };
+
+        let quote_before = quote_at(9);
+        let quote_after = quote_at(10);
+
+        assert_eq!(
+            quote_before,
+            quote_exact_in(
+                lb_pair_pubkey,
+                &lb_pair,
+                1_000,
+                true,
+                bin_arrays.clone(),
+                None,
+                &clock,
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap()
+        );
+        assert_eq!(quote_before.amount_into_pool + quote_before.fee, 1_000);
+        assert_eq!(quote_after.amount_into_pool + quote_after.fee, 900);
+        assert!(quote_after.amount_out < quote_before.amount_out);
+    }
+
+    #[test]
+    fn test_lp_fee_share() {
+        let breakdown = vec![
+            BinSwapBreakdown {
+                bin_id: 1,
+                amount_in: 10_000,
+                amount_out: 9_000,
+                precise_amount_out: 9_000 << SCALE_OFFSET,
+                fee: 1_000,
+                protocol_fee: 100,
+                fee_bps: 1_000,
+                remaining_amount_x: 0,
+                remaining_amount_y: 0,
+            },
+            BinSwapBreakdown {
+                bin_id: 2,
+                amount_in: 5_000,
+                amount_out: 4_500,
+                precise_amount_out: 4_500 << SCALE_OFFSET,
+                fee: 500,
+                protocol_fee: 50,
+                fee_bps: 1_000,
+                remaining_amount_x: 0,
+                remaining_amount_y: 0,
+            },
+        ];
+
+        assert_eq!(lp_fee_share(&breakdown, 1, 1, 3).unwrap(), 300);
+        assert_eq!(lp_fee_share(&breakdown, 2, 100, 100).unwrap(), 450);
+        assert_eq!(lp_fee_share(&breakdown, 3, 100, 100).unwrap(), 0);
+        assert_eq!(lp_fee_share(&breakdown, 1, 0, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_route_total_fee() {
+        assert_eq!(route_total_fee(&[]).unwrap(), 0);
+
+        // 1 X = 2 Y, then 1 Y = 0.5 Z
+        let x_to_y = SwapExactInQuote {
+            fee: 10,
+            amount_into_pool: 1_000,
+            amount_out_of_pool: 2_000,
+            ..Default::default()
+        };
+        let y_to_z = SwapExactInQuote {
+            fee: 20,
+            amount_into_pool: 1_980,
+            amount_out_of_pool: 990,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            route_total_fee(std::slice::from_ref(&x_to_y)).unwrap(),
+            x_to_y.fee_both_terms().unwrap().1
+        );
+        // 10 X = 20 Y, plus 20 Y, in Z
+        assert_eq!(route_total_fee(&[x_to_y.clone(), y_to_z]).unwrap(), 20);
+
+        assert!(route_total_fee(&[x_to_y, SwapExactInQuote::default()]).is_err());
+    }
+
+    #[test]
+    fn test_quote_exact_in_worst_case_fee() {
+        let (lb_pair_pubkey, mut lb_pair, bin_arrays) = new_test_pool();
+        lb_pair.parameters.variable_fee_control = 40_000;
+        lb_pair.parameters.max_volatility_accumulator = 350_000;
+        lb_pair.parameters.filter_period = 30;
+        lb_pair.parameters.decay_period = 600;
+        lb_pair.parameters.reduction_factor = 5_000;
+        let mint_account = new_test_mint_account();
+
+        let quote = quote_exact_in(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            true,
+            bin_arrays.clone(),
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        let worst_case_quote = quote_exact_in_worst_case_fee(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            true,
+            bin_arrays,
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        assert!(worst_case_quote.fee > quote.fee);
+        assert!(worst_case_quote.amount_out < quote.amount_out);
+
+        let mut max_fee_lb_pair = lb_pair;
+        max_fee_lb_pair.v_parameters.volatility_accumulator =
+            lb_pair.parameters.max_volatility_accumulator;
+        assert_eq!(
+            worst_case_quote.fee,
+            max_fee_lb_pair
+                .compute_fee_from_amount(worst_case_quote.amount_into_pool + worst_case_quote.fee)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_fee_rate_after_swap() {
+        let (lb_pair_pubkey, mut lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+
+        let quote = quote_exact_in(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            true,
+            bin_arrays.clone(),
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+        // Static fee pair
+        assert_eq!(quote.fee_rate_after_swap(&lb_pair).unwrap(), 10);
+
+        lb_pair.parameters.variable_fee_control = 40_000;
+        lb_pair.parameters.max_volatility_accumulator = 350_000;
+        lb_pair.parameters.filter_period = 30;
+        lb_pair.parameters.decay_period = 600;
+        lb_pair.parameters.reduction_factor = 5_000;
+
+        let quote = quote_exact_in(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            true,
+            bin_arrays,
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        // Swap ends at bin -2, 2 bins away from the index reference
+        assert_eq!(quote.post_swap_volatility_accumulator, 20_000);
+
+        let mut post_swap_lb_pair = lb_pair;
+        post_swap_lb_pair.v_parameters.volatility_accumulator = 20_000;
+        let fee_rate_after_swap = quote.fee_rate_after_swap(&lb_pair).unwrap();
+        assert_eq!(
+            fee_rate_after_swap,
+            post_swap_lb_pair.get_total_fee_bps().unwrap()
+        );
+        assert!(fee_rate_after_swap > lb_pair.get_total_fee_bps().unwrap());
+    }
+
+    #[test]
+    fn test_resume_quote_exact_in() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+
+        for swap_for_y in [true, false] {
+            let mut cursor = QuoteCursor::new(
+                lb_pair_pubkey,
+                &lb_pair,
+                swap_for_y,
+                bin_arrays.clone(),
+                &Clock::default(),
+            )
+            .unwrap();
+
+            let mut previous_amount_out = 0;
+            for _ in 0..5 {
+                cursor = resume_quote_exact_in(
+                    cursor,
+                    500,
+                    None,
+                    &Clock::default(),
+                    &mint_account,
+                    &mint_account,
+                )
+                .unwrap();
+                assert!(cursor.quote.amount_out > previous_amount_out);
+                previous_amount_out = cursor.quote.amount_out;
+            }
+            assert_eq!(cursor.amount_in, 2_500);
+
+            let quote = quote_exact_in(
+                lb_pair_pubkey,
+                &lb_pair,
+                2_500,
+                swap_for_y,
+                bin_arrays.clone(),
+                None,
+                &Clock::default(),
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap();
+
+            assert_eq!(
+                cursor.quote.amount_into_pool + cursor.quote.fee,
+                quote.amount_into_pool + quote.fee
+            );
+            // Chunked swap only differs by rounding
+            assert!(cursor.quote.amount_out.abs_diff(quote.amount_out) <= 5);
+        }
+    }
+
+    #[test]
+    fn test_get_bin_array_pubkeys_for_swap_mismatched_bitmap_extension() {
+        let (lb_pair_pubkey, lb_pair, _) = new_test_pool();
+
+        let mut bitmap_extension: BinArrayBitmapExtension = bytemuck::Zeroable::zeroed();
+        bitmap_extension.lb_pair = lb_pair_pubkey;
+        assert!(get_bin_array_pubkeys_for_swap(
+            lb_pair_pubkey,
+            &lb_pair,
+            Some(&bitmap_extension),
+            true,
+            1
+        )
+        .is_ok());
+
+        bitmap_extension.lb_pair = Pubkey::new_unique();
+        let err = get_bin_array_pubkeys_for_swap(
+            lb_pair_pubkey,
+            &lb_pair,
+            Some(&bitmap_extension),
+            true,
+            1,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<QuoteError>(),
+            Some(&QuoteError::MismatchedBitmapExtension {
+                bitmap_extension_lb_pair: bitmap_extension.lb_pair,
+                lb_pair: lb_pair_pubkey,
+            })
+        );
+    }
+
+    #[test]
+    fn test_quote_mismatched_bitmap_extension() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+
+        let mut bitmap_extension: BinArrayBitmapExtension = bytemuck::Zeroable::zeroed();
+        bitmap_extension.lb_pair = Pubkey::new_unique();
+        let expected_err = QuoteError::MismatchedBitmapExtension {
+            bitmap_extension_lb_pair: bitmap_extension.lb_pair,
+            lb_pair: lb_pair_pubkey,
+        };
+
+        let err = quote_exact_in(
+            lb_pair_pubkey,
+            &lb_pair,
+            1_000,
+            true,
+            bin_arrays.clone(),
+            Some(&bitmap_extension),
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap_err();
+        assert_eq!(err.downcast_ref::<QuoteError>(), Some(&expected_err));
+
+        let err = quote_exact_out(
+            lb_pair_pubkey,
+            &lb_pair,
+            1_000,
+            true,
+            bin_arrays,
+            Some(&bitmap_extension),
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap_err();
+        assert_eq!(err.downcast_ref::<QuoteError>(), Some(&expected_err));
+    }
+
+    #[test]
+    fn test_validate_bin_arrays() {
+        let (lb_pair_pubkey, _, mut bin_arrays) = new_test_pool();
+        assert!(validate_bin_arrays(lb_pair_pubkey, &bin_arrays).is_ok());
+
+        let bin_array_pubkey = derive_bin_array_pda(lb_pair_pubkey, 0).0;
+        let bin_array = bin_arrays[&bin_array_pubkey];
+
+        // Bin array of another pair, keyed by its own PDA
+        let other_lb_pair_pubkey = Pubkey::new_unique();
+        let err = validate_bin_arrays(
+            other_lb_pair_pubkey,
+            &HashMap::from([(bin_array_pubkey, bin_array)]),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<QuoteError>(),
+            Some(&QuoteError::MismatchedBinArray {
+                bin_array: bin_array_pubkey,
+                bin_array_lb_pair: lb_pair_pubkey,
+                expected_lb_pair: other_lb_pair_pubkey,
+                expected_pubkey: derive_bin_array_pda(other_lb_pair_pubkey, 0).0,
+            })
+        );
+
+        // Bin array of index 0 keyed by the PDA of index -1
+        let wrong_pubkey = derive_bin_array_pda(lb_pair_pubkey, -1).0;
+        bin_arrays.insert(wrong_pubkey, bin_array);
+        let err = validate_bin_arrays(lb_pair_pubkey, &bin_arrays).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<QuoteError>(),
+            Some(&QuoteError::MismatchedBinArray {
+                bin_array: wrong_pubkey,
+                bin_array_lb_pair: lb_pair_pubkey,
+                expected_lb_pair: lb_pair_pubkey,
+                expected_pubkey: bin_array_pubkey,
+            })
+        );
+    }
+
+    #[test]
+    fn test_quote_exact_out_with_price_limit() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+
+        // Price limit of bin -1 allows to buy token Y from bin 0 and -1 only
+        let price_limit = get_price_from_id(-1, lb_pair.bin_step).unwrap();
+        let (quote, unfulfilled_amount_out) = quote_exact_out_with_price_limit(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            true,
+            bin_arrays.clone(),
+            None,
+            price_limit,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+        assert_eq!(quote.amount_out_of_pool, 2_000);
+        assert_eq!(unfulfilled_amount_out, 500);
+
+        // Limit not reached
+        let (quote, unfulfilled_amount_out) = quote_exact_out_with_price_limit(
+            lb_pair_pubkey,
+            &lb_pair,
+            1_500,
+            true,
+            bin_arrays.clone(),
+            None,
+            price_limit,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+        assert_eq!(unfulfilled_amount_out, 0);
+        assert_eq!(
+            quote,
+            quote_exact_out(
+                lb_pair_pubkey,
+                &lb_pair,
+                1_500,
+                true,
+                bin_arrays.clone(),
+                None,
+                &Clock::default(),
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap()
+        );
+
+        // Buying token X above the active price is not allowed
+        let price_limit = get_price_from_id(-1, lb_pair.bin_step).unwrap();
+        let (quote, unfulfilled_amount_out) = quote_exact_out_with_price_limit(
+            lb_pair_pubkey,
+            &lb_pair,
+            500,
+            false,
+            bin_arrays,
+            None,
+            price_limit,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+        assert_eq!(quote.amount_in, 0);
+        assert_eq!(unfulfilled_amount_out, 500);
+    }
+
+    #[test]
+    fn test_exact_out_bin_path() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+
+        let bin_path = exact_out_bin_path(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            true,
+            bin_arrays.clone(),
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+        assert_eq!(bin_path, vec![0, -1, -2]);
+
+        let mut bin_array_indexes = bin_path
+            .iter()
+            .map(|bin_id| BinArray::bin_id_to_bin_array_index(*bin_id).unwrap())
+            .collect::<Vec<_>>();
+        bin_array_indexes.dedup();
+        assert_eq!(bin_array_indexes, vec![0, -1]);
+
+        let bin_path = exact_out_bin_path(
+            lb_pair_pubkey,
+            &lb_pair,
+            1_500,
+            false,
+            bin_arrays,
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+        assert_eq!(bin_path, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_quote_empty_pool() {
+        let lb_pair_pubkey = Pubkey::new_unique();
+        let lb_pair = new_test_lb_pair(10, 10_000);
+        let mint_account = new_test_mint_account();
+
+        for swap_for_y in [true, false] {
+            let err = quote_exact_in(
+                lb_pair_pubkey,
+                &lb_pair,
+                1_000,
+                swap_for_y,
+                HashMap::new(),
+                None,
+                &Clock::default(),
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<QuoteError>(),
+                Some(&QuoteError::EmptyPool)
+            );
+
+            let err = quote_exact_out(
+                lb_pair_pubkey,
+                &lb_pair,
+                1_000,
+                swap_for_y,
+                HashMap::new(),
+                None,
+                &Clock::default(),
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<QuoteError>(),
+                Some(&QuoteError::EmptyPool)
+            );
+        }
+    }
+
+    #[test]
+    fn test_quote_insufficient_liquidity() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+
+        // Bin -3 to 0 hold 1_000 token Y each
+        let err = quote_exact_in(
+            lb_pair_pubkey,
+            &lb_pair,
+            10_000,
+            true,
+            bin_arrays.clone(),
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<QuoteError>(),
+            Some(&QuoteError::InsufficientLiquidity { filled_out: 4_000 })
+        );
+
+        let err = quote_exact_out(
+            lb_pair_pubkey,
+            &lb_pair,
+            10_000,
+            true,
+            bin_arrays,
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<QuoteError>(),
+            Some(&QuoteError::InsufficientLiquidity { filled_out: 4_000 })
+        );
+    }
+
+    #[test]
+    fn test_validate_clock_age() {
+        let clock = Clock {
+            slot: 100,
+            ..Default::default()
+        };
+
+        assert!(validate_clock_age(&clock, 1_000, None).is_ok());
+        assert!(validate_clock_age(&clock, 110, Some(10)).is_ok());
+        // Clock ahead of the caller slot is not stale
+        assert!(validate_clock_age(&clock, 90, Some(0)).is_ok());
+
+        let err = validate_clock_age(&clock, 111, Some(10)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<QuoteError>(),
+            Some(&QuoteError::StaleClock {
+                clock_slot: 100,
+                current_slot: 111
+            })
+        );
+    }
+
+    #[test]
+    fn test_post_swap_token_ratio() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+
+        // Bin 0 holds both tokens
+        let (total_x, total_y) = post_swap_token_ratio(
+            lb_pair_pubkey,
+            &lb_pair,
+            0,
+            true,
+            bin_arrays.clone(),
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+        assert_eq!((total_x, total_y), (4_000, 4_000));
+
+        let quote = quote_exact_in(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            true,
+            bin_arrays.clone(),
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        let (total_x, total_y) = post_swap_token_ratio(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            true,
+            bin_arrays,
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        // Fee is claimed separately, and not part of the bin liquidity
+        assert_eq!(total_x, 4_000 + u128::from(quote.amount_into_pool));
+        assert_eq!(total_y, 4_000 - u128::from(quote.amount_out_of_pool));
+    }
+
+    #[test]
+    fn test_describe() {
+        let quote = SwapExactInQuote {
+            // 1% transfer fee on the output token
+            amount_out: 223_047_000,
+            fee: 4_500_000,
+            protocol_fee: 0,
+            amount_into_pool: 1_495_500_000,
+            amount_out_of_pool: 225_300_000,
+            final_bin_partially_filled: false,
+            post_swap_volatility_accumulator: 0,
+        };
+
+        assert_eq!(quote.describe(9, 6), "1.5 -> 225.3 (fee: 0.0045)");
+    }
+
+    #[test]
+    fn test_describe_with_transfer_fee() {
+        use crate::token_2022::tests::new_transfer_fee_mint_account;
+
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        // 1% transfer fee on both tokens
+        let mint_account = new_transfer_fee_mint_account(100, u64::MAX);
+
+        let quote = quote_exact_in(
+            lb_pair_pubkey,
+            &lb_pair,
+            1_000,
+            true,
+            bin_arrays,
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        // Input side is after the input transfer fee, output side is before the output transfer fee
+        assert_eq!(quote.amount_into_pool + quote.fee, 990);
+        assert!(quote.amount_out < quote.amount_out_of_pool);
+        assert_eq!(
+            quote.describe(3, 3),
+            format!(
+                "0.99 -> {} (fee: {})",
+                quote.amount_out_of_pool as f64 / 1_000.0,
+                quote.fee as f64 / 1_000.0
+            )
+        );
+    }
+
+    #[test]
+    fn test_realized_slippage_bps() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+
+        // Within the active bin, only the fee. 10 bps base fee of 500 rounds up to 1, which is 20 bps
+        let slippage_bps = realized_slippage_bps(
+            lb_pair_pubkey,
+            &lb_pair,
+            500,
+            true,
+            bin_arrays.clone(),
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+        assert_eq!(slippage_bps, 20);
+
+        // Crossing bins adds price impact
+        let large_slippage_bps = realized_slippage_bps(
+            lb_pair_pubkey,
+            &lb_pair,
+            3_500,
+            true,
+            bin_arrays,
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+        assert!(large_slippage_bps > slippage_bps);
+    }
+
+    #[test]
+    fn test_revalidate_quote() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+
+        let original = quote_exact_in(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            false,
+            bin_arrays.clone(),
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        let revalidate = |lb_pair: &LbPair, tolerance_bps| {
+            revalidate_quote(
+                &original,
+                lb_pair_pubkey,
+                lb_pair,
+                2_500,
+                false,
+                bin_arrays.clone(),
+                None,
+                &Clock::default(),
+                &mint_account,
+                &mint_account,
+                tolerance_bps,
+            )
+        };
+
+        // Unchanged accounts
+        assert!(revalidate(&lb_pair, 0).unwrap());
+
+        // Base fee raised from 10 bps to 50 bps
+        let mut fresh_lb_pair = lb_pair;
+        fresh_lb_pair.parameters.base_factor = 50_000;
+
+        assert!(!revalidate(&fresh_lb_pair, 0).unwrap());
+        assert!(revalidate(&fresh_lb_pair, 100).unwrap());
+        assert!(revalidate(&fresh_lb_pair, BASIS_POINT_MAX as u16 + 1).is_err());
+    }
+
+    #[test]
+    fn test_quote_exact_out_verified() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+
+        for swap_for_y in [true, false] {
+            let quote = quote_exact_out_verified(
+                lb_pair_pubkey,
+                &lb_pair,
+                2_500,
+                swap_for_y,
+                bin_arrays.clone(),
+                None,
+                &Clock::default(),
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap();
+
+            let exact_in_quote = quote_exact_in(
+                lb_pair_pubkey,
+                &lb_pair,
+                quote.amount_in,
+                swap_for_y,
+                bin_arrays.clone(),
+                None,
+                &Clock::default(),
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap();
+            assert!(exact_in_quote.amount_out >= 2_500);
+        }
+    }
+
+    #[test]
+    fn test_raise_exact_out_quote() {
+        use crate::token_2022::tests::new_transfer_fee_mint_account;
+
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        // 10% transfer fee on X, 5% on Y
+        let mint_x_account = new_transfer_fee_mint_account(1_000, u64::MAX);
+        let mint_y_account = new_transfer_fee_mint_account(500, u64::MAX);
+
+        for swap_for_y in [true, false] {
+            let exact_in_quote = |amount_in: u64| {
+                quote_exact_in(
+                    lb_pair_pubkey,
+                    &lb_pair,
+                    amount_in,
+                    swap_for_y,
+                    bin_arrays.clone(),
+                    None,
+                    &Clock::default(),
+                    &mint_x_account,
+                    &mint_y_account,
+                )
+            };
+
+            let quote = quote_exact_out(
+                lb_pair_pubkey,
+                &lb_pair,
+                2_500,
+                swap_for_y,
+                bin_arrays.clone(),
+                None,
+                &Clock::default(),
+                &mint_x_account,
+                &mint_y_account,
+            )
+            .unwrap();
+
+            // Short amount in, with the fields of the exact out quote left over
+            let short_quote = SwapExactOutQuote {
+                amount_in: quote.amount_in / 2,
+                ..quote.clone()
+            };
+            assert!(exact_in_quote(short_quote.amount_in).unwrap().amount_out < 2_500);
+
+            let raised_quote =
+                raise_exact_out_quote(short_quote.clone(), 2_500, exact_in_quote).unwrap();
+            assert!(raised_quote.amount_in > short_quote.amount_in);
+            assert!(exact_in_quote(raised_quote.amount_in).unwrap().amount_out >= 2_500);
+            assert!(
+                exact_in_quote(raised_quote.amount_in - 1)
+                    .unwrap()
+                    .amount_out
+                    < 2_500
+            );
+
+            let raised_exact_in_quote = exact_in_quote(raised_quote.amount_in).unwrap();
+            assert_eq!(raised_quote.fee, raised_exact_in_quote.fee);
+            assert_eq!(
+                raised_quote.amount_into_pool,
+                raised_exact_in_quote.amount_into_pool
+            );
+            assert_eq!(
+                raised_quote.amount_out_of_pool,
+                raised_exact_in_quote.amount_out_of_pool
+            );
+
+            // A quote meeting the amount out is kept as is
+            assert_eq!(
+                raise_exact_out_quote(quote.clone(), 2_500, exact_in_quote).unwrap(),
+                quote
+            );
+        }
+    }
+
+    #[test]
+    fn test_static_fee_quote() {
+        let (lb_pair_pubkey, mut lb_pair, bin_arrays) = new_test_pool();
+        lb_pair.parameters.variable_fee_control = 0;
+        lb_pair.parameters.max_volatility_accumulator = 350_000;
+        let mint_account = new_test_mint_account();
+
+        // Accumulator left untouched by the static fee fast path
+        let cursor = QuoteCursor::new(
+            lb_pair_pubkey,
+            &lb_pair,
+            true,
+            bin_arrays.clone(),
+            &Clock::default(),
+        )
+        .unwrap();
+        let cursor = resume_quote_exact_in(
+            cursor,
+            3_500,
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+        assert_eq!(cursor.lb_pair.v_parameters.volatility_accumulator, 0);
+
+        // Same fee as a pair with variable fee control, but no volatility
+        let mut no_volatility_lb_pair = lb_pair;
+        no_volatility_lb_pair.parameters.variable_fee_control = 1;
+        no_volatility_lb_pair.parameters.max_volatility_accumulator = 0;
+
+        for swap_for_y in [true, false] {
+            assert_eq!(
+                quote_exact_in(
+                    lb_pair_pubkey,
+                    &lb_pair,
+                    3_500,
+                    swap_for_y,
+                    bin_arrays.clone(),
+                    None,
+                    &Clock::default(),
+                    &mint_account,
+                    &mint_account,
+                )
+                .unwrap(),
+                quote_exact_in(
+                    lb_pair_pubkey,
+                    &no_volatility_lb_pair,
+                    3_500,
+                    swap_for_y,
+                    bin_arrays.clone(),
+                    None,
+                    &Clock::default(),
+                    &mint_account,
+                    &mint_account,
+                )
+                .unwrap()
+            );
+
+            assert_eq!(
+                quote_exact_out(
+                    lb_pair_pubkey,
+                    &lb_pair,
+                    3_500,
+                    swap_for_y,
+                    bin_arrays.clone(),
+                    None,
+                    &Clock::default(),
+                    &mint_account,
+                    &mint_account,
+                )
+                .unwrap(),
+                quote_exact_out(
+                    lb_pair_pubkey,
+                    &no_volatility_lb_pair,
+                    3_500,
+                    swap_for_y,
+                    bin_arrays.clone(),
+                    None,
+                    &Clock::default(),
+                    &mint_account,
+                    &mint_account,
+                )
+                .unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_quote_missing_bin_array() {
+        let (lb_pair_pubkey, lb_pair, mut bin_arrays) = new_test_pool();
+        bin_arrays.remove(&derive_bin_array_pda(lb_pair_pubkey, -1).0);
+        let mint_account = new_test_mint_account();
+
+        // Bin 0 is swapped, then bin array -1 is needed
+        let err = quote_exact_in(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            true,
+            bin_arrays.clone(),
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap_err();
+
+        let Some(QuoteError::MissingBinArray {
+            bin_array_index,
+            consumed_in,
+            produced_out,
+            last_bin_id,
+        }) = err.downcast_ref::<QuoteError>().copied()
+        else {
+            panic!("Unexpected error {}", err);
+        };
+        assert_eq!(bin_array_index, -1);
+        assert!(consumed_in > 1_000);
+        assert_eq!(produced_out, 1_000);
+        assert_eq!(last_bin_id, -1);
+
+        let err = quote_exact_out(
+            lb_pair_pubkey,
+            &lb_pair,
+            2_500,
+            true,
+            bin_arrays,
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap_err();
+
+        let Some(QuoteError::MissingBinArray {
+            bin_array_index,
+            produced_out,
+            last_bin_id,
+            ..
+        }) = err.downcast_ref::<QuoteError>().copied()
+        else {
+            panic!("Unexpected error {}", err);
+        };
+        assert_eq!(bin_array_index, -1);
+        assert_eq!(produced_out, 1_000);
+        assert_eq!(last_bin_id, -1);
+    }
+
+    #[test]
+    fn test_theoretical_max_bins_reachable() {
+        let (_, mut lb_pair, _) = new_test_pool();
+
+        // Active bin 0 in bin array 0, and bin array -1
+        assert_eq!(
+            theoretical_max_bins_reachable(&lb_pair, None, true).unwrap(),
+            1 + MAX_BIN_PER_ARRAY as u32
+        );
+        assert_eq!(
+            theoretical_max_bins_reachable(&lb_pair, None, false).unwrap(),
+            MAX_BIN_PER_ARRAY as u32
+        );
+
+        // Every bin array of the internal bitmap, more than a single search can take
+        lb_pair.bin_array_bitmap = [u64::MAX; 16];
+        let (_, max_bitmap_id) = LbPair::bitmap_range();
+        assert_eq!(
+            theoretical_max_bins_reachable(&lb_pair, None, false).unwrap(),
+            (max_bitmap_id as u32 + 1) * MAX_BIN_PER_ARRAY as u32
+        );
+    }
+
+    #[test]
+    fn test_amount_to_move_n_bins() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+        let clock = Clock::default();
+
+        let last_bin_id = |amount_in, swap_for_y| {
+            quote_exact_in_with_breakdown(
+                lb_pair_pubkey,
+                &lb_pair,
+                amount_in,
+                swap_for_y,
+                bin_arrays.clone(),
+                None,
+                &clock,
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap()
+            .breakdown
+            .last()
+            .unwrap()
+            .bin_id
+        };
+
+        // Bins 0 and -1 are in different bin arrays
+        for (swap_for_y, n_bins, expected_bin_id) in [(false, 2, 2), (true, 2, -2), (true, 1, -1)] {
+            let amount_in = amount_to_move_n_bins(
+                lb_pair_pubkey,
+                &lb_pair,
+                &bin_arrays,
+                n_bins,
+                swap_for_y,
+                &clock,
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap();
+
+            assert_eq!(last_bin_id(amount_in, swap_for_y), expected_bin_id);
+            assert_eq!(
+                last_bin_id(amount_in - 1, swap_for_y),
+                expected_bin_id + if swap_for_y { 1 } else { -1 }
+            );
+        }
+
+        assert_eq!(
+            amount_to_move_n_bins(
+                lb_pair_pubkey,
+                &lb_pair,
+                &bin_arrays,
+                0,
+                true,
+                &clock,
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap(),
+            0
+        );
+
+        let err = amount_to_move_n_bins(
+            lb_pair_pubkey,
+            &lb_pair,
+            &bin_arrays,
+            80,
+            false,
+            &clock,
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<QuoteError>(),
+            Some(QuoteError::MissingBinArray {
+                bin_array_index: 1,
+                consumed_in: _,
+                produced_out: 4_000,
+                last_bin_id: 70,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_final_bin_partially_filled() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+        let clock = Clock::default();
+
+        let quote_at = |amount_in| {
+            quote_exact_in(
+                lb_pair_pubkey,
+                &lb_pair,
+                amount_in,
+                true,
+                bin_arrays.clone(),
+                None,
+                &clock,
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap()
+        };
+
+        assert!(quote_at(100).final_bin_partially_filled);
+
+        // Exactly drains bin 0
+        let amount_in = amount_to_next_bin_array(
+            &lb_pair,
+            &bin_arrays[&derive_bin_array_pda(lb_pair_pubkey, 0).0],
+            true,
+            &clock,
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+        let quote = quote_at(amount_in);
+        assert_eq!(quote.amount_out, 1_000);
+        assert!(!quote.final_bin_partially_filled);
+
+        // Steps into bin -1
+        assert!(quote_at(amount_in + 100).final_bin_partially_filled);
+    }
+
+    #[test]
+    fn test_quote_exact_in_single_array() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+        let clock = Clock::default();
+        let active_bin_array = bin_arrays[&derive_bin_array_pda(lb_pair_pubkey, 0).0];
+
+        // Stays within the active bin
+        let (quote, unfilled_amount_in) = quote_exact_in_single_array(
+            &lb_pair,
+            &active_bin_array,
+            100,
+            true,
+            &clock,
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        assert_eq!(unfilled_amount_in, 0);
+        assert_eq!(
+            quote,
+            quote_exact_in(
+                lb_pair_pubkey,
+                &lb_pair,
+                100,
+                true,
+                bin_arrays.clone(),
+                None,
+                &clock,
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap()
+        );
+
+        // Only bin 0 of bin array 0 holds token Y, the rest spills into bin array -1
+        let (quote, unfilled_amount_in) = quote_exact_in_single_array(
+            &lb_pair,
+            &active_bin_array,
+            2_500,
+            true,
+            &clock,
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        let amount_to_drain = amount_to_next_bin_array(
+            &lb_pair,
+            &active_bin_array,
+            true,
+            &clock,
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+
+        assert_eq!(quote.amount_out, 1_000);
+        assert_eq!(unfilled_amount_in, 2_500 - amount_to_drain);
+
+        let mut lb_pair = lb_pair;
+        lb_pair.active_id = -1;
+        assert!(quote_exact_in_single_array(
+            &lb_pair,
+            &active_bin_array,
+            100,
+            true,
+            &clock,
+            &mint_account,
+            &mint_account,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_quote_exact_in_max_slippage() {
+        let (lb_pair_pubkey, lb_pair, bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
+        let clock = Clock::default();
+
+        let quote_with_max_slippage = |max_slippage_bps| {
+            quote_exact_in_max_slippage(
+                lb_pair_pubkey,
+                &lb_pair,
+                2_500,
+                true,
+                bin_arrays.clone(),
+                None,
+                max_slippage_bps,
+                &clock,
+                &mint_account,
+                &mint_account,
+            )
+        };
+
+        let quote = quote_with_max_slippage(50).unwrap();
+        assert_eq!(
+            quote,
+            quote_exact_in(
+                lb_pair_pubkey,
+                &lb_pair,
+                2_500,
+                true,
+                bin_arrays.clone(),
+                None,
+                &clock,
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap()
+        );
+
+        // Bin 0 is swapped at the starting price, and the same amount in bin -1 at 10 bps lower, which is 5 bps of slippage
+        let err = quote_with_max_slippage(2).unwrap_err();
+        match err.downcast_ref::<QuoteError>() {
+            Some(QuoteError::SlippageExceeded {
+                at_bin_id,
+                realized_bps,
+            }) => {
+                assert_eq!(*at_bin_id, -1);
+                assert!(*realized_bps > 2 && *realized_bps <= 5);
+            }
+            _ => panic!("Unexpected error {}", err),
+        }
+    }
+
+    #[test]
+    fn test_best_quote_exact_in() {
+        let (lb_pair_pubkey_a, lb_pair_a, bin_arrays_a) = new_test_pool();
+        let (lb_pair_pubkey_b, lb_pair_b, bin_arrays_b) = new_test_pool();
+        let (lb_pair_pubkey_c, mut lb_pair_c, bin_arrays_c) = new_test_pool();
+        // Pool C charges 5x the base fee
+        lb_pair_c.parameters.base_factor = 50_000;
+        let mint_account = new_test_mint_account();
+        let clock = Clock::default();
+
+        let pool_a = PoolQuoteAccounts {
+            lb_pair_pubkey: lb_pair_pubkey_a,
+            lb_pair: &lb_pair_a,
+            bin_arrays: &bin_arrays_a,
+            bitmap_extension: None,
+        };
+        let pool_b = PoolQuoteAccounts {
+            lb_pair_pubkey: lb_pair_pubkey_b,
+            lb_pair: &lb_pair_b,
+            bin_arrays: &bin_arrays_b,
+            bitmap_extension: None,
+        };
+        let pool_c = PoolQuoteAccounts {
+            lb_pair_pubkey: lb_pair_pubkey_c,
+            lb_pair: &lb_pair_c,
+            bin_arrays: &bin_arrays_c,
+            bitmap_extension: None,
+        };
+
+        // Pool A and B are identical, so their quotes tie, and the lower pair pubkey wins in any order
+        let lower_pubkey = lb_pair_pubkey_a.min(lb_pair_pubkey_b);
+        for pools in [[pool_a, pool_b, pool_c], [pool_c, pool_b, pool_a]] {
+            let (index, quote) =
+                best_quote_exact_in(&pools, 1_500, true, &clock, &mint_account, &mint_account)
+                    .unwrap();
+            assert_eq!(pools[index].lb_pair_pubkey, lower_pubkey);
+            assert_eq!(
+                Some(quote),
+                quote_pool_exact_in(&pool_a, 1_500, true, &clock, &mint_account, &mint_account)
+                    .unwrap()
+            );
+        }
+
+        // No pool has the liquidity
+        assert!(best_quote_exact_in(
+            &[pool_a, pool_b, pool_c],
+            10_000,
+            true,
+            &clock,
+            &mint_account,
+            &mint_account
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_best_quote_exact_in_fee_tie_break() {
+        // Single bin pool with token Y in the active bin
+        let new_single_bin_pool = |active_id: i32, base_factor: u16| {
+            let lb_pair_pubkey = Pubkey::new_unique();
+            let mut lb_pair = new_test_lb_pair(10, base_factor);
+            lb_pair.active_id = active_id;
+            let offset = LbPair::get_bin_array_offset(0);
+            lb_pair.bin_array_bitmap[offset / 64] |= 1 << (offset % 64);
+
+            let mut bin_array = new_test_bin_array(0);
+            bin_array.lb_pair = lb_pair_pubkey;
+            bin_array.get_bin_mut(active_id).unwrap().amount_y = 1_000_000;
+            let bin_arrays =
+                HashMap::from([(derive_bin_array_pda(lb_pair_pubkey, 0).0, bin_array)]);
+
+            (lb_pair_pubkey, lb_pair, bin_arrays)
+        };
+
+        // Pool A charges 0.19% at a price 1 bin higher, pool B charges 0.1%, and both pay out the same amount.
+        // Pool A has the lower pubkey, so only the fee rule picks pool B.
+        let (lb_pair_pubkey_a, lb_pair_a, bin_arrays_a) = new_single_bin_pool(1, 19_000);
+        let (lb_pair_pubkey_b, lb_pair_b, bin_arrays_b) = new_single_bin_pool(0, 10_000);
+        assert!(lb_pair_pubkey_a < lb_pair_pubkey_b);
+        let mint_account = new_test_mint_account();
+        let clock = Clock::default();
+
+        let pool_a = PoolQuoteAccounts {
+            lb_pair_pubkey: lb_pair_pubkey_a,
+            lb_pair: &lb_pair_a,
+            bin_arrays: &bin_arrays_a,
+            bitmap_extension: None,
+        };
+        let pool_b = PoolQuoteAccounts {
+            lb_pair_pubkey: lb_pair_pubkey_b,
+            lb_pair: &lb_pair_b,
+            bin_arrays: &bin_arrays_b,
+            bitmap_extension: None,
+        };
+
+        let quote_a =
+            quote_pool_exact_in(&pool_a, 10_000, true, &clock, &mint_account, &mint_account)
+                .unwrap()
+                .unwrap();
+        let quote_b =
+            quote_pool_exact_in(&pool_b, 10_000, true, &clock, &mint_account, &mint_account)
+                .unwrap()
+                .unwrap();
+        assert_eq!(quote_a.amount_out, quote_b.amount_out);
+        assert!(quote_a.fee > quote_b.fee);
+
+        for pools in [[pool_a, pool_b], [pool_b, pool_a]] {
+            let (index, quote) =
+                best_quote_exact_in(&pools, 10_000, true, &clock, &mint_account, &mint_account)
+                    .unwrap();
+            assert_eq!(pools[index].lb_pair_pubkey, lb_pair_pubkey_b);
+            assert_eq!(quote, quote_b);
+        }
+    }
+
+    #[test]
+    fn test_optimal_split() {
+        let (lb_pair_pubkey_a, lb_pair_a, bin_arrays_a) = new_test_pool();
+        let (lb_pair_pubkey_b, mut lb_pair_b, bin_arrays_b) = new_test_pool();
+        // Pool B charges 5x the base fee
+        lb_pair_b.parameters.base_factor = 50_000;
+        let mint_account = new_test_mint_account();
+        let clock = Clock::default();
+
+        let pool_a = PoolQuoteAccounts {
+            lb_pair_pubkey: lb_pair_pubkey_a,
+            lb_pair: &lb_pair_a,
+            bin_arrays: &bin_arrays_a,
+            bitmap_extension: None,
+        };
+        let pool_b = PoolQuoteAccounts {
+            lb_pair_pubkey: lb_pair_pubkey_b,
+            lb_pair: &lb_pair_b,
+            bin_arrays: &bin_arrays_b,
+            bitmap_extension: None,
+        };
+
+        let amount_out = |pool: &PoolQuoteAccounts, amount_in| {
+            quote_exact_in(
+                pool.lb_pair_pubkey,
+                pool.lb_pair,
+                amount_in,
+                true,
+                pool.bin_arrays.clone(),
+                None,
+                &clock,
+                &mint_account,
+                &mint_account,
+            )
+            .map(|quote| quote.amount_out)
+            .ok()
+        };
+
+        for total_amount_in in [500, 2_500, 5_000] {
+            let (amount_to_a, amount_to_b, total_amount_out) = optimal_split(
+                pool_a,
+                pool_b,
+                total_amount_in,
+                true,
+                &clock,
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap();
+
+            assert_eq!(amount_to_a + amount_to_b, total_amount_in);
+            assert_eq!(
+                total_amount_out,
+                amount_out(&pool_a, amount_to_a).unwrap()
+                    + amount_out(&pool_b, amount_to_b).unwrap()
+            );
+
+            let best_amount_out = (0..=total_amount_in)
+                .step_by(10)
+                .filter_map(|amount_to_a| {
+                    Some(
+                        amount_out(&pool_a, amount_to_a)?
+                            + amount_out(&pool_b, total_amount_in - amount_to_a)?,
+                    )
+                })
+                .max()
+                .unwrap();
+            // Optimal within the rounding of the bin swaps
+            assert!(total_amount_out + 2 >= best_amount_out);
+        }
+
+        // Small swap goes to the cheaper pool A only
+        let (amount_to_a, _, _) = optimal_split(
+            pool_a,
+            pool_b,
+            500,
+            true,
+            &clock,
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+        assert_eq!(amount_to_a, 500);
+
+        // Each pool holds 4_000 token Y
+        assert!(optimal_split(
+            pool_a,
+            pool_b,
+            10_000,
+            true,
+            &clock,
+            &mint_account,
+            &mint_account,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_amount_to_next_bin_array() {
+        let (lb_pair_pubkey, lb_pair, mut bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
 
-        let clock_state: Clock = bincode::deserialize(clock_account.data.as_ref())?;
+        let active_bin_array = bin_arrays[&derive_bin_array_pda(lb_pair_pubkey, 0).0];
+        bin_arrays.remove(&derive_bin_array_pda(lb_pair_pubkey, -1).0);
 
-        Ok(clock_state)
-    }
+        // Only bin 0 of bin array 0 holds token Y
+        let amount_in = amount_to_next_bin_array(
+            &lb_pair,
+            &active_bin_array,
+            true,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
 
-    #[tokio::test]
-    async fn test_swap_quote_exact_out() {
-        // RPC client. No gPA is required.
-        let rpc_client = RpcClient::new(Cluster::Mainnet.url().to_string());
+        let quote = quote_exact_in(
+            lb_pair_pubkey,
+            &lb_pair,
+            amount_in,
+            true,
+            bin_arrays.clone(),
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+        assert_eq!(quote.amount_out, 1_000);
 
-        let sol_usdc = Pubkey::from_str("HTvjzsfX3yU6BUodCjZ5vZkUrAxMDTrBs3CJaq43ashR").unwrap();
+        let err = quote_exact_in(
+            lb_pair_pubkey,
+            &lb_pair,
+            amount_in + 1,
+            true,
+            bin_arrays,
+            None,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<QuoteError>(),
+            Some(QuoteError::MissingBinArray {
+                bin_array_index: -1,
+                ..
+            })
+        ));
 
-        let lb_pair_account = rpc_client.get_account(&sol_usdc).await.unwrap();
+        // Bins 0 to 3 hold token X
+        let amount_in = amount_to_next_bin_array(
+            &lb_pair,
+            &active_bin_array,
+            false,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+        assert!(amount_in > 4_000);
+    }
 
-        let lb_pair = LbPairAccount::deserialize(&lb_pair_account.data).unwrap().0;
+    #[test]
+    fn test_quote_exact_in_against_rational() {
+        use num_bigint::BigUint;
+        use num_traits::ToPrimitive;
 
-        let mut mint_accounts = rpc_client
-            .get_multiple_accounts(&[lb_pair.token_x_mint, lb_pair.token_y_mint])
-            .await
-            .unwrap();
+        let mint_account = new_test_mint_account();
 
-        let mint_x_account = mint_accounts[0].take().unwrap();
-        let mint_y_account = mint_accounts[1].take().unwrap();
+        for bin_step in [1u16, 10, 100] {
+            for active_id in [-1_000, -7, 0, 13, 1_000] {
+                let lb_pair_pubkey = Pubkey::new_unique();
+                let mut lb_pair = new_test_lb_pair(bin_step, 10_000);
+                lb_pair.active_id = active_id;
 
-        // 3 bin arrays to left, and right is enough to cover most of the swap, and stay under 1.4m CU constraint.
-        // Get 3 bin arrays to the left from the active bin
-        let left_bin_array_pubkeys =
-            get_bin_array_pubkeys_for_swap(sol_usdc, &lb_pair, None, true, 3).unwrap();
+                let bin_array_index = BinArray::bin_id_to_bin_array_index(active_id).unwrap();
+                let offset = LbPair::get_bin_array_offset(bin_array_index);
+                lb_pair.bin_array_bitmap[offset / 64] |= 1 << (offset % 64);
+                let bin_array_pubkey =
+                    derive_bin_array_pda(lb_pair_pubkey, bin_array_index.into()).0;
 
-        // Get 3 bin arrays to the right the from active bin
-        let right_bin_array_pubkeys =
-            get_bin_array_pubkeys_for_swap(sol_usdc, &lb_pair, None, false, 3).unwrap();
+                let price = BigUint::from(get_price_from_id(active_id, bin_step).unwrap());
+                let fee_rate = BigUint::from(lb_pair.get_total_fee().unwrap());
+                let fee_precision = BigUint::from(FEE_PRECISION);
+                let one = BigUint::from(ONE);
 
-        // Fetch bin arrays
-        let bin_array_pubkeys = left_bin_array_pubkeys
-            .into_iter()
-            .chain(right_bin_array_pubkeys.into_iter())
-            .collect::<Vec<Pubkey>>();
+                for amount_in in [1u64, 7, 999, 123_456_789, 1_000_000_000_003] {
+                    for swap_for_y in [true, false] {
+                        // Fee rounds up, amount out rounds down
+                        let fee = (BigUint::from(amount_in) * &fee_rate + &fee_precision
+                            - BigUint::from(1u8))
+                            / &fee_precision;
+                        let amount_in_after_fee = BigUint::from(amount_in) - &fee;
+                        let amount_out = if swap_for_y {
+                            amount_in_after_fee * &price / &one
+                        } else {
+                            amount_in_after_fee * &one / &price
+                        };
 
-        let accounts = rpc_client
-            .get_multiple_accounts(&bin_array_pubkeys)
-            .await
-            .unwrap();
+                        // Twice the amount out in the bin, so the swap is within the active bin
+                        let liquidity = amount_out.to_u64().unwrap() * 2 + 1;
+                        let mut bin_array = new_test_bin_array(bin_array_index.into());
+                        let bin = bin_array.get_bin_mut(active_id).unwrap();
+                        if swap_for_y {
+                            bin.amount_y = liquidity;
+                        } else {
+                            bin.amount_x = liquidity;
+                        }
 
-        let bin_arrays = accounts
-            .into_iter()
-            .zip(bin_array_pubkeys.into_iter())
-            .map(|(account, key)| {
-                (
-                    key,
-                    BinArrayAccount::deserialize(&account.unwrap().data)
-                        .unwrap()
-                        .0,
-                )
-            })
-            .collect::<HashMap<_, _>>();
+                        let quote = quote_exact_in(
+                            lb_pair_pubkey,
+                            &lb_pair,
+                            amount_in,
+                            swap_for_y,
+                            HashMap::from([(bin_array_pubkey, bin_array)]),
+                            None,
+                            &Clock::default(),
+                            &mint_account,
+                            &mint_account,
+                        )
+                        .unwrap();
 
-        let usdc_token_multiplier = 1_000_000.0;
-        let sol_token_multiplier = 1_000_000_000.0;
+                        assert_eq!(BigUint::from(quote.fee), fee);
+                        assert_eq!(
+                            BigUint::from(quote.amount_out),
+                            amount_out,
+                            "bin step {} active id {} amount in {} swap for y {}",
+                            bin_step,
+                            active_id,
+                            amount_in,
+                            swap_for_y
+                        );
+                    }
+                }
+            }
+        }
+    }
 
-        let out_sol_amount = 1_000_000_000;
-        let clock = get_clock(rpc_client).await.unwrap();
+    #[test]
+    fn test_quote_with_added_liquidity() {
+        let (lb_pair_pubkey, lb_pair, mut bin_arrays) = new_test_pool();
+        let mint_account = new_test_mint_account();
 
-        let quote_result = quote_exact_out(
-            sol_usdc,
+        let bin_array_pubkey = derive_bin_array_pda(lb_pair_pubkey, 0).0;
+        let bin = bin_arrays
+            .get_mut(&bin_array_pubkey)
+            .unwrap()
+            .get_bin_mut(1)
+            .unwrap();
+        bin.liquidity_supply = 1_000 << SCALE_OFFSET;
+        let mut bin_array = new_test_bin_array(1);
+        bin_array.lb_pair = lb_pair_pubkey;
+        bin_arrays.insert(derive_bin_array_pda(lb_pair_pubkey, 1).0, bin_array);
+
+        let quote = quote_exact_in(
+            lb_pair_pubkey,
             &lb_pair,
-            out_sol_amount,
+            2_500,
             false,
             bin_arrays.clone(),
             None,
-            &clock,
-            &mint_x_account,
-            &mint_y_account,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
         )
         .unwrap();
 
-        let in_amount = quote_result.amount_in + quote_result.fee;
+        let quote_with_added_liquidity = |added: &[(i32, u64, u64)]| {
+            quote_with_added_liquidity(
+                lb_pair_pubkey,
+                &lb_pair,
+                bin_arrays.clone(),
+                added,
+                2_500,
+                false,
+                None,
+                &Clock::default(),
+                &mint_account,
+                &mint_account,
+            )
+        };
 
-        println!(
-            "{} USDC -> exact 1 SOL",
-            in_amount as f64 / usdc_token_multiplier
-        );
+        assert_eq!(quote_with_added_liquidity(&[]).unwrap(), quote);
 
-        let quote_result = quote_exact_in(
-            sol_usdc,
+        // Swap is filled by bin 1 instead of reaching bin 2
+        let deepened_quote = quote_with_added_liquidity(&[(1, 1_000, 0)]).unwrap();
+        assert!(deepened_quote.amount_out > quote.amount_out);
+
+        // Liquidity in bin array 1, which had no liquidity before, is reached after bin array 0 is drained
+        let filled_out = quote_exact_in(
+            lb_pair_pubkey,
             &lb_pair,
-            in_amount,
+            10_000,
             false,
             bin_arrays.clone(),
             None,
-            &clock,
-            &mint_x_account,
-            &mint_y_account,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
         )
+        .unwrap_err()
+        .downcast_ref::<QuoteError>()
+        .map(|err| match err {
+            QuoteError::InsufficientLiquidity { filled_out } => *filled_out,
+            _ => panic!("Unexpected error {}", err),
+        })
         .unwrap();
+        assert_eq!(filled_out, 4_000);
 
-        println!(
-            "{} USDC -> {} SOL",
-            in_amount as f64 / usdc_token_multiplier,
-            quote_result.amount_out as f64 / sol_token_multiplier
+        let mut added_lb_pair = lb_pair;
+        let mut added_bin_arrays = bin_arrays.clone();
+        add_liquidity(
+            lb_pair_pubkey,
+            &mut added_lb_pair,
+            &mut added_bin_arrays,
+            None,
+            &[(70, 10_000, 0)],
+        )
+        .unwrap();
+        let offset = LbPair::get_bin_array_offset(1);
+        assert_ne!(
+            added_lb_pair.bin_array_bitmap[offset / 64] & (1 << (offset % 64)),
+            0
         );
 
-        let out_usdc_amount = 200_000_000;
-
-        let quote_result = quote_exact_out(
-            sol_usdc,
-            &lb_pair,
-            out_usdc_amount,
-            true,
-            bin_arrays.clone(),
+        let SwapExactInQuoteWithBreakdown { quote, breakdown } = quote_exact_in_with_breakdown(
+            lb_pair_pubkey,
+            &added_lb_pair,
+            10_000,
+            false,
+            added_bin_arrays,
             None,
-            &clock,
-            &mint_x_account,
-            &mint_y_account,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
         )
         .unwrap();
+        assert!(quote.amount_out > filled_out);
+        assert_eq!(breakdown.last().unwrap().bin_id, 70);
 
-        let in_amount = quote_result.amount_in + quote_result.fee;
+        // Bin array not provided
+        assert!(quote_with_added_liquidity(&[(140, 1_000, 0)]).is_err());
 
-        println!(
-            "{} SOL -> exact 200 USDC",
-            in_amount as f64 / sol_token_multiplier
+        // Liquidity share follows the bin liquidity valued in token Y
+        let mut bin: Bin = bytemuck::Zeroable::zeroed();
+        let price = get_price_from_id(1, 10).unwrap();
+        let in_liquidity = Bin::get_liquidity(1_000, 0, price).unwrap();
+        assert_eq!(in_liquidity, 1_000 * price);
+        assert_eq!(
+            bin.get_liquidity_share(in_liquidity, price).unwrap(),
+            in_liquidity
         );
+        bin.deposit(1_000, 0, in_liquidity).unwrap();
 
-        let quote_result = quote_exact_in(
-            sol_usdc,
+        // Bin amount doubled by swaps, each share is worth twice as much
+        bin.amount_x = 2_000;
+        let in_liquidity = Bin::get_liquidity(0, 500, price).unwrap();
+        let liquidity_share = bin.get_liquidity_share(in_liquidity, price).unwrap();
+        assert_eq!(liquidity_share, in_liquidity / 2);
+    }
+
+    #[test]
+    fn test_quote_with_added_liquidity_in_bitmap_extension() {
+        let lb_pair_pubkey = Pubkey::new_unique();
+        let mut lb_pair = new_test_lb_pair(10, 10_000);
+        // Last bin of bin array 511, the last bin array of the internal bitmap
+        lb_pair.active_id = 35_839;
+        let offset = LbPair::get_bin_array_offset(511);
+        lb_pair.bin_array_bitmap[offset / 64] |= 1 << (offset % 64);
+
+        let mut bin_arrays = HashMap::new();
+        for bin_array_index in [511, 512] {
+            let mut bin_array = new_test_bin_array(bin_array_index.into());
+            bin_array.lb_pair = lb_pair_pubkey;
+            bin_arrays.insert(
+                derive_bin_array_pda(lb_pair_pubkey, bin_array_index.into()).0,
+                bin_array,
+            );
+        }
+        bin_arrays
+            .get_mut(&derive_bin_array_pda(lb_pair_pubkey, 511).0)
+            .unwrap()
+            .get_bin_mut(35_839)
+            .unwrap()
+            .amount_x = 1;
+
+        let mut bitmap_extension: BinArrayBitmapExtension = bytemuck::Zeroable::zeroed();
+        bitmap_extension.lb_pair = lb_pair_pubkey;
+        let mint_account = new_test_mint_account();
+
+        // ~3.6e15 token Y per token X, enough to buy more than the single token X of bin 35839
+        let amount_in = 10_000_000_000_000_000;
+        assert!(quote_exact_in(
+            lb_pair_pubkey,
             &lb_pair,
-            in_amount,
-            true,
-            bin_arrays,
+            amount_in,
+            false,
+            bin_arrays.clone(),
+            Some(&bitmap_extension),
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .is_err());
+
+        // Bin array 512 is tracked by the bitmap extension
+        assert!(lb_pair.is_overflow_default_bin_array_bitmap(512));
+        assert!(add_liquidity(
+            lb_pair_pubkey,
+            &mut lb_pair.clone(),
+            &mut bin_arrays.clone(),
             None,
-            &clock,
-            &mint_x_account,
-            &mint_y_account,
+            &[(35_840, 1_000, 0)],
+        )
+        .is_err());
+
+        let mut added_lb_pair = lb_pair;
+        let mut added_bin_arrays = bin_arrays.clone();
+        let mut added_bitmap_extension = bitmap_extension;
+        add_liquidity(
+            lb_pair_pubkey,
+            &mut added_lb_pair,
+            &mut added_bin_arrays,
+            Some(&mut added_bitmap_extension),
+            &[(35_840, 1_000, 0)],
         )
         .unwrap();
+        assert_eq!(added_lb_pair.bin_array_bitmap, lb_pair.bin_array_bitmap);
+        assert!(added_bitmap_extension.bit(512).unwrap());
 
-        println!(
-            "{} SOL -> {} USDC",
-            in_amount as f64 / sol_token_multiplier,
-            quote_result.amount_out as f64 / usdc_token_multiplier
+        let SwapExactInQuoteWithBreakdown { quote, breakdown } = quote_exact_in_with_breakdown(
+            lb_pair_pubkey,
+            &added_lb_pair,
+            amount_in,
+            false,
+            added_bin_arrays,
+            Some(&added_bitmap_extension),
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
+        )
+        .unwrap();
+        assert!(quote.amount_out > 1);
+        assert_eq!(breakdown.last().unwrap().bin_id, 35_840);
+
+        assert_eq!(
+            quote_with_added_liquidity(
+                lb_pair_pubkey,
+                &lb_pair,
+                bin_arrays,
+                &[(35_840, 1_000, 0)],
+                amount_in,
+                false,
+                Some(&bitmap_extension),
+                &Clock::default(),
+                &mint_account,
+                &mint_account,
+            )
+            .unwrap(),
+            quote
         );
     }
 
-    #[tokio::test]
-    async fn test_swap_quote_exact_in() {
-        // RPC client. No gPA is required.
-        let rpc_client = RpcClient::new(Cluster::Mainnet.url().to_string());
+    #[test]
+    fn test_bin_swap_exact_out() {
+        let lb_pair = new_test_lb_pair(10, 10_000);
+        let mut bin: Bin = bytemuck::Zeroable::zeroed();
+        bin.amount_y = 1_000;
+        let price = get_price_from_id(0, 10).unwrap();
 
-        let sol_usdc = Pubkey::from_str("HTvjzsfX3yU6BUodCjZ5vZkUrAxMDTrBs3CJaq43ashR").unwrap();
-
-        let lb_pair_account = rpc_client.get_account(&sol_usdc).await.unwrap();
+        // Filled within the bin
+        let mut partially_swapped_bin = bin;
+        let result = partially_swapped_bin
+            .swap_exact_out(400, price, true, &lb_pair, None)
+            .unwrap();
+        assert!(result.is_exact_out_amount);
+        assert_eq!(result.amount_out, 400);
+        assert_eq!(result.fee, lb_pair.compute_fee(400).unwrap());
+        assert_eq!(partially_swapped_bin.amount_y, 600);
+        assert_eq!(
+            partially_swapped_bin.amount_x,
+            result.amount_in_with_fees - result.fee
+        );
 
-        let lb_pair = LbPairAccount::deserialize(&lb_pair_account.data).unwrap().0;
+        // Same amount in as swapping exact in with the amount in
+        let mut exact_in_bin = bin;
+        let exact_in_result = exact_in_bin
+            .swap(result.amount_in_with_fees, price, true, &lb_pair, None)
+            .unwrap();
+        assert!(!exact_in_result.is_exact_out_amount);
+        assert_eq!(exact_in_result.amount_out, 400);
 
-        let mut mint_accounts = rpc_client
-            .get_multiple_accounts(&[lb_pair.token_x_mint, lb_pair.token_y_mint])
-            .await
+        // Bin drained, the rest has to be filled by the next bins
+        let mut drained_bin = bin;
+        let result = drained_bin
+            .swap_exact_out(1_500, price, true, &lb_pair, None)
             .unwrap();
+        assert!(!result.is_exact_out_amount);
+        assert_eq!(result.amount_out, 1_000);
+        assert_eq!(drained_bin.amount_y, 0);
+    }
 
-        let mint_x_account = mint_accounts[0].take().unwrap();
-        let mint_y_account = mint_accounts[1].take().unwrap();
+    #[test]
+    fn test_estimate_rpc_calls() {
+        // Pair, 2 mints and 3 bin arrays
+        assert_eq!(estimate_rpc_calls(false, 3, 100), 1);
+        assert_eq!(estimate_rpc_calls(false, 3, 6), 1);
+        assert_eq!(estimate_rpc_calls(true, 3, 6), 2);
+        assert_eq!(estimate_rpc_calls(true, 255, 100), 3);
+        assert_eq!(estimate_rpc_calls(false, 0, 1), 3);
+        assert_eq!(estimate_rpc_calls(false, 0, 0), 3);
+    }
 
-        // 3 bin arrays to left, and right is enough to cover most of the swap, and stay under 1.4m CU constraint.
-        // Get 3 bin arrays to the left from the active bin
-        let left_bin_array_pubkeys =
-            get_bin_array_pubkeys_for_swap(sol_usdc, &lb_pair, None, true, 3).unwrap();
+    #[test]
+    fn test_estimate_account_fetch_bytes() {
+        // 8 bytes discriminator + account data
+        let lb_pair_len = 904;
+        let bin_array_len = 10_136;
+        let bitmap_extension_len = 1_576;
+        let mint_len = 82;
+        let transfer_fee_mint_len = 278;
 
-        // Get 3 bin arrays to the right the from active bin
-        let right_bin_array_pubkeys =
-            get_bin_array_pubkeys_for_swap(sol_usdc, &lb_pair, None, false, 3).unwrap();
+        assert_eq!(
+            estimate_account_fetch_bytes(0, false, false),
+            lb_pair_len + 2 * mint_len
+        );
+        assert_eq!(
+            estimate_account_fetch_bytes(3, true, false),
+            lb_pair_len + 3 * bin_array_len + bitmap_extension_len + 2 * mint_len
+        );
+        assert_eq!(
+            estimate_account_fetch_bytes(1, false, true),
+            lb_pair_len + bin_array_len + 2 * transfer_fee_mint_len
+        );
+    }
 
-        // Fetch bin arrays
-        let bin_array_pubkeys = left_bin_array_pubkeys
-            .into_iter()
-            .chain(right_bin_array_pubkeys.into_iter())
-            .collect::<Vec<Pubkey>>();
+    #[test]
+    fn test_liquidity_span() {
+        let (_, lb_pair, _) = new_test_pool();
 
-        let accounts = rpc_client
-            .get_multiple_accounts(&bin_array_pubkeys)
-            .await
-            .unwrap();
+        // Bin array -1 and 0
+        assert_eq!(
+            liquidity_span(&lb_pair, None).unwrap(),
+            Some((-70, 69, 140))
+        );
 
-        let bin_arrays = accounts
-            .into_iter()
-            .zip(bin_array_pubkeys.into_iter())
-            .map(|(account, key)| {
-                (
-                    key,
-                    BinArrayAccount::deserialize(&account.unwrap().data)
-                        .unwrap()
-                        .0,
-                )
-            })
-            .collect::<HashMap<_, _>>();
+        let mut bitmap_extension: BinArrayBitmapExtension = bytemuck::Zeroable::zeroed();
+        assert_eq!(
+            liquidity_span(&lb_pair, Some(&bitmap_extension)).unwrap(),
+            Some((-70, 69, 140))
+        );
 
-        // 1 SOL -> USDC
-        let in_sol_amount = 1_000_000_000;
+        // Bin array 600 in the first positive bitmap of the extension
+        let bit = 600 % BIN_ARRAY_BITMAP_SIZE as usize;
+        bitmap_extension.positive_bin_array_bitmap[0][bit / 64] |= 1 << (bit % 64);
+        assert_eq!(
+            liquidity_span(&lb_pair, Some(&bitmap_extension)).unwrap(),
+            Some((-70, 42_069, 42_140))
+        );
 
-        let clock = get_clock(rpc_client).await.unwrap();
+        let empty_lb_pair = new_test_lb_pair(10, 10_000);
+        assert_eq!(liquidity_span(&empty_lb_pair, None).unwrap(), None);
+    }
 
-        let quote_result = quote_exact_in(
-            sol_usdc,
+    #[test]
+    fn test_quote_exact_in_output_exceeds_u64() {
+        let lb_pair_pubkey = Pubkey::new_unique();
+        let mut lb_pair = new_test_lb_pair(100, 10_000);
+        lb_pair.active_id = 100;
+
+        let offset = LbPair::get_bin_array_offset(1);
+        lb_pair.bin_array_bitmap[offset / 64] |= 1 << (offset % 64);
+
+        // Price of ~2.7 token Y per token X, 3 bins can pay out more than u64::MAX
+        let mut bin_array = new_test_bin_array(1);
+        for bin_id in 98..=100 {
+            bin_array.get_bin_mut(bin_id).unwrap().amount_y = u64::MAX / 2;
+        }
+        let bin_arrays = HashMap::from([(derive_bin_array_pda(lb_pair_pubkey, 1).0, bin_array)]);
+        let mint_account = new_test_mint_account();
+
+        let err = quote_exact_in(
+            lb_pair_pubkey,
             &lb_pair,
-            in_sol_amount,
+            10_000_000_000_000_000_000,
             true,
-            bin_arrays.clone(),
+            bin_arrays,
             None,
-            &clock,
-            &mint_x_account,
-            &mint_y_account,
+            &Clock::default(),
+            &mint_account,
+            &mint_account,
         )
-        .unwrap();
+        .unwrap_err();
 
-        println!(
-            "1 SOL -> {:?} USDC",
-            quote_result.amount_out as f64 / 1_000_000.0
+        assert_eq!(
+            err.downcast_ref::<QuoteError>(),
+            Some(&QuoteError::OutputExceedsU64 { bin_id: 98 })
         );
+    }
 
-        // 100 USDC -> SOL
-        let in_usdc_amount = 100_000_000;
+    #[test]
+    fn test_get_bin_array_indexes_for_swap_with_limit() {
+        let (_, mut lb_pair, _) = new_test_pool();
 
-        let quote_result = quote_exact_in(
-            sol_usdc,
-            &lb_pair,
-            in_usdc_amount,
-            false,
-            bin_arrays.clone(),
-            None,
-            &clock,
-            &mint_x_account,
-            &mint_y_account,
-        )
-        .unwrap();
+        // Bin array 0 and 400, with an empty region in between
+        let offset = LbPair::get_bin_array_offset(400);
+        lb_pair.bin_array_bitmap[offset / 64] |= 1 << (offset % 64);
 
-        println!(
-            "100 USDC -> {:?} SOL",
-            quote_result.amount_out as f64 / 1_000_000_000.0
+        assert_eq!(
+            get_bin_array_indexes_for_swap_with_limit(&lb_pair, None, false, 2, u32::MAX).unwrap(),
+            (vec![0, 400], false)
+        );
+        assert_eq!(
+            get_bin_array_indexes_for_swap_with_limit(&lb_pair, None, false, 2, 1).unwrap(),
+            (vec![0], true)
+        );
+        assert_eq!(
+            get_bin_array_indexes_for_swap_with_limit(&lb_pair, None, false, 2, 0).unwrap(),
+            (vec![], true)
+        );
+
+        // Search exhausted, not truncated
+        assert_eq!(
+            get_bin_array_indexes_for_swap_with_limit(&lb_pair, None, false, 3, 10).unwrap(),
+            (vec![0, 400], false)
         );
     }
 }
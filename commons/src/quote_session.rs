@@ -0,0 +1,282 @@
+use crate::*;
+use anchor_client::solana_sdk::pubkey::Pubkey;
+use solana_sdk::{account::Account, clock::Clock};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+};
+
+#[derive(Debug, Clone)]
+struct CachedQuote {
+    quote: SwapExactInQuote,
+    /// Index of the bin arrays read by the quote
+    bin_array_indexes: Vec<i32>,
+}
+
+/// Default maximum number of quotes cached by a [`QuoteSession`].
+pub const DEFAULT_MAX_CACHED_QUOTES: usize = 256;
+
+/// Quote state of a pair kept in sync with account updates. Quotes are cached, and only the quotes which read an updated bin array are recomputed.
+///
+/// Quotes are cached by exact `amount_in` and direction, up to [`DEFAULT_MAX_CACHED_QUOTES`] entries, or the limit set by
+/// [`Self::with_max_cached_quotes`]. When full, the oldest quote is evicted first, so the memory used by the cache is bounded.
+/// Any pair, bitmap extension or clock update clears the whole cache, so it only pays off when the same amounts are requoted between pair updates.
+#[derive(Debug, Clone)]
+pub struct QuoteSession {
+    lb_pair_pubkey: Pubkey,
+    lb_pair: LbPair,
+    bitmap_extension: Option<BinArrayBitmapExtension>,
+    bin_arrays: HashMap<i32, BinArray>,
+    clock: Clock,
+    mint_x_account: Account,
+    mint_y_account: Account,
+    cache: HashMap<(u64, bool), CachedQuote>,
+    /// Keys of `cache`, oldest first
+    cache_order: VecDeque<(u64, bool)>,
+    max_cached_quotes: usize,
+}
+
+impl QuoteSession {
+    pub fn new(
+        lb_pair_pubkey: Pubkey,
+        lb_pair: LbPair,
+        bitmap_extension: Option<BinArrayBitmapExtension>,
+        bin_arrays: HashMap<Pubkey, BinArray>,
+        clock: Clock,
+        mint_x_account: Account,
+        mint_y_account: Account,
+    ) -> Result<Self> {
+        let mut session = Self {
+            lb_pair_pubkey,
+            lb_pair,
+            bitmap_extension,
+            bin_arrays: HashMap::new(),
+            clock,
+            mint_x_account,
+            mint_y_account,
+            cache: HashMap::new(),
+            cache_order: VecDeque::new(),
+            max_cached_quotes: DEFAULT_MAX_CACHED_QUOTES,
+        };
+
+        for (bin_array_pubkey, bin_array) in bin_arrays {
+            session.update_bin_array(bin_array_pubkey, bin_array)?;
+        }
+
+        Ok(session)
+    }
+
+    /// Limit the number of cached quotes. 0 disables the cache.
+    pub fn with_max_cached_quotes(mut self, max_cached_quotes: usize) -> Self {
+        self.max_cached_quotes = max_cached_quotes;
+        while self.cache.len() > max_cached_quotes {
+            self.evict_oldest_quote();
+        }
+        self
+    }
+
+    /// Drop all cached quotes.
+    pub fn clear_cache(&mut self) {
+        self.cache.clear();
+        self.cache_order.clear();
+    }
+
+    fn evict_oldest_quote(&mut self) {
+        if let Some(key) = self.cache_order.pop_front() {
+            self.cache.remove(&key);
+        }
+    }
+
+    /// Replace the bin array, and drop the cached quotes which read it.
+    pub fn update_bin_array(
+        &mut self,
+        bin_array_pubkey: Pubkey,
+        bin_array: BinArray,
+    ) -> Result<()> {
+        validate_bin_array(self.lb_pair_pubkey, bin_array_pubkey, &bin_array)?;
+
+        let bin_array_index: i32 = bin_array.index.try_into().context("overflow")?;
+        self.bin_arrays.insert(bin_array_index, bin_array);
+        self.cache
+            .retain(|_, cached| !cached.bin_array_indexes.contains(&bin_array_index));
+        let cache = &self.cache;
+        self.cache_order.retain(|key| cache.contains_key(key));
+
+        Ok(())
+    }
+
+    /// Replace the pair state. All cached quotes depend on it, so the cache is cleared.
+    pub fn update_lb_pair(&mut self, lb_pair: LbPair) {
+        self.lb_pair = lb_pair;
+        self.clear_cache();
+    }
+
+    /// Replace the bitmap extension. All cached quotes depend on it, so the cache is cleared.
+    pub fn update_bitmap_extension(&mut self, bitmap_extension: Option<BinArrayBitmapExtension>) {
+        self.bitmap_extension = bitmap_extension;
+        self.clear_cache();
+    }
+
+    /// Replace the clock. All cached quotes depend on it, so the cache is cleared.
+    pub fn update_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+        self.clear_cache();
+    }
+
+    /// Quote exact in against the current state, reusing the cached quote if none of its bin arrays changed.
+    pub fn requote(&mut self, amount_in: u64, swap_for_y: bool) -> Result<SwapExactInQuote> {
+        if let Some(cached) = self.cache.get(&(amount_in, swap_for_y)) {
+            return Ok(cached.quote.clone());
+        }
+
+        validate_bitmap_extension(self.lb_pair_pubkey, self.bitmap_extension.as_ref())?;
+
+        let bin_array_indexes = RefCell::new(vec![]);
+
+        let SwapExactInQuoteWithBreakdown { quote, .. } = quote_exact_in_internal(
+            &self.lb_pair,
+            amount_in,
+            swap_for_y,
+            self.bitmap_extension.as_ref(),
+            TransferFeeMode::Net,
+            &self.clock,
+            &self.mint_x_account,
+            &self.mint_y_account,
+            |bin_array_index| {
+                bin_array_indexes.borrow_mut().push(bin_array_index);
+                self.bin_arrays.get(&bin_array_index).cloned()
+            },
+        )?;
+
+        if self.max_cached_quotes == 0 {
+            return Ok(quote);
+        }
+
+        if self.cache.len() >= self.max_cached_quotes {
+            self.evict_oldest_quote();
+        }
+
+        self.cache.insert(
+            (amount_in, swap_for_y),
+            CachedQuote {
+                quote: quote.clone(),
+                bin_array_indexes: bin_array_indexes.into_inner(),
+            },
+        );
+        self.cache_order.push_back((amount_in, swap_for_y));
+
+        Ok(quote)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_test_session() -> (QuoteSession, Pubkey, BinArray) {
+        let lb_pair_pubkey = Pubkey::new_unique();
+
+        let mut lb_pair: LbPair = bytemuck::Zeroable::zeroed();
+        lb_pair.bin_step = 10;
+        lb_pair.parameters.base_factor = 10_000;
+        let offset = LbPair::get_bin_array_offset(0);
+        lb_pair.bin_array_bitmap[offset / 64] |= 1 << (offset % 64);
+
+        let mut bin_array: BinArray = bytemuck::Zeroable::zeroed();
+        bin_array.lb_pair = lb_pair_pubkey;
+        for bin_id in 0..=3 {
+            bin_array.get_bin_mut(bin_id).unwrap().amount_x = 1_000;
+        }
+        let bin_array_pubkey = derive_bin_array_pda(lb_pair_pubkey, 0).0;
+
+        let mint_account = Account {
+            owner: anchor_spl::token::spl_token::ID,
+            ..Default::default()
+        };
+
+        let session = QuoteSession::new(
+            lb_pair_pubkey,
+            lb_pair,
+            None,
+            HashMap::from([(bin_array_pubkey, bin_array)]),
+            Clock::default(),
+            mint_account.clone(),
+            mint_account,
+        )
+        .unwrap();
+
+        (session, bin_array_pubkey, bin_array)
+    }
+
+    #[test]
+    fn test_requote_after_bin_array_update() {
+        let (mut session, bin_array_pubkey, mut bin_array) = new_test_session();
+
+        let quote = session.requote(1_000, false).unwrap();
+        assert_eq!(session.cache.len(), 1);
+        assert_eq!(session.requote(1_000, false).unwrap(), quote);
+
+        // Unrelated bin array keeps the cached quote
+        let mut other_bin_array: BinArray = bytemuck::Zeroable::zeroed();
+        other_bin_array.lb_pair = session.lb_pair_pubkey;
+        other_bin_array.index = 5;
+        // Bin array keyed by a pubkey other than its PDA
+        assert!(session
+            .update_bin_array(Pubkey::new_unique(), other_bin_array)
+            .is_err());
+        assert!(session
+            .update_bin_array(bin_array_pubkey, other_bin_array)
+            .is_err());
+        session
+            .update_bin_array(
+                derive_bin_array_pda(session.lb_pair_pubkey, 5).0,
+                other_bin_array,
+            )
+            .unwrap();
+        assert_eq!(session.cache.len(), 1);
+
+        // Halving the liquidity of the active bin pushes the swap into the next, more expensive bin
+        bin_array.get_bin_mut(0).unwrap().amount_x = 500;
+        session
+            .update_bin_array(bin_array_pubkey, bin_array)
+            .unwrap();
+        assert!(session.cache.is_empty());
+
+        let updated_quote = session.requote(1_000, false).unwrap();
+        assert!(updated_quote.amount_out < quote.amount_out);
+    }
+
+    #[test]
+    fn test_update_bin_array_of_another_pair() {
+        let (mut session, bin_array_pubkey, mut bin_array) = new_test_session();
+        bin_array.lb_pair = Pubkey::new_unique();
+        assert!(session
+            .update_bin_array(bin_array_pubkey, bin_array)
+            .is_err());
+    }
+
+    #[test]
+    fn test_requote_cache_is_bounded() {
+        let (session, _, _) = new_test_session();
+        let mut session = session.with_max_cached_quotes(2);
+
+        let quote = session.requote(100, false).unwrap();
+        session.requote(200, false).unwrap();
+        session.requote(300, false).unwrap();
+
+        // Oldest quote is evicted first
+        assert_eq!(session.cache.len(), 2);
+        assert!(!session.cache.contains_key(&(100, false)));
+        assert!(session.cache.contains_key(&(300, false)));
+        assert_eq!(session.requote(100, false).unwrap(), quote);
+        assert!(!session.cache.contains_key(&(200, false)));
+
+        session.clear_cache();
+        assert!(session.cache.is_empty());
+        assert!(session.cache_order.is_empty());
+
+        let mut session = session.with_max_cached_quotes(0);
+        session.requote(100, false).unwrap();
+        assert!(session.cache.is_empty());
+    }
+}
@@ -140,6 +140,52 @@ pub fn get_epoch_transfer_fee(mint_account: &Account, epoch: u64) -> Result<Opti
     Ok(None)
 }
 
+/// Whether quoted amounts include Token 2022 transfer fee adjustments.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TransferFeeMode {
+    /// Amounts swapped within the pool, ignoring transfer fee
+    Gross,
+    /// Amounts transferred by the user, adjusted by transfer fee
+    #[default]
+    Net,
+}
+
+impl TransferFeeMode {
+    pub fn transfer_fee_excluded_amount(
+        &self,
+        mint_account: &Account,
+        transfer_fee_included_amount: u64,
+        epoch: u64,
+    ) -> Result<u64> {
+        match self {
+            TransferFeeMode::Gross => Ok(transfer_fee_included_amount),
+            TransferFeeMode::Net => Ok(calculate_transfer_fee_excluded_amount(
+                mint_account,
+                transfer_fee_included_amount,
+                epoch,
+            )?
+            .amount),
+        }
+    }
+
+    pub fn transfer_fee_included_amount(
+        &self,
+        mint_account: &Account,
+        transfer_fee_excluded_amount: u64,
+        epoch: u64,
+    ) -> Result<u64> {
+        match self {
+            TransferFeeMode::Gross => Ok(transfer_fee_excluded_amount),
+            TransferFeeMode::Net => Ok(calculate_transfer_fee_included_amount(
+                mint_account,
+                transfer_fee_excluded_amount,
+                epoch,
+            )?
+            .amount),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct TransferFeeExcludedAmount {
     pub amount: u64,
@@ -245,3 +291,127 @@ pub fn calculate_inverse_fee(transfer_fee: &TransferFee, post_fee_amount: u64) -
     let pre_fee_amount = calculate_pre_fee_amount(transfer_fee, post_fee_amount)?;
     transfer_fee.calculate_fee(pre_fee_amount)
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use anchor_spl::token_2022::spl_token_2022;
+    use anchor_spl::token_2022::spl_token_2022::state::Mint;
+
+    pub(crate) fn new_transfer_fee_mint_account(
+        transfer_fee_basis_points: u16,
+        maximum_fee: u64,
+    ) -> Account {
+        let transfer_fee = TransferFee {
+            epoch: 0.into(),
+            maximum_fee: maximum_fee.into(),
+            transfer_fee_basis_points: transfer_fee_basis_points.into(),
+        };
+        new_transfer_fee_schedule_mint_account(transfer_fee, transfer_fee)
+    }
+
+    // Mint charging `older_transfer_fee` until the epoch of `newer_transfer_fee`
+    pub(crate) fn new_transfer_fee_schedule_mint_account(
+        older_transfer_fee: TransferFee,
+        newer_transfer_fee: TransferFee,
+    ) -> Account {
+        let space =
+            ExtensionType::try_calculate_account_len::<Mint>(&[ExtensionType::TransferFeeConfig])
+                .unwrap();
+        let mut data = vec![0u8; space];
+
+        let mut state = StateWithExtensionsMut::<Mint>::unpack_uninitialized(&mut data).unwrap();
+        let transfer_fee_config = state.init_extension::<TransferFeeConfig>(true).unwrap();
+        transfer_fee_config.older_transfer_fee = older_transfer_fee;
+        transfer_fee_config.newer_transfer_fee = newer_transfer_fee;
+
+        state.base = Mint {
+            is_initialized: true,
+            ..Default::default()
+        };
+        state.pack_base();
+        state.init_account_type().unwrap();
+
+        Account {
+            owner: spl_token_2022::ID,
+            data,
+            ..Default::default()
+        }
+    }
+
+    // Transfer fee math must agree with the Token 2022 program to the last lamport
+    #[test]
+    fn test_transfer_fee_matches_token_2022() {
+        let amounts = [
+            0,
+            1,
+            2,
+            99,
+            100,
+            101,
+            9_999,
+            10_000,
+            1_234_567,
+            u32::MAX as u64,
+        ];
+
+        for transfer_fee_basis_points in [0, 1, 50, 1_000, 9_999] {
+            for maximum_fee in [0, 10, 1_000_000, u64::MAX] {
+                let mint_account =
+                    new_transfer_fee_mint_account(transfer_fee_basis_points, maximum_fee);
+                let transfer_fee = get_epoch_transfer_fee(&mint_account, 0).unwrap().unwrap();
+
+                for amount in amounts {
+                    let excluded =
+                        calculate_transfer_fee_excluded_amount(&mint_account, amount, 0).unwrap();
+                    assert_eq!(
+                        Some(excluded.transfer_fee),
+                        transfer_fee.calculate_fee(amount)
+                    );
+                    assert_eq!(
+                        Some(excluded.amount),
+                        transfer_fee.calculate_post_fee_amount(amount)
+                    );
+
+                    assert_eq!(
+                        calculate_pre_fee_amount(&transfer_fee, amount),
+                        transfer_fee.calculate_pre_fee_amount(amount)
+                    );
+
+                    let included =
+                        calculate_transfer_fee_included_amount(&mint_account, amount, 0).unwrap();
+                    if amount > 0 {
+                        assert_eq!(
+                            Some(included.transfer_fee),
+                            transfer_fee.calculate_inverse_fee(amount)
+                        );
+                    }
+                    // Transferring the included amount delivers at least the excluded amount
+                    assert!(
+                        transfer_fee
+                            .calculate_post_fee_amount(included.amount)
+                            .unwrap()
+                            >= amount
+                    );
+                }
+            }
+        }
+    }
+
+    // Token 2022 has no pre fee amount at 100% fee rate, the maximum fee is charged on top instead
+    #[test]
+    fn test_transfer_fee_included_amount_at_max_fee_basis_points() {
+        let mint_account = new_transfer_fee_mint_account(MAX_FEE_BASIS_POINTS, 1_000);
+        let transfer_fee = get_epoch_transfer_fee(&mint_account, 0).unwrap().unwrap();
+
+        for amount in [1, 999, 1_000, 1_000_000] {
+            let included =
+                calculate_transfer_fee_included_amount(&mint_account, amount, 0).unwrap();
+            assert_eq!(included.transfer_fee, 1_000);
+            assert_eq!(
+                transfer_fee.calculate_post_fee_amount(included.amount),
+                Some(amount)
+            );
+        }
+    }
+}
@@ -1,4 +1,7 @@
-#[derive(Debug)]
+use anchor_client::solana_sdk::pubkey::Pubkey;
+use dlmm_interface::{ActivationType, PairType};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SwapResult {
     /// Amount of token swap into the bin
     pub amount_in_with_fees: u64,
@@ -13,3 +16,183 @@ pub struct SwapResult {
     /// Indicate whether we reach exact out amount
     pub is_exact_out_amount: bool,
 }
+
+/// Remaining time before a permission pair is activated, in the unit of the pair activation type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActivationCountdown {
+    Slots(u64),
+    Seconds(u64),
+}
+
+/// Launch metadata of a pair, readable before the pair is activated
+#[derive(Debug, Clone, PartialEq)]
+pub struct LaunchInfo {
+    pub pair_type: PairType,
+    pub activation_type: ActivationType,
+    pub activation_point: u64,
+    /// Remaining time before activation. None if the pair is not a permission pair, or is already activated
+    pub activation_countdown: Option<ActivationCountdown>,
+    /// Whether any bin array tracked by the internal bitmap has liquidity
+    pub is_liquidity_seeded: bool,
+}
+
+/// Bin with liquidity in a [`LiquidityBookSnapshot`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LiquidityBookBin {
+    pub bin_id: i32,
+    /// Q64.64 price of token Y per token X
+    pub price: u128,
+    pub amount_x: u64,
+    pub amount_y: u64,
+    pub liquidity_supply: u128,
+}
+
+/// Depth of a pair, which is every bin holding liquidity sorted by bin id
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LiquidityBookSnapshot {
+    pub active_id: i32,
+    pub bin_step: u16,
+    pub bins: Vec<LiquidityBookBin>,
+}
+
+/// Swap direction and amount resolved from the input and output mints, ready to be passed to the quote functions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwapIntent {
+    pub swap_for_y: bool,
+    pub amount_in: u64,
+}
+
+/// Change of a bin between 2 snapshots of the pair
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BinLiquidityDelta {
+    pub bin_id: i32,
+    pub delta_x: i128,
+    pub delta_y: i128,
+    /// Change of the liquidity share supply. Swaps only move the amounts, so a non zero delta means liquidity was deposited or withdrawn
+    pub delta_liquidity_supply: i128,
+}
+
+/// Error of a quote that could not be completed. Returned wrapped in [`anyhow::Error`], use `downcast_ref` to match on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuoteError {
+    /// Pair has no bin array with liquidity in either direction
+    EmptyPool,
+    /// Swap ran out of liquidity after filling `filled_out` amount out, which includes transfer fee
+    InsufficientLiquidity { filled_out: u64 },
+    /// Clock passed to the quote is older than the allowed age
+    StaleClock { clock_slot: u64, current_slot: u64 },
+    /// Bin array the swap needs next is not in the bin arrays passed to the quote. Carries the progress made before it.
+    /// The bin array pubkey can be derived with `derive_bin_array_pda`
+    MissingBinArray {
+        bin_array_index: i32,
+        /// Amount in consumed by the bins, includes fee and excludes transfer fee
+        consumed_in: u64,
+        /// Amount out produced by the bins, includes transfer fee
+        produced_out: u64,
+        /// Active bin id when the swap stopped
+        last_bin_id: i32,
+    },
+    /// Total amount out of the swap doesn't fit in u64, overflowed while swapping in `bin_id`
+    OutputExceedsU64 { bin_id: i32 },
+    /// Slippage of the amount swapped so far against the starting active bin price crossed the limit after swapping in `at_bin_id`
+    SlippageExceeded { at_bin_id: i32, realized_bps: u64 },
+    /// Bitmap extension passed to the quote belongs to `bitmap_extension_lb_pair` instead of `lb_pair`
+    MismatchedBitmapExtension {
+        bitmap_extension_lb_pair: Pubkey,
+        lb_pair: Pubkey,
+    },
+    /// Pair account data is `len` bytes instead of the `expected_len` bytes of the [`LbPair`] layout this SDK was built for
+    UnsupportedPoolVersion { len: usize, expected_len: usize },
+    /// Swap made no progress in bin array `bin_array_index`, which doesn't contain the active bin `active_id`
+    NoProgress {
+        active_id: i32,
+        bin_array_index: i32,
+    },
+    /// Bin array keyed by `bin_array` belongs to `bin_array_lb_pair` instead of `expected_lb_pair`, or is not keyed by the PDA of its index,
+    /// which is `expected_pubkey`
+    MismatchedBinArray {
+        bin_array: Pubkey,
+        bin_array_lb_pair: Pubkey,
+        expected_lb_pair: Pubkey,
+        expected_pubkey: Pubkey,
+    },
+}
+
+impl std::fmt::Display for QuoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuoteError::EmptyPool => write!(f, "EmptyPool: pool has no liquidity"),
+            QuoteError::InsufficientLiquidity { filled_out } => write!(
+                f,
+                "InsufficientLiquidity: pool out of liquidity after filling {} amount out",
+                filled_out
+            ),
+            QuoteError::StaleClock {
+                clock_slot,
+                current_slot,
+            } => write!(
+                f,
+                "StaleClock: clock at slot {} is behind current slot {}",
+                clock_slot, current_slot
+            ),
+            QuoteError::MissingBinArray {
+                bin_array_index,
+                consumed_in,
+                produced_out,
+                last_bin_id,
+            } => write!(
+                f,
+                "MissingBinArray: bin array {} not found at bin {}, after consuming {} amount in for {} amount out",
+                bin_array_index, last_bin_id, consumed_in, produced_out
+            ),
+            QuoteError::OutputExceedsU64 { bin_id } => write!(
+                f,
+                "OutputExceedsU64: amount out exceeds u64 while swapping in bin {}, swap is too large",
+                bin_id
+            ),
+            QuoteError::SlippageExceeded {
+                at_bin_id,
+                realized_bps,
+            } => write!(
+                f,
+                "SlippageExceeded: slippage reached {} bps at bin {}",
+                realized_bps, at_bin_id
+            ),
+            QuoteError::MismatchedBitmapExtension {
+                bitmap_extension_lb_pair,
+                lb_pair,
+            } => write!(
+                f,
+                "MismatchedBitmapExtension: bitmap extension belongs to {}, not {}",
+                bitmap_extension_lb_pair, lb_pair
+            ),
+            QuoteError::UnsupportedPoolVersion { len, expected_len } => write!(
+                f,
+                "UnsupportedPoolVersion: pair account is {} bytes, expected {} bytes",
+                len, expected_len
+            ),
+            QuoteError::NoProgress {
+                active_id,
+                bin_array_index,
+            } => write!(
+                f,
+                "NoProgress: active bin {} is not within bin array {}",
+                active_id, bin_array_index
+            ),
+            QuoteError::MismatchedBinArray {
+                bin_array,
+                bin_array_lb_pair,
+                expected_lb_pair,
+                expected_pubkey,
+            } => write!(
+                f,
+                "MismatchedBinArray: bin array {} belongs to {}, expected bin array {} of {}",
+                bin_array, bin_array_lb_pair, expected_pubkey, expected_lb_pair
+            ),
+        }
+    }
+}
+
+impl std::error::Error for QuoteError {}
@@ -464,3 +464,80 @@ async fn test_swap() {
         quote_result.amount_out
     );
 }
+
+#[tokio::test]
+async fn test_compute_reserves_within_vault_balances() {
+    let (
+        test,
+        SplTestPair {
+            lb_pair,
+            reserve_x,
+            reserve_y,
+            bin_array_1,
+            bin_array_2,
+            ..
+        },
+    ) = setup_spl_test_pair();
+
+    let (mut banks_client, _payer, _recent_blockhash) = test.start().await;
+
+    let lb_pair_account = banks_client
+        .get_account(lb_pair)
+        .await
+        .ok()
+        .flatten()
+        .unwrap();
+
+    let lb_pair_state = LbPairAccount::deserialize(&lb_pair_account.data).unwrap().0;
+
+    let mut bin_arrays = vec![];
+    for bin_array in [bin_array_1, bin_array_2] {
+        let bin_array_account = banks_client
+            .get_account(bin_array)
+            .await
+            .ok()
+            .flatten()
+            .unwrap();
+
+        bin_arrays.push(
+            BinArrayAccount::deserialize(&bin_array_account.data)
+                .unwrap()
+                .0,
+        );
+    }
+
+    let mut vault_balances = vec![];
+    for reserve in [reserve_x, reserve_y] {
+        let reserve_account = banks_client
+            .get_account(reserve)
+            .await
+            .ok()
+            .flatten()
+            .unwrap();
+
+        let reserve_state =
+            TokenAccount::try_deserialize(&mut reserve_account.data.as_ref()).unwrap();
+        vault_balances.push(reserve_state.amount);
+    }
+
+    let (bin_reserve_x, bin_reserve_y) = commons::compute_reserves(&bin_arrays);
+
+    // Reserve vaults also hold the pending protocol fee and the fees not yet claimed by the positions,
+    // and the fixture holds the bin arrays around the active bin only, so the bins sum up to less than the vaults
+    for (bin_reserve, protocol_fee, vault_balance) in [
+        (
+            bin_reserve_x,
+            lb_pair_state.protocol_fee.amount_x,
+            vault_balances[0],
+        ),
+        (
+            bin_reserve_y,
+            lb_pair_state.protocol_fee.amount_y,
+            vault_balances[1],
+        ),
+    ] {
+        assert!(bin_reserve + u128::from(protocol_fee) <= u128::from(vault_balance));
+        // Bin arrays around the active bin hold most of the liquidity
+        assert!(bin_reserve >= u128::from(vault_balance) * 3 / 4);
+    }
+}